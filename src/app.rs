@@ -0,0 +1,754 @@
+use crate::{
+    command::CommandLine,
+    config::SmartPlaylist,
+    dj::AutoDj,
+    help::Help,
+    input::Input,
+    keys::{Action, KeyMap},
+    lyrics::Lyrics,
+    message::Messages,
+    outputs::{Output, Outputs},
+    play::Songs,
+    search::Search,
+    state::StatefulList,
+    stats::Library,
+    stickers::Ratings,
+    Mode, View,
+};
+use async_mpd::{Status, Subsystem, Track};
+use crossterm::event::KeyCode;
+
+// Something that happened, a key press or something MPD changed or answered
+#[derive(Debug)]
+pub enum Msg {
+    Input(Input),
+    // from the idle connection
+    Changed(Subsystem),
+    // after connecting, everything shown is fetched again
+    Connected,
+    Disconnected,
+    Status(Box<Status>),
+    Queue(Vec<Track>),
+    Outputs(Vec<Output>),
+    Ratings(Ratings),
+    // what MPD found for the search query
+    Found(Vec<Track>),
+    // a search result was queued and is playing, by its title
+    Added(String),
+    Cleared,
+    OnlyOutput(u32),
+    Loaded {
+        playlist: usize,
+        added: usize,
+        replace: bool,
+    },
+    // `None` is the whole database
+    Updating {
+        dir: Option<String>,
+        job: i32,
+    },
+    // the `:` prompt was told to quit
+    Quit,
+}
+
+// What MPD has to do for the state, `input::execute` does it and answers with a `Msg`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Effect {
+    FetchStatus,
+    FetchQueue,
+    FetchOutputs,
+    FetchRatings,
+    // searches for the query, if there is one
+    Search,
+    Play(u32),
+    TogglePause,
+    AddAndPlay {
+        file: String,
+        title: String,
+    },
+    ClearQueue,
+    // `toggle`, `enable` or `disable`
+    SetOutput(&'static str, u32),
+    OnlyOutput(u32),
+    LoadPlaylist {
+        playlist: usize,
+        replace: bool,
+    },
+    Rate {
+        file: String,
+        rating: u8,
+    },
+    Update(Option<String>),
+    // what was typed on the `:` prompt
+    RunCommand,
+    CompleteCommand,
+    // scripts belong to the app, this is only which one to run
+    #[cfg(feature = "lua")]
+    Script(usize),
+    Quit,
+}
+
+// Everything the UI shows and works on, without a terminal or connections to MPD
+pub struct AppState {
+    pub song_list: StatefulList<Songs>,
+    pub results: StatefulList<Songs>,
+    pub search: Search,
+    pub mode: Mode,
+    pub view: View,
+    pub help: Help,
+    pub cmdline: CommandLine,
+    pub messages: Messages,
+    // false while MPD is gone and we are waiting for it to come back
+    pub connected: bool,
+    pub keymap: KeyMap,
+    // lyrics of the playing song
+    pub lyrics: Lyrics,
+    pub outputs: Outputs,
+    // only loaded once the stats view is opened
+    pub library: Library,
+    pub ratings: Ratings,
+    pub playlists: Vec<SmartPlaylist>,
+    // smart playlist that tops up the queue when it runs low
+    pub refill: Option<usize>,
+    pub dj: AutoDj,
+}
+
+impl AppState {
+    pub fn new(
+        keymap: KeyMap,
+        messages: Messages,
+        playlists: Vec<SmartPlaylist>,
+        dj: AutoDj,
+    ) -> AppState {
+        AppState {
+            song_list: Default::default(),
+            results: Default::default(),
+            search: Default::default(),
+            mode: Default::default(),
+            view: Default::default(),
+            help: Default::default(),
+            cmdline: Default::default(),
+            messages,
+            connected: true,
+            keymap,
+            lyrics: Default::default(),
+            outputs: Default::default(),
+            library: Default::default(),
+            ratings: Default::default(),
+            playlists,
+            refill: None,
+            dj,
+        }
+    }
+
+    // Applies `msg` and returns what MPD has to do because of it, in order
+    pub fn update(&mut self, msg: Msg) -> Vec<Effect> {
+        match msg {
+            Msg::Input(input) => return self.input(input),
+            Msg::Changed(subsystem) => return self.changed(subsystem),
+            Msg::Connected => {
+                self.results.clear();
+                self.search.clear();
+                if let Mode::Searching | Mode::Selecting = self.mode {
+                    self.mode = Mode::Browsing;
+                }
+                // the playing song might be the same, but its lyrics file could have changed
+                self.lyrics = Lyrics::default();
+                // the queue first, so whatever follows the status sees the songs in it
+                return vec![
+                    Effect::FetchQueue,
+                    Effect::FetchStatus,
+                    Effect::FetchOutputs,
+                    Effect::FetchRatings,
+                ];
+            }
+            Msg::Disconnected => {
+                self.connected = false;
+                self.messages.error("Lost connection to MPD");
+            }
+            Msg::Status(status) => {
+                let was_updating = self
+                    .song_list
+                    .status()
+                    .is_some_and(|s| s.updating_db.is_some());
+                if was_updating && status.updating_db.is_none() {
+                    self.messages.success("Database update finished");
+                }
+                self.song_list.set_status(Some(*status));
+            }
+            Msg::Queue(queue) => {
+                self.song_list.set_songs(&queue);
+                // the queue might have gotten shorter
+                if !self.song_list.is_empty() && self.song_list.selected().is_none() {
+                    self.song_list.select(0);
+                }
+            }
+            Msg::Outputs(outputs) => self.outputs.set(outputs),
+            Msg::Ratings(ratings) => self.ratings = ratings,
+            Msg::Found(songs) => {
+                self.search.found(songs, &self.ratings);
+                self.results.set_songs(self.search.results());
+            }
+            Msg::Added(title) => {
+                self.messages.success(format!("Added {}", title));
+                self.search.clear();
+                self.results.clear();
+            }
+            Msg::Cleared => {
+                self.messages.success("Cleared the queue");
+                if self.refill.take().is_some() {
+                    self.messages.info("Stopped refilling the queue");
+                }
+            }
+            Msg::OnlyOutput(id) => {
+                if let Some(o) = self.outputs.outputs().iter().find(|o| o.id == id) {
+                    self.messages.success(format!("Only playing on {}", o.name));
+                }
+            }
+            Msg::Loaded {
+                playlist,
+                added,
+                replace,
+            } => {
+                let name = &self.playlists[playlist].name;
+                // the queue wasn't touched
+                if added == 0 {
+                    self.messages.warn(format!("No songs matched {}", name));
+                    return Vec::new();
+                }
+                self.messages
+                    .success(format!("Added {} songs from {}", added, name));
+                if self.playlists[playlist].refill {
+                    self.refill = Some(playlist);
+                } else if replace {
+                    self.refill = None;
+                }
+            }
+            Msg::Updating { dir: None, job } => self
+                .messages
+                .info(format!("Updating the database (job {})", job)),
+            Msg::Updating {
+                dir: Some(dir),
+                job,
+            } => self
+                .messages
+                .info(format!("Updating {} (job {})", dir, job)),
+            Msg::Quit => return vec![Effect::Quit],
+        }
+        Vec::new()
+    }
+
+    // What has to be fetched again after MPD changed something
+    fn changed(&mut self, subsystem: Subsystem) -> Vec<Effect> {
+        match subsystem {
+            Subsystem::Player | Subsystem::Mixer | Subsystem::Options | Subsystem::Update => {
+                vec![Effect::FetchStatus]
+            }
+            // the status has the new queue length as well
+            Subsystem::Playlist | Subsystem::StoredPlaylist => {
+                vec![Effect::FetchQueue, Effect::FetchStatus]
+            }
+            // tags of any song might have changed after a database update
+            Subsystem::Database => {
+                self.library = Library::default();
+                self.lyrics = Lyrics::default();
+                vec![Effect::FetchQueue, Effect::Search]
+            }
+            Subsystem::Sticker => vec![Effect::FetchRatings],
+            Subsystem::Output => vec![Effect::FetchOutputs],
+            _ => Vec::new(),
+        }
+    }
+
+    fn input(&mut self, input: Input) -> Vec<Effect> {
+        let (action, code) = match input {
+            Input::Key(code) => (self.keymap.get(self.mode, code), code),
+            Input::Action(action) => (Some(action), KeyCode::Null),
+        };
+
+        match self.mode {
+            Mode::Searching => match (action, code) {
+                (Some(Action::Select), _) if !self.results.is_empty() => {
+                    self.results.next();
+                    self.mode = Mode::Selecting;
+                }
+                (Some(Action::Erase), _) => {
+                    self.search.pop();
+                    return vec![Effect::Search];
+                }
+                (Some(Action::Back), _) => self.mode = Mode::Browsing,
+                (None, KeyCode::Char(c)) => {
+                    self.search.push(c);
+                    return vec![Effect::Search];
+                }
+                _ => {}
+            },
+            Mode::Command => match (action, code) {
+                (Some(Action::Execute), _) => {
+                    self.mode = Mode::Browsing;
+                    return vec![Effect::RunCommand];
+                }
+                (Some(Action::Complete), _) => return vec![Effect::CompleteCommand],
+                (Some(Action::Up), _) => self.cmdline.history_up(),
+                (Some(Action::Down), _) => self.cmdline.history_down(),
+                // backspace on an empty prompt closes it, like vim
+                (Some(Action::Erase), _) if self.cmdline.input().is_empty() => {
+                    self.mode = Mode::Browsing
+                }
+                (Some(Action::Erase), _) => self.cmdline.pop(),
+                (Some(Action::Back), _) => self.mode = Mode::Browsing,
+                (None, KeyCode::Char(c)) => self.cmdline.push(c),
+                _ => {}
+            },
+            Mode::Help if self.help.is_filtering() => match code {
+                KeyCode::Char(c) => self.help.push(c),
+                KeyCode::Backspace => self.help.pop(),
+                KeyCode::Enter | KeyCode::Esc => self.help.set_filtering(false),
+                _ => {}
+            },
+            Mode::Help => {
+                let help = &mut self.help;
+                match action {
+                    Some(Action::Down) => help.scroll_to(help.scroll() + 1, &self.keymap),
+                    Some(Action::Up) => {
+                        help.scroll_to(help.scroll().saturating_sub(1), &self.keymap)
+                    }
+                    Some(Action::Top) => help.scroll_to(0, &self.keymap),
+                    Some(Action::Bottom) => help.scroll_to(usize::MAX, &self.keymap),
+                    Some(Action::Search) => help.set_filtering(true),
+                    Some(Action::Back) => self.mode = help.previous(),
+                    _ => {}
+                }
+            }
+            Mode::Selecting => match action {
+                Some(Action::Down) => self.results.next(),
+                Some(Action::Up) => self.results.previous(),
+                Some(Action::Top) => self.results.select(0),
+                Some(Action::Bottom) => self.results.select_last(),
+                Some(Action::AddAndPlay) => {
+                    if let Some(s) = self.results.selected() {
+                        let effect = Effect::AddAndPlay {
+                            file: s.file.clone(),
+                            title: s.title.clone().unwrap_or_else(|| s.file.clone()),
+                        };
+                        self.mode = Mode::Browsing;
+                        return vec![effect];
+                    }
+                }
+                Some(Action::Back) => self.mode = Mode::Searching,
+                Some(Action::Help) => {
+                    self.help.open(self.mode);
+                    self.mode = Mode::Help;
+                }
+                _ => {}
+            },
+            Mode::Browsing => return self.browse(action),
+        }
+        Vec::new()
+    }
+
+    fn browse(&mut self, action: Option<Action>) -> Vec<Effect> {
+        let outputs = self.view == View::Outputs;
+        let effect = match action {
+            Some(Action::Quit) => Effect::Quit,
+            Some(Action::QueueView) => return self.show(View::Queue),
+            Some(Action::LyricsView) => return self.show(View::Lyrics),
+            Some(Action::VisualizerView) => return self.show(View::Visualizer),
+            Some(Action::OutputsView) => return self.show(View::Outputs),
+            Some(Action::StatsView) => return self.show(View::Stats),
+            Some(Action::Down) => {
+                match self.view {
+                    View::Lyrics => self.lyrics.scroll_down(),
+                    View::Outputs => self.outputs.next(),
+                    _ => self.song_list.next(),
+                }
+                return Vec::new();
+            }
+            Some(Action::Up) => {
+                match self.view {
+                    View::Lyrics => self.lyrics.scroll_up(),
+                    View::Outputs => self.outputs.previous(),
+                    _ => self.song_list.previous(),
+                }
+                return Vec::new();
+            }
+            Some(Action::Top) => {
+                match self.view {
+                    View::Outputs => self.outputs.select(0),
+                    _ => self.song_list.select(0),
+                }
+                return Vec::new();
+            }
+            Some(Action::Bottom) => {
+                match self.view {
+                    View::Outputs => self.outputs.select(usize::MAX),
+                    _ => self.song_list.select_last(),
+                }
+                return Vec::new();
+            }
+            // the list is refreshed by the output event MPD sends back
+            Some(Action::ToggleOutput) | Some(Action::Play) if outputs => {
+                match self.outputs.selected() {
+                    Some(o) => Effect::SetOutput("toggle", o.id),
+                    None => return Vec::new(),
+                }
+            }
+            Some(Action::EnableOutput) if outputs => match self.outputs.selected() {
+                Some(o) => Effect::SetOutput("enable", o.id),
+                None => return Vec::new(),
+            },
+            Some(Action::DisableOutput) if outputs => match self.outputs.selected() {
+                Some(o) => Effect::SetOutput("disable", o.id),
+                None => return Vec::new(),
+            },
+            Some(Action::ExclusiveOutput) if outputs => match self.outputs.selected() {
+                Some(o) => Effect::OnlyOutput(o.id),
+                None => return Vec::new(),
+            },
+            Some(Action::ClearQueue) => Effect::ClearQueue,
+            Some(Action::ToggleAutoDj) => {
+                if self.dj.toggle() {
+                    self.messages.info("Auto-DJ on");
+                } else {
+                    self.messages.info("Auto-DJ off");
+                }
+                return Vec::new();
+            }
+            Some(Action::LoadPlaylist(i)) => Effect::LoadPlaylist {
+                playlist: i,
+                replace: true,
+            },
+            Some(Action::AppendPlaylist(i)) => Effect::LoadPlaylist {
+                playlist: i,
+                replace: false,
+            },
+            Some(Action::RateUp) | Some(Action::RateDown) => match self.song_list.selected() {
+                Some(s) => {
+                    let file = s.file.clone();
+                    let rating = self.ratings.get(&file).unwrap_or(0);
+                    let rating = match action {
+                        Some(Action::RateUp) => (rating + 1).min(5),
+                        _ => rating.saturating_sub(1),
+                    };
+                    // set right away, pressing again before MPD's sticker event goes on from here
+                    self.ratings.set(&file, rating);
+                    Effect::Rate { file, rating }
+                }
+                None => return Vec::new(),
+            },
+            Some(Action::Update) => Effect::Update(None),
+            // songs at the top level update everything
+            Some(Action::UpdateDirectory) => match self.song_list.selected() {
+                Some(s) => Effect::Update(s.file.rsplit_once('/').map(|(d, _)| d.to_string())),
+                None => return Vec::new(),
+            },
+            Some(Action::Search) => {
+                self.mode = Mode::Searching;
+                return Vec::new();
+            }
+            Some(Action::TogglePause) => Effect::TogglePause,
+            Some(Action::Play) => match self.song_list.selected().and_then(|s| s.id) {
+                Some(id) => Effect::Play(id),
+                None => return Vec::new(),
+            },
+            Some(Action::Help) => {
+                self.help.open(self.mode);
+                self.mode = Mode::Help;
+                return Vec::new();
+            }
+            #[cfg(feature = "lua")]
+            Some(Action::Script(i)) => Effect::Script(i),
+            Some(Action::Command) => {
+                self.cmdline.open();
+                self.mode = Mode::Command;
+                return Vec::new();
+            }
+            _ => return Vec::new(),
+        };
+        vec![effect]
+    }
+
+    fn show(&mut self, view: View) -> Vec<Effect> {
+        self.view = view;
+        Vec::new()
+    }
+
+    // Views can only be switched while browsing
+    pub fn switch_view(&mut self, view: View) -> Vec<Effect> {
+        self.mode = Mode::Browsing;
+        self.show(view)
+    }
+
+    // Opens search with `query` typed in
+    pub fn search_for(&mut self, query: &str) -> Vec<Effect> {
+        self.mode = Mode::Searching;
+        self.search.clear();
+        query.chars().for_each(|c| self.search.push(c));
+        vec![Effect::Search]
+    }
+
+    // Selects the playing song in the queue, returns its position
+    pub fn jump_to_playing(&mut self) -> Option<u32> {
+        let pos = self.song_list.status().and_then(|s| s.song)?;
+        self.mode = Mode::Browsing;
+        self.view = View::Queue;
+        self.song_list.select(pos as usize);
+        Some(pos)
+    }
+
+    // What the UI shows right now, for the IPC socket
+    pub fn summary(&self) -> serde_json::Value {
+        serde_json::json!({
+            "mode": self.mode.name(),
+            "view": self.view.name(),
+            "connected": self.connected,
+            "selected": self.song_list.state().selected(),
+            "selected_file": self.song_list.selected().map(|s| &s.file),
+            "search": self.search.query(),
+            "results": self.results.len(),
+            "message": self.messages.current().map(|m| &m.text),
+            "auto_dj": self.dj.is_enabled(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::Overrides;
+    use KeyCode::{Backspace, Char, Enter, Esc};
+
+    fn state() -> AppState {
+        let keymap = KeyMap::new(&Overrides::default(), &[]).unwrap();
+        let mut state = AppState::new(
+            keymap,
+            Messages::default(),
+            Vec::new(),
+            AutoDj::new(&Default::default()),
+        );
+        state.update(Msg::Queue(vec![
+            track(1, "a/1.flac", "One"),
+            track(2, "a/2.flac", "Two"),
+            track(3, "b/3.flac", "Three"),
+        ]));
+        state
+    }
+
+    fn track(id: u32, file: &str, title: &str) -> Track {
+        Track {
+            id: Some(id),
+            file: file.to_string(),
+            title: Some(title.to_string()),
+            ..Track::default()
+        }
+    }
+
+    fn key(state: &mut AppState, code: KeyCode) -> Vec<Effect> {
+        state.update(Msg::Input(Input::Key(code)))
+    }
+
+    fn keys(state: &mut AppState, keys: &str) -> Vec<Effect> {
+        keys.chars().flat_map(|c| key(state, Char(c))).collect()
+    }
+
+    fn selected(list: &StatefulList<Songs>) -> Option<&str> {
+        list.selected().map(|s| s.file.as_str())
+    }
+
+    fn message(state: &AppState) -> Option<&str> {
+        state.messages.current().map(|m| m.text.as_str())
+    }
+
+    #[test]
+    fn selects_the_first_song_of_a_new_queue() {
+        let mut state = state();
+        assert_eq!(selected(&state.song_list), Some("a/1.flac"));
+
+        key(&mut state, Char('G'));
+        state.update(Msg::Queue(vec![track(1, "a/1.flac", "One")]));
+        assert_eq!(selected(&state.song_list), Some("a/1.flac"));
+    }
+
+    #[test]
+    fn plays_the_selected_song() {
+        let mut state = state();
+        assert_eq!(keys(&mut state, "j"), []);
+        assert_eq!(key(&mut state, Enter), [Effect::Play(2)]);
+    }
+
+    #[test]
+    fn searches_while_typing() {
+        let mut state = state();
+        assert_eq!(keys(&mut state, "/"), []);
+        assert_eq!(state.mode, Mode::Searching);
+        // keys bound while browsing are typed in
+        assert_eq!(keys(&mut state, "tq"), [Effect::Search, Effect::Search]);
+        assert_eq!(key(&mut state, Backspace), [Effect::Search]);
+        assert_eq!(state.search.query(), "t");
+
+        // nothing to select yet
+        key(&mut state, Enter);
+        assert_eq!(state.mode, Mode::Searching);
+
+        state.update(Msg::Found(vec![
+            track(4, "c/4.flac", "Two"),
+            track(5, "c/5.flac", "Three"),
+        ]));
+        key(&mut state, Enter);
+        assert_eq!(state.mode, Mode::Selecting);
+        assert_eq!(selected(&state.results), Some("c/4.flac"));
+        key(&mut state, Char('j'));
+        assert_eq!(
+            key(&mut state, Enter),
+            [Effect::AddAndPlay {
+                file: "c/5.flac".to_string(),
+                title: "Three".to_string(),
+            }]
+        );
+        assert_eq!(state.mode, Mode::Browsing);
+
+        state.update(Msg::Added("Three".to_string()));
+        assert_eq!(message(&state), Some("Added Three"));
+        assert_eq!(state.search.query(), "");
+        assert!(state.results.is_empty());
+    }
+
+    #[test]
+    fn filters_found_songs_by_rating() {
+        let mut state = state();
+        state.search_for("t rating>=1");
+        state.update(Msg::Found(vec![track(2, "a/2.flac", "Two")]));
+        // unrated songs count as 0
+        assert!(state.results.is_empty());
+
+        state.search_for("t rating=0");
+        state.update(Msg::Found(vec![track(2, "a/2.flac", "Two")]));
+        assert_eq!(selected(&state.results), None);
+        assert_eq!(state.results.len(), 1);
+    }
+
+    #[test]
+    fn goes_back_through_modes() {
+        let mut state = state();
+        keys(&mut state, "/");
+        state.update(Msg::Found(vec![track(4, "c/4.flac", "Two")]));
+        key(&mut state, Enter);
+        assert_eq!(state.mode, Mode::Selecting);
+
+        // help goes back to where it was opened
+        keys(&mut state, "?");
+        assert_eq!(state.mode, Mode::Help);
+        key(&mut state, Esc);
+        assert_eq!(state.mode, Mode::Selecting);
+        key(&mut state, Esc);
+        assert_eq!(state.mode, Mode::Searching);
+        key(&mut state, Esc);
+        assert_eq!(state.mode, Mode::Browsing);
+    }
+
+    #[test]
+    fn runs_commands_from_the_prompt() {
+        let mut state = state();
+        keys(&mut state, ":");
+        assert_eq!(state.mode, Mode::Command);
+        // q is typed in, not quitting
+        assert_eq!(keys(&mut state, "q"), []);
+        assert_eq!(key(&mut state, Enter), [Effect::RunCommand]);
+        assert_eq!(state.mode, Mode::Browsing);
+        assert_eq!(state.update(Msg::Quit), [Effect::Quit]);
+
+        keys(&mut state, ":");
+        key(&mut state, Backspace);
+        assert_eq!(state.mode, Mode::Browsing);
+    }
+
+    #[test]
+    fn rates_the_selected_song() {
+        let mut state = state();
+        assert_eq!(
+            keys(&mut state, "-+"),
+            [
+                Effect::Rate {
+                    file: "a/1.flac".to_string(),
+                    rating: 0,
+                },
+                Effect::Rate {
+                    file: "a/1.flac".to_string(),
+                    rating: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn fetches_what_changed() {
+        let mut state = state();
+        assert_eq!(
+            state.update(Msg::Changed(Subsystem::Playlist)),
+            [Effect::FetchQueue, Effect::FetchStatus]
+        );
+        assert_eq!(
+            state.update(Msg::Changed(Subsystem::Mixer)),
+            [Effect::FetchStatus]
+        );
+        assert_eq!(
+            state.update(Msg::Changed(Subsystem::Database)),
+            [Effect::FetchQueue, Effect::Search]
+        );
+        assert_eq!(state.update(Msg::Changed(Subsystem::Message)), []);
+    }
+
+    #[test]
+    fn says_when_an_update_finished() {
+        let mut state = state();
+        state.update(Msg::Status(Box::new(Status {
+            updating_db: Some(1),
+            ..Status::default()
+        })));
+        assert_eq!(message(&state), None);
+        state.update(Msg::Status(Box::default()));
+        assert_eq!(message(&state), Some("Database update finished"));
+    }
+
+    #[test]
+    fn starts_over_after_connecting() {
+        let mut state = state();
+        state.search_for("one");
+        state.update(Msg::Found(vec![track(1, "a/1.flac", "One")]));
+        assert_eq!(
+            state.update(Msg::Connected),
+            [
+                Effect::FetchQueue,
+                Effect::FetchStatus,
+                Effect::FetchOutputs,
+                Effect::FetchRatings,
+            ]
+        );
+        assert_eq!(state.mode, Mode::Browsing);
+        assert!(state.results.is_empty());
+    }
+
+    #[test]
+    fn jumps_to_the_playing_song() {
+        let mut state = state();
+        state.update(Msg::Status(Box::new(Status {
+            song: Some(1),
+            songid: Some(2),
+            ..Status::default()
+        })));
+        state.switch_view(View::Lyrics);
+        assert_eq!(state.jump_to_playing(), Some(1));
+        assert_eq!(state.view, View::Queue);
+        assert_eq!(selected(&state.song_list), Some("a/2.flac"));
+    }
+
+    #[test]
+    fn stops_refilling_when_cleared() {
+        let mut state = state();
+        assert_eq!(keys(&mut state, "c"), [Effect::ClearQueue]);
+        state.refill = Some(0);
+        state.update(Msg::Cleared);
+        assert_eq!(state.refill, None);
+        assert_eq!(message(&state), Some("Cleared the queue"));
+    }
+}
@@ -0,0 +1,382 @@
+use crate::protocol::Connection;
+use async_mpd::{Error, Track};
+use image::{imageops::FilterType, RgbImage};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    env,
+    fmt::Write as _,
+    io::{self, Write},
+};
+use tui::layout::Rect;
+
+// how many decoded covers are kept around
+const CACHE_SIZE: usize = 32;
+
+// covers are scaled down to this on load, no terminal pane is going to be bigger
+const MAX_SIZE: u32 = 512;
+
+// used when the terminal doesn't report its size in pixels
+const CELL_SIZE: (u32, u32) = (10, 20);
+
+const KITTY_ID: u32 = 1;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Protocol {
+    Kitty,
+    Sixel,
+    // unicode half blocks, works everywhere with true color
+    HalfBlock,
+}
+
+impl Protocol {
+    pub fn detect() -> Protocol {
+        let term = env::var("TERM").unwrap_or_default();
+        let program = env::var("TERM_PROGRAM").unwrap_or_default();
+
+        if env::var_os("KITTY_WINDOW_ID").is_some() || term.contains("kitty") {
+            Protocol::Kitty
+        } else if term.contains("sixel")
+            || ["foot", "mlterm", "yaft-256color"].contains(&term.as_str())
+            || program == "WezTerm"
+        {
+            Protocol::Sixel
+        } else {
+            Protocol::HalfBlock
+        }
+    }
+}
+
+pub struct Cover {
+    image: RgbImage,
+    // last scaled version, so redrawing doesn't resize every time
+    scaled: RefCell<Option<RgbImage>>,
+}
+
+impl Cover {
+    // Scales the cover to fit inside `width`x`height` pixels, keeping the aspect ratio
+    pub fn fit(&self, width: u32, height: u32) -> RgbImage {
+        let (w, h) = fit(self.image.dimensions(), (width, height));
+        let mut scaled = self.scaled.borrow_mut();
+
+        match &*scaled {
+            Some(img) if img.dimensions() == (w, h) => img.clone(),
+            _ => {
+                let img = image::imageops::resize(&self.image, w, h, FilterType::Triangle);
+                *scaled = Some(img.clone());
+                img
+            }
+        }
+    }
+}
+
+pub struct AlbumArt {
+    protocol: Protocol,
+    covers: HashMap<String, Option<Cover>>,
+    // order covers were loaded in, oldest first
+    order: VecDeque<String>,
+    current: Option<String>,
+    // the cover being fetched
+    pending: Option<String>,
+    // what was last drawn with a graphics protocol
+    drawn: Option<(String, Rect)>,
+}
+
+impl AlbumArt {
+    pub fn new() -> AlbumArt {
+        AlbumArt {
+            protocol: Protocol::detect(),
+            covers: HashMap::new(),
+            order: VecDeque::new(),
+            current: None,
+            pending: None,
+            drawn: None,
+        }
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub fn current(&self) -> Option<&Cover> {
+        self.current
+            .as_ref()
+            .and_then(|key| self.covers.get(key))
+            .and_then(Option::as_ref)
+    }
+
+    // Makes the cover for `track`'s album current. Returns the key to fetch it as if it isn't
+    // cached or already being fetched.
+    pub fn show(&mut self, track: Option<&Track>) -> Option<String> {
+        self.current = track.map(album_key);
+        let key = self.current.clone()?;
+        if self.covers.contains_key(&key) || self.pending.as_ref() == Some(&key) {
+            return None;
+        }
+        self.pending = Some(key.clone());
+        Some(key)
+    }
+
+    // Caches a fetched cover, `None` if the album doesn't have one
+    pub fn loaded(&mut self, key: String, cover: Option<Cover>) {
+        if self.pending.as_ref() == Some(&key) {
+            self.pending = None;
+        }
+        if self.order.len() >= CACHE_SIZE {
+            if let Some(old) = self.order.pop_front() {
+                self.covers.remove(&old);
+            }
+        }
+        self.order.push_back(key.clone());
+        self.covers.insert(key, cover);
+    }
+
+    // Fetching failed, so the next `show` tries again
+    pub fn failed(&mut self, key: &str) {
+        if self.pending.as_deref() == Some(key) {
+            self.pending = None;
+        }
+    }
+
+    // Drops every cached cover, they might have changed on disk
+    pub fn clear(&mut self) {
+        self.covers.clear();
+        self.order.clear();
+        self.current = None;
+        self.pending = None;
+        self.drawn = None;
+    }
+
+    // Forget what is on screen, e.g. after the terminal was cleared
+    pub fn invalidate(&mut self) {
+        self.drawn = None;
+    }
+
+    // Draws the current cover with a graphics protocol, the half block fallback is a widget
+    // drawn along with everything else. Only writes anything when the cover or area changed.
+    pub fn render<W: Write>(&mut self, w: &mut W, area: Option<Rect>) -> io::Result<()> {
+        if self.protocol == Protocol::HalfBlock {
+            return Ok(());
+        }
+
+        let next = match (&self.current, area) {
+            (Some(key), Some(area)) if self.current().is_some() => Some((key.clone(), area)),
+            _ => None,
+        };
+        if next == self.drawn {
+            return Ok(());
+        }
+
+        if let Some((_, old)) = self.drawn.take() {
+            match self.protocol {
+                Protocol::Kitty => write!(w, "\x1b_Ga=d,d=I,i={},q=2\x1b\\", KITTY_ID)?,
+                // sixels stay until something is drawn over them
+                _ => {
+                    let blank = " ".repeat(old.width as usize);
+                    for y in old.y..old.y + old.height {
+                        move_to(w, old.x, y)?;
+                        w.write_all(blank.as_bytes())?;
+                    }
+                }
+            }
+        }
+
+        if let (Some((_, area)), Some(cover)) = (&next, self.current()) {
+            let (cw, ch) = cell_size();
+            let img = cover.fit(area.width as u32 * cw, area.height as u32 * ch);
+            let (iw, ih) = img.dimensions();
+
+            // center the image in the area
+            let cols = iw.div_ceil(cw) as u16;
+            let rows = ih.div_ceil(ch) as u16;
+            let x = area.x + area.width.saturating_sub(cols) / 2;
+            let y = area.y + area.height.saturating_sub(rows) / 2;
+            move_to(w, x, y)?;
+
+            match self.protocol {
+                Protocol::Kitty => kitty(w, &img, cols, rows)?,
+                _ => w.write_all(sixel(&img).as_bytes())?,
+            }
+        }
+        w.flush()?;
+
+        self.drawn = next;
+        Ok(())
+    }
+}
+
+impl Default for AlbumArt {
+    fn default() -> Self {
+        AlbumArt::new()
+    }
+}
+
+// Covers are cached per album, falling back to the directory for untagged files
+fn album_key(track: &Track) -> String {
+    match &track.album {
+        Some(album) => {
+            let artist = track
+                .album_artist
+                .as_deref()
+                .or(track.artist.as_deref())
+                .unwrap_or_default();
+            format!("{}\0{}", artist, album)
+        }
+        None => track
+            .file
+            .rsplit_once('/')
+            .map(|(dir, _)| dir)
+            .unwrap_or_default()
+            .to_string(),
+    }
+}
+
+// Fetches and decodes the cover for `uri`, scaled down to what a pane could show
+pub async fn cover(conn: &mut Connection, uri: &str) -> Result<Option<Cover>, Error> {
+    Ok(fetch(conn, uri).await?.and_then(|data| {
+        image::load_from_memory(&data)
+            .map_err(|e| log::warn!("Cannot decode cover for {}: {}", uri, e))
+            .ok()
+            .map(|img| Cover {
+                image: img.thumbnail(MAX_SIZE, MAX_SIZE).to_rgb8(),
+                scaled: RefCell::new(None),
+            })
+    }))
+}
+
+// Looks for a cover file in the directory first, then for a picture embedded in the file
+pub async fn fetch(conn: &mut Connection, uri: &str) -> Result<Option<Vec<u8>>, Error> {
+    for cmd in &["albumart", "readpicture"] {
+        match conn.binary(cmd, uri).await {
+            Ok(Some(data)) => return Ok(Some(data)),
+            Ok(None) => {}
+            // no such file, or a server too old for readpicture
+            Err(Error::CommandError { msg }) => log::info!("{} for {}: {}", cmd, uri, msg),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(None)
+}
+
+// Largest size with the aspect ratio of `image` that fits in `area`
+pub fn fit((w, h): (u32, u32), (aw, ah): (u32, u32)) -> (u32, u32) {
+    if w == 0 || h == 0 {
+        return (1, 1);
+    }
+
+    let scale = f64::min(aw as f64 / w as f64, ah as f64 / h as f64);
+    (
+        ((w as f64 * scale) as u32).max(1),
+        ((h as f64 * scale) as u32).max(1),
+    )
+}
+
+// Size of a terminal cell in pixels
+#[cfg(unix)]
+fn cell_size() -> (u32, u32) {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let ok = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0;
+
+    if ok && size.ws_xpixel > 0 && size.ws_ypixel > 0 && size.ws_col > 0 && size.ws_row > 0 {
+        (
+            (size.ws_xpixel / size.ws_col) as u32,
+            (size.ws_ypixel / size.ws_row) as u32,
+        )
+    } else {
+        CELL_SIZE
+    }
+}
+
+#[cfg(not(unix))]
+fn cell_size() -> (u32, u32) {
+    CELL_SIZE
+}
+
+fn move_to<W: Write>(w: &mut W, x: u16, y: u16) -> io::Result<()> {
+    write!(w, "\x1b[{};{}H", y + 1, x + 1)
+}
+
+// https://sw.kovidgoyal.net/kitty/graphics-protocol/
+fn kitty<W: Write>(w: &mut W, img: &RgbImage, cols: u16, rows: u16) -> io::Result<()> {
+    let data = base64::encode(img.as_raw());
+    let chunks = data.as_bytes().chunks(4096).collect::<Vec<_>>();
+
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = (i + 1 < chunks.len()) as u8;
+        if i == 0 {
+            // z=-1 keeps text (e.g. the search popup) on top of the image
+            write!(
+                w,
+                "\x1b_Ga=T,f=24,s={},v={},c={},r={},i={},z=-1,C=1,q=2,m={};",
+                img.width(),
+                img.height(),
+                cols,
+                rows,
+                KITTY_ID,
+                more
+            )?;
+        } else {
+            write!(w, "\x1b_Gm={};", more)?;
+        }
+        w.write_all(chunk)?;
+        w.write_all(b"\x1b\\")?;
+    }
+    Ok(())
+}
+
+// Encodes the image as sixels using a 6x6x6 color cube
+fn sixel(img: &RgbImage) -> String {
+    let (width, height) = img.dimensions();
+    let mut out = format!("\x1bPq\"1;1;{};{}", width, height);
+
+    for i in 0..216 {
+        let _ = write!(
+            out,
+            "#{};2;{};{};{}",
+            i,
+            i / 36 * 20,
+            i / 6 % 6 * 20,
+            i % 6 * 20
+        );
+    }
+
+    let color = |x, y| {
+        let p = img.get_pixel(x, y);
+        let level = |c: u8| (c as usize * 5 + 127) / 255;
+        level(p[0]) * 36 + level(p[1]) * 6 + level(p[2])
+    };
+
+    for band in (0..height).step_by(6) {
+        // bits set for every column, per color used in this band
+        let mut colors: Vec<Option<Vec<u8>>> = vec![None; 216];
+        for x in 0..width {
+            for dy in 0..6.min(height - band) {
+                let bits =
+                    colors[color(x, band + dy)].get_or_insert_with(|| vec![0; width as usize]);
+                bits[x as usize] |= 1 << dy;
+            }
+        }
+
+        for (c, bits) in colors.iter().enumerate() {
+            if let Some(bits) = bits {
+                let _ = write!(out, "#{}", c);
+                let mut i = 0;
+                while i < bits.len() {
+                    let run = bits[i..].iter().take_while(|b| **b == bits[i]).count();
+                    let ch = (63 + bits[i]) as char;
+                    if run > 3 {
+                        let _ = write!(out, "!{}{}", run, ch);
+                    } else {
+                        (0..run).for_each(|_| out.push(ch));
+                    }
+                    i += run;
+                }
+                out.push('$');
+            }
+        }
+        out.push('-');
+    }
+
+    out.push_str("\x1b\\");
+    out
+}
@@ -0,0 +1,629 @@
+use crate::{
+    command::{self, Outcome},
+    protocol::{self, Connection},
+};
+use anyhow::{anyhow, Context, Result};
+use async_mpd::{Filter, FilterExpr, MpdClient, Status, Tag, Track};
+use serde_json::{json, Value};
+use std::{io::Write, net::SocketAddrV4, time::Duration};
+use structopt::{clap::AppSettings, StructOpt};
+
+const CONNECT_ERROR: &str =
+    "Failed to start MPD client. Is it started and on 127.0.0.1:6600 or the specified port/ip?";
+
+// Commands that run without the TUI, for scripts and key bindings
+#[derive(StructOpt)]
+pub enum Cmd {
+    /// Starts playing
+    Play,
+    /// Pauses playback
+    Pause,
+    /// Pauses or resumes playback
+    Toggle,
+    /// Stops playback
+    Stop,
+    /// Plays the next song in the queue
+    Next,
+    /// Plays the previous song in the queue
+    Prev,
+    /// Adds a file or directory to the queue
+    Add { uri: String },
+    /// Removes every song from the queue
+    Clear,
+    /// Sets the volume, `+5` and `-5` change it relative to the current one
+    #[structopt(setting = AppSettings::AllowNegativeNumbers)]
+    Volume { volume: String },
+    /// Seeks in the playing song, like `1:30`, `+10` or `-10`
+    #[structopt(setting = AppSettings::AllowLeadingHyphen)]
+    Seek { time: String },
+    /// Turns random, repeat, consume or single on, off or toggles it
+    Set { option: String, value: String },
+    /// Updates the database, or only `path` in it
+    Update { path: Option<String> },
+    /// Shows the playing song, the playback state and options
+    Status,
+    /// Shows the playing song
+    Current,
+    /// Lists the songs in the queue
+    Queue,
+    /// Lists the files whose `tag` contains `query`, any of artist, album, albumartist,
+    /// title, genre, date, composer, performer or any
+    Search { tag: String, query: Vec<String> },
+    /// Prints a line whenever something changes in MPD, until it's stopped
+    Watch,
+}
+
+impl Cmd {
+    // Same as typing it on the `:` prompt
+    fn line(&self) -> Option<String> {
+        Some(match self {
+            Cmd::Play => "play".to_string(),
+            Cmd::Pause => "pause".to_string(),
+            Cmd::Toggle => "toggle".to_string(),
+            Cmd::Stop => "stop".to_string(),
+            Cmd::Next => "next".to_string(),
+            Cmd::Prev => "prev".to_string(),
+            Cmd::Add { uri } => format!("add {}", uri),
+            Cmd::Clear => "clear".to_string(),
+            Cmd::Volume { volume } => format!("volume {}", volume),
+            Cmd::Seek { time } => format!("seek {}", time),
+            Cmd::Set { option, value } => format!("set {} {}", option, value),
+            Cmd::Update { path } => format!("update {}", path.as_deref().unwrap_or_default()),
+            Cmd::Status | Cmd::Current | Cmd::Queue | Cmd::Search { .. } | Cmd::Watch => {
+                return None
+            }
+        })
+    }
+}
+
+// Runs `cmd` and writes what it has to say to `out`, as one JSON value per line with `json`
+pub async fn run(cmd: Cmd, addr: SocketAddrV4, json: bool, out: &mut impl Write) -> Result<()> {
+    let mut client = MpdClient::new(addr).await.context(CONNECT_ERROR)?;
+
+    if let Some(line) = cmd.line() {
+        let mut conn = Connection::new(addr).await.context(CONNECT_ERROR)?;
+        if let Outcome::Done(Some(message)) = command::run(&mut client, &mut conn, &line).await? {
+            if json {
+                writeln!(out, "{}", json!({ "message": message }))?;
+            } else {
+                writeln!(out, "{}", message)?;
+            }
+        }
+        return Ok(());
+    }
+
+    match cmd {
+        Cmd::Status => {
+            let status = client.status().await?;
+            let track = current(&mut client, &status).await?;
+            if json {
+                writeln!(
+                    out,
+                    "{}",
+                    json!({ "status": status_json(&status), "song": track.as_ref().map(song_json) })
+                )?;
+                return Ok(());
+            }
+            if let Some(track) = track {
+                writeln!(out, "{}", song(&track))?;
+            }
+            writeln!(out, "{}", state(&status))?;
+            writeln!(out, "{}", options(&status))?;
+        }
+        Cmd::Current => {
+            let status = client.status().await?;
+            let track = current(&mut client, &status).await?;
+            if json {
+                writeln!(out, "{}", track.as_ref().map_or(Value::Null, song_json))?;
+            } else if let Some(track) = track {
+                writeln!(out, "{}", song(&track))?;
+            }
+        }
+        Cmd::Queue => {
+            let status = client.status().await?;
+            let queue = client.queue().await?;
+            if json {
+                writeln!(
+                    out,
+                    "{}",
+                    Value::Array(queue.iter().map(song_json).collect())
+                )?;
+                return Ok(());
+            }
+            for (i, track) in queue.iter().enumerate() {
+                let marker = if status.song == Some(i as u32) {
+                    ">"
+                } else {
+                    " "
+                };
+                writeln!(
+                    out,
+                    "{}{:>4} {} ({})",
+                    marker,
+                    i + 1,
+                    song(track),
+                    time(track.duration)
+                )?;
+            }
+        }
+        Cmd::Search { tag: name, query } => {
+            let filter = Filter::new().and(FilterExpr::Contains(tag(&name)?, query.join(" ")));
+            let found = client.search(&filter).await?;
+            if json {
+                writeln!(
+                    out,
+                    "{}",
+                    Value::Array(found.iter().map(song_json).collect())
+                )?;
+                return Ok(());
+            }
+            for track in found {
+                writeln!(out, "{}", track.file)?;
+            }
+        }
+        Cmd::Watch => watch(&mut client, json, out).await?,
+        _ => unreachable!("commands with a line are run above"),
+    }
+    Ok(())
+}
+
+// One line for every change, starting with how things are now. Status bars keep this running,
+// so it waits for MPD to come back instead of exiting.
+async fn watch(client: &mut MpdClient, json: bool, out: &mut impl Write) -> Result<()> {
+    let mut event = Value::from("start");
+    loop {
+        let playing = async {
+            let status = client.status().await?;
+            Ok::<_, anyhow::Error>((current(client, &status).await?, status))
+        }
+        .await;
+        let (track, status) = match playing {
+            Ok(playing) => playing,
+            // gone again right after reconnecting
+            Err(e) if protocol::is_disconnect(&e) => {
+                log::warn!("Lost connection to MPD: {}", e);
+                protocol::reconnect(client).await;
+                event = Value::from("reconnect");
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if json {
+            writeln!(
+                out,
+                "{}",
+                json!({
+                    "event": event,
+                    "status": status_json(&status),
+                    "song": track.as_ref().map(song_json),
+                })
+            )?;
+        } else {
+            let event = event.as_str().unwrap_or_default();
+            match &track {
+                Some(track) => writeln!(out, "{}: {} {}", event, state(&status), song(track))?,
+                None => writeln!(out, "{}: {}", event, state(&status))?,
+            }
+        }
+
+        event = loop {
+            match client.idle().await {
+                Ok(Some(subsystem)) => break serde_json::to_value(subsystem)?,
+                // a subsystem async_mpd doesn't know about
+                Ok(None) => {}
+                Err(e) => {
+                    log::warn!("Lost connection to MPD: {}", e);
+                    protocol::reconnect(client).await;
+                    break Value::from("reconnect");
+                }
+            }
+        };
+    }
+}
+
+// MPD has `currentsong`, but async_mpd only reads whole queues
+async fn current(client: &mut MpdClient, status: &Status) -> Result<Option<Track>> {
+    let id = match status.songid {
+        Some(id) => id,
+        None => return Ok(None),
+    };
+    Ok(client.queue().await?.into_iter().find(|t| t.id == Some(id)))
+}
+
+fn tag(name: &str) -> Result<Tag> {
+    Ok(match name.to_lowercase().as_str() {
+        "artist" => Tag::Artist,
+        "album" => Tag::Album,
+        "albumartist" => Tag::AlbumArtist,
+        "title" => Tag::Title,
+        "genre" => Tag::Genre,
+        "date" => Tag::Date,
+        "composer" => Tag::Composer,
+        "performer" => Tag::Performer,
+        "any" => Tag::Any,
+        _ => return Err(anyhow!("Cannot search by {}", name)),
+    })
+}
+
+fn status_json(status: &Status) -> Value {
+    json!({
+        "state": status.state,
+        "volume": status.volume,
+        "repeat": status.repeat,
+        "random": status.random,
+        "single": status.single != "0",
+        "consume": status.consume,
+        "song": status.song,
+        "songid": status.songid,
+        "elapsed": status.elapsed.map(|e| e.as_secs_f64()),
+        "duration": status.duration.map(|d| d.as_secs_f64()),
+        "queue_length": status.playlistlength,
+        "updating_db": status.updating_db,
+    })
+}
+
+fn song_json(track: &Track) -> Value {
+    json!({
+        "file": track.file,
+        "id": track.id,
+        "title": track.title,
+        "artist": track.artist,
+        "album": track.album,
+        "album_artist": track.album_artist,
+        "genre": track.genre,
+        "date": track.date,
+        "track": track.track,
+        "duration": track.duration.as_secs_f64(),
+    })
+}
+
+// `Artist - Title`, or the file when it has no title
+fn song(track: &Track) -> String {
+    match (&track.artist, &track.title) {
+        (Some(artist), Some(title)) => format!("{} - {}", artist, title),
+        (None, Some(title)) => title.clone(),
+        _ => track.file.clone(),
+    }
+}
+
+// `[playing] #3/12 1:23/4:56 (28%)`
+fn state(status: &Status) -> String {
+    let state = match status.state.as_str() {
+        "play" => "playing",
+        "pause" => "paused",
+        _ => return "[stopped]".to_string(),
+    };
+    let elapsed = status.elapsed.unwrap_or_default();
+    let duration = status.duration.unwrap_or_default();
+    let percent = if duration.as_secs() > 0 {
+        elapsed.as_secs() * 100 / duration.as_secs()
+    } else {
+        0
+    };
+    format!(
+        "[{}] #{}/{} {}/{} ({}%)",
+        state,
+        status.song.map_or(0, |s| s + 1),
+        status.playlistlength,
+        time(elapsed),
+        time(duration),
+        percent
+    )
+}
+
+fn options(status: &Status) -> String {
+    let on = |b: bool| if b { "on" } else { "off" };
+    format!(
+        "volume: {}  repeat: {}  random: {}  single: {}  consume: {}",
+        status
+            .volume
+            .map_or_else(|| "n/a".to_string(), |v| format!("{}%", v)),
+        on(status.repeat),
+        on(status.random),
+        on(status.single != "0"),
+        on(status.consume)
+    )
+}
+
+// `4:05`
+fn time(d: Duration) -> String {
+    format!("{}:{:02}", d.as_secs() / 60, d.as_secs() % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{self, Mpd};
+    use async_std::{channel, future::timeout, task};
+    use std::io;
+
+    // The library queued, the second song 45 seconds in
+    async fn mpd() -> Mpd {
+        let mpd = Mpd::start(vec![
+            mock::song("a/1.flac", "One", "Alpha", "First"),
+            mock::song("a/2.flac", "Two", "Alpha", "First"),
+            mock::song("b/3.flac", "Three", "Beta", "Second"),
+        ])
+        .await;
+        mpd.with(|s| {
+            for song in s.library.clone() {
+                s.enqueue(song);
+            }
+            s.current = Some(1);
+            s.state = "play".to_string();
+            s.elapsed = 45.0;
+            s.consume = true;
+        });
+        mpd
+    }
+
+    async fn output(mpd: &Mpd, cmd: Cmd, json: bool) -> Result<String> {
+        let mut out = Vec::new();
+        run(cmd, mpd.addr, json, &mut out).await?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    fn status(state: &str) -> Status {
+        Status {
+            state: state.to_string(),
+            song: Some(0),
+            playlistlength: 2,
+            elapsed: Some(Duration::from_secs(30)),
+            single: "0".to_string(),
+            ..Status::default()
+        }
+    }
+
+    #[async_std::test]
+    async fn prints_the_status() {
+        let mpd = mpd().await;
+        assert_eq!(
+            output(&mpd, Cmd::Status, false).await.unwrap(),
+            concat!(
+                "Alpha - Two\n",
+                "[playing] #2/3 0:45/3:00 (25%)\n",
+                "volume: 50%  repeat: off  random: off  single: off  consume: on\n",
+            )
+        );
+        assert_eq!(
+            output(&mpd, Cmd::Current, false).await.unwrap(),
+            "Alpha - Two\n"
+        );
+    }
+
+    #[async_std::test]
+    async fn lists_the_queue() {
+        let mpd = mpd().await;
+        assert_eq!(
+            output(&mpd, Cmd::Queue, false).await.unwrap(),
+            concat!(
+                "    1 Alpha - One (3:00)\n",
+                ">   2 Alpha - Two (3:00)\n",
+                "    3 Beta - Three (3:00)\n",
+            )
+        );
+    }
+
+    #[async_std::test]
+    async fn searches_by_tag() {
+        let mpd = mpd().await;
+        let search = |tag: &str, query: &str| Cmd::Search {
+            tag: tag.to_string(),
+            query: vec![query.to_string()],
+        };
+        assert_eq!(
+            output(&mpd, search("Title", "t"), false).await.unwrap(),
+            "a/2.flac\nb/3.flac\n"
+        );
+        let e = output(&mpd, search("bogus", "t"), false).await.unwrap_err();
+        assert_eq!(e.to_string(), "Cannot search by bogus");
+    }
+
+    #[async_std::test]
+    async fn runs_the_other_commands_like_the_prompt() {
+        let mpd = mpd().await;
+        let volume = Cmd::Volume {
+            volume: "-10".to_string(),
+        };
+        output(&mpd, volume, false).await.unwrap();
+        assert_eq!(mpd.with(|s| s.volume), 40);
+
+        output(&mpd, Cmd::Next, false).await.unwrap();
+        assert_eq!(mpd.with(|s| s.current), Some(2));
+
+        let seek = Cmd::Seek {
+            time: "junk".to_string(),
+        };
+        assert!(output(&mpd, seek, false).await.is_err());
+        assert_eq!(
+            Cmd::Update { path: None }.line().as_deref(),
+            Some("update ")
+        );
+        assert_eq!(Cmd::Queue.line(), None);
+    }
+
+    #[test]
+    fn formats_the_state() {
+        assert_eq!(state(&status("stop")), "[stopped]");
+        // no duration for streams
+        assert_eq!(state(&status("pause")), "[paused] #1/2 0:30/0:00 (0%)");
+        let mut playing = status("play");
+        playing.duration = Some(Duration::from_secs(120));
+        assert_eq!(state(&playing), "[playing] #1/2 0:30/2:00 (25%)");
+    }
+
+    #[test]
+    fn formats_the_options() {
+        let mut status = status("play");
+        assert_eq!(
+            options(&status),
+            "volume: n/a  repeat: off  random: off  single: off  consume: off"
+        );
+        status.volume = Some(80);
+        status.random = true;
+        // oneshot counts as on
+        status.single = "oneshot".to_string();
+        assert_eq!(
+            options(&status),
+            "volume: 80%  repeat: off  random: on  single: on  consume: off"
+        );
+    }
+
+    #[test]
+    fn formats_songs_and_times() {
+        let mut track = Track {
+            file: "a/1.flac".to_string(),
+            ..Track::default()
+        };
+        assert_eq!(song(&track), "a/1.flac");
+        track.title = Some("One".to_string());
+        assert_eq!(song(&track), "One");
+        track.artist = Some("Alpha".to_string());
+        assert_eq!(song(&track), "Alpha - One");
+
+        assert_eq!(time(Duration::from_secs(0)), "0:00");
+        assert_eq!(time(Duration::from_secs(65)), "1:05");
+        assert_eq!(time(Duration::from_secs(3725)), "62:05");
+    }
+
+    // Hands over every whole line written to it
+    struct Lines(Vec<u8>, channel::Sender<String>);
+
+    impl Write for Lines {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.extend_from_slice(buf);
+            while let Some(end) = self.0.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.0.drain(..=end).collect();
+                let _ = self
+                    .1
+                    .try_send(String::from_utf8_lossy(&line).trim_end().to_string());
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn keys(value: &Value) -> Vec<&str> {
+        let mut keys: Vec<_> = value
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    #[async_std::test]
+    async fn keeps_the_json_keys() {
+        let mpd = mpd().await;
+        let status: Value =
+            serde_json::from_str(&output(&mpd, Cmd::Status, true).await.unwrap()).unwrap();
+        assert_eq!(keys(&status), ["song", "status"]);
+        assert_eq!(
+            keys(&status["status"]),
+            [
+                "consume",
+                "duration",
+                "elapsed",
+                "queue_length",
+                "random",
+                "repeat",
+                "single",
+                "song",
+                "songid",
+                "state",
+                "updating_db",
+                "volume",
+            ]
+        );
+        assert_eq!(status["status"]["state"], "play");
+        assert_eq!(status["status"]["elapsed"], 45.0);
+        assert_eq!(status["status"]["single"], false);
+        assert_eq!(
+            keys(&status["song"]),
+            [
+                "album",
+                "album_artist",
+                "artist",
+                "date",
+                "duration",
+                "file",
+                "genre",
+                "id",
+                "title",
+                "track",
+            ]
+        );
+        assert_eq!(status["song"]["file"], "a/2.flac");
+        assert_eq!(status["song"]["duration"], 180.0);
+
+        let queue: Value =
+            serde_json::from_str(&output(&mpd, Cmd::Queue, true).await.unwrap()).unwrap();
+        assert_eq!(queue.as_array().unwrap().len(), 3);
+        assert_eq!(queue[2]["title"], "Three");
+
+        let message: Value = serde_json::from_str(
+            &output(
+                &mpd,
+                Cmd::Add {
+                    uri: "b".to_string(),
+                },
+                true,
+            )
+            .await
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(keys(&message), ["message"]);
+
+        mpd.with(|s| {
+            s.state = "stop".to_string();
+            s.current = None;
+        });
+        assert_eq!(output(&mpd, Cmd::Current, true).await.unwrap(), "null\n");
+    }
+
+    #[async_std::test]
+    async fn watches_for_changes() {
+        let mpd = mpd().await;
+        let (s, lines) = channel::unbounded();
+        let addr = mpd.addr;
+        task::spawn(async move {
+            let _ = run(Cmd::Watch, addr, true, &mut Lines(Vec::new(), s)).await;
+        });
+        let next = || async {
+            let line = timeout(Duration::from_secs(5), lines.recv())
+                .await
+                .expect("no event")
+                .unwrap();
+            serde_json::from_str::<Value>(&line).unwrap()
+        };
+
+        let start = next().await;
+        assert_eq!(keys(&start), ["event", "song", "status"]);
+        assert_eq!(start["event"], "start");
+        assert_eq!(start["song"]["title"], "Two");
+
+        mpd.with(|s| {
+            s.volume = 70;
+            s.changed("mixer");
+        });
+        let mixer = next().await;
+        assert_eq!(mixer["event"], "mixer");
+        assert_eq!(mixer["status"]["volume"], 70);
+
+        mpd.disconnect();
+        assert_eq!(next().await["event"], "reconnect");
+
+        // the first connection back is closed before the greeting
+        mpd.with(|s| s.refuse = 1);
+        mpd.disconnect();
+        let reconnect = next().await;
+        assert_eq!(reconnect["event"], "reconnect");
+        assert_eq!(reconnect["status"]["volume"], 70);
+    }
+}
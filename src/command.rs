@@ -0,0 +1,496 @@
+use crate::{
+    message::Messages,
+    outputs,
+    protocol::{self, Connection},
+    stickers,
+};
+use anyhow::{anyhow, Result};
+use async_mpd::MpdClient;
+
+// Every command with a short usage, used for completion and error messages
+const COMMANDS: &[(&str, &str)] = &[
+    ("add", "add <uri>"),
+    ("clear", "clear"),
+    ("next", "next"),
+    ("output", "output <toggle|enable|disable|only> <id>"),
+    ("pause", "pause"),
+    ("play", "play"),
+    ("prev", "prev"),
+    ("quit", "quit"),
+    ("rate", "rate <0-5>"),
+    ("rescan", "rescan [path]"),
+    ("save", "save <playlist>"),
+    ("seek", "seek <[+-][mm:]ss>"),
+    ("set", "set <random|repeat|consume|single> <on|off|toggle>"),
+    ("stop", "stop"),
+    ("toggle", "toggle"),
+    ("update", "update [path]"),
+    ("volume", "volume <[+-]0-100>"),
+];
+
+const OPTIONS: &[&str] = &["consume", "random", "repeat", "single"];
+const SWITCHES: &[&str] = &["off", "on", "toggle"];
+const OUTPUT_ACTIONS: &[&str] = &["disable", "enable", "only", "toggle"];
+
+// What running a command did
+pub enum Outcome {
+    Done(Option<String>),
+    Quit,
+}
+
+// The `:` prompt
+#[derive(Debug, Default)]
+pub struct CommandLine {
+    input: String,
+    history: Vec<String>,
+    // position while going through history, `None` when editing a new line
+    browsing: Option<usize>,
+    // candidates for the word being completed, which one is shown and where in the input it goes
+    completions: Vec<String>,
+    completion: usize,
+    completion_start: usize,
+}
+
+impl CommandLine {
+    pub fn open(&mut self) {
+        self.input.clear();
+        self.browsing = None;
+        self.completions.clear();
+    }
+
+    pub fn input(&self) -> &str {
+        &self.input
+    }
+
+    pub fn push(&mut self, c: char) {
+        self.input.push(c);
+        self.completions.clear();
+    }
+
+    pub fn pop(&mut self) {
+        self.input.pop();
+        self.completions.clear();
+    }
+
+    pub fn history_up(&mut self) {
+        let i = match self.browsing {
+            Some(0) => return,
+            Some(i) => i - 1,
+            None if self.history.is_empty() => return,
+            None => self.history.len() - 1,
+        };
+        self.browsing = Some(i);
+        self.input = self.history[i].clone();
+    }
+
+    pub fn history_down(&mut self) {
+        match self.browsing {
+            Some(i) if i + 1 < self.history.len() => {
+                self.browsing = Some(i + 1);
+                self.input = self.history[i + 1].clone();
+            }
+            Some(_) => {
+                self.browsing = None;
+                self.input.clear();
+            }
+            None => {}
+        }
+    }
+
+    // Completes the last word, pressing it again cycles through the other candidates
+    pub async fn complete(&mut self, conn: &mut Connection, messages: &mut Messages) -> Result<()> {
+        if self.completions.is_empty() {
+            // uris can have spaces, so everything after the last slash is replaced. Found before
+            // completing, the candidates might end in a space or slash themselves.
+            self.completion_start = match argument(&self.input, "add") {
+                Some(arg) => self.input[arg..].rfind('/').map_or(arg, |i| arg + i + 1),
+                None => self.input.rfind(' ').map_or(0, |i| i + 1),
+            };
+
+            let (words, word) = match self.input.rfind(' ') {
+                Some(i) => (&self.input[..i], &self.input[i + 1..]),
+                None => ("", self.input.as_str()),
+            };
+            let words = words.split_whitespace().collect::<Vec<_>>();
+
+            self.completions = candidates(conn, &words, word, &self.input).await?;
+            self.completion = 0;
+            if self.completions.len() > 1 {
+                messages.info(self.completions.join("  "));
+            }
+        } else {
+            self.completion = (self.completion + 1) % self.completions.len();
+        }
+
+        if let Some(completion) = self.completions.get(self.completion) {
+            self.input.truncate(self.completion_start);
+            self.input.push_str(completion);
+        }
+        Ok(())
+    }
+
+    // Runs what was typed and saves it to the history
+    pub async fn run(
+        &mut self,
+        client: &mut MpdClient,
+        conn: &mut Connection,
+        messages: &mut Messages,
+    ) -> Outcome {
+        let line = self.input.trim().to_string();
+        self.input.clear();
+        self.completions.clear();
+        self.browsing = None;
+
+        if line.is_empty() {
+            return Outcome::Done(None);
+        }
+        if self.history.last() != Some(&line) {
+            self.history.push(line.clone());
+        }
+
+        match run(client, conn, &line).await {
+            Ok(Outcome::Done(message)) => {
+                if let Some(m) = &message {
+                    messages.success(m.as_str());
+                }
+                Outcome::Done(message)
+            }
+            Ok(Outcome::Quit) => Outcome::Quit,
+            Err(e) => {
+                messages.error(protocol::error_message(&e));
+                Outcome::Done(None)
+            }
+        }
+    }
+}
+
+pub async fn run(client: &mut MpdClient, conn: &mut Connection, line: &str) -> Result<Outcome> {
+    let (cmd, rest) = match line.split_once(' ') {
+        Some((cmd, rest)) => (cmd, rest.trim()),
+        None => (line, ""),
+    };
+    let args = rest.split_whitespace().collect::<Vec<_>>();
+
+    let message = match (cmd, args.as_slice()) {
+        ("add", [_, ..]) => {
+            let before = client.status().await?.playlistlength;
+            client.queue_add(rest).await?;
+            let added = client.status().await?.playlistlength.saturating_sub(before);
+            format!("Added {} track{}", added, if added == 1 { "" } else { "s" })
+        }
+        ("save", [_, ..]) => {
+            conn.command("save", &[rest]).await?;
+            format!("Saved the queue as {}", rest)
+        }
+        ("clear", []) => {
+            client.queue_clear().await?;
+            "Cleared the queue".to_string()
+        }
+        ("update", _) => {
+            let job = client.update(Some(rest).filter(|p| !p.is_empty())).await?;
+            format!("Updating the database (job {})", job)
+        }
+        // like update, but also reads files that didn't change
+        ("rescan", _) => {
+            let job = client.rescan(Some(rest).filter(|p| !p.is_empty())).await?;
+            format!("Rescanning the database (job {})", job)
+        }
+        // rates the playing song, the queue view has keys for the selected one
+        ("rate", [rating]) => {
+            let rating = rating
+                .parse::<u8>()
+                .ok()
+                .filter(|r| *r <= 5)
+                .ok_or_else(|| anyhow!("Ratings go from 0 to 5"))?;
+            let file = conn
+                .command("currentsong", &[])
+                .await?
+                .into_iter()
+                .find(|(k, _)| k == "file")
+                .map(|(_, v)| v)
+                .ok_or_else(|| anyhow!("Nothing is playing"))?;
+            stickers::rate(conn, &file, rating).await?;
+            format!("Rated {} {}", file, stickers::stars(Some(rating)))
+        }
+        ("seek", [time]) => {
+            conn.command("seekcur", &[&seconds(time)?]).await?;
+            return Ok(Outcome::Done(None));
+        }
+        ("volume", [change]) => {
+            let current = client.status().await?.volume.unwrap_or(0);
+            let volume = volume(current, change)?;
+            client.setvol(volume).await?;
+            format!("Volume {}%", volume)
+        }
+        ("output", [action, id]) if OUTPUT_ACTIONS.contains(action) => {
+            let id = id
+                .parse::<u32>()
+                .map_err(|_| anyhow!("Output ids are numbers"))?;
+            match *action {
+                "only" => outputs::only(conn, id).await?,
+                _ => outputs::set(conn, action, id).await?,
+            }
+            return Ok(Outcome::Done(None));
+        }
+        ("set", [option, value]) if OPTIONS.contains(option) => {
+            let status = client.status().await?;
+            let current = match *option {
+                "random" => status.random,
+                "repeat" => status.repeat,
+                "consume" => status.consume,
+                _ => status.single != "0",
+            };
+            let on = match *value {
+                "on" => true,
+                "off" => false,
+                "toggle" => !current,
+                _ => return Err(anyhow!("Expected on, off or toggle")),
+            };
+            match *option {
+                "random" => client.random(on).await?,
+                "repeat" => client.repeat(on).await?,
+                "consume" => client.consume(on).await?,
+                _ => {
+                    conn.command("single", &[if on { "1" } else { "0" }])
+                        .await?;
+                }
+            }
+            format!("{} {}", option, if on { "on" } else { "off" })
+        }
+        ("play", []) => {
+            client.play().await?;
+            return Ok(Outcome::Done(None));
+        }
+        ("pause", []) => {
+            client.pause().await?;
+            return Ok(Outcome::Done(None));
+        }
+        ("toggle", []) => {
+            match client.status().await?.state.as_str() {
+                "pause" | "stop" => client.play().await?,
+                _ => client.pause().await?,
+            }
+            return Ok(Outcome::Done(None));
+        }
+        ("next", []) => {
+            client.next().await?;
+            return Ok(Outcome::Done(None));
+        }
+        ("prev", []) => {
+            client.prev().await?;
+            return Ok(Outcome::Done(None));
+        }
+        ("stop", []) => {
+            client.stop().await?;
+            return Ok(Outcome::Done(None));
+        }
+        ("quit", []) | ("q", []) => return Ok(Outcome::Quit),
+        _ => {
+            return Err(match COMMANDS.iter().find(|(c, _)| *c == cmd) {
+                Some((_, usage)) => anyhow!("Usage: {}", usage),
+                None => anyhow!("Unknown command: {}", cmd),
+            })
+        }
+    };
+    Ok(Outcome::Done(Some(message)))
+}
+
+// Where what was typed after `cmd` starts in `line`, if `line` is that command
+fn argument(line: &str, cmd: &str) -> Option<usize> {
+    let rest = line.trim_start().strip_prefix(cmd)?;
+    if !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    Some(line.len() - rest.trim_start().len())
+}
+
+// `50`, or `+10` and `-10` relative to `current`, kept between 0 and 100
+fn volume(current: u8, change: &str) -> Result<u32> {
+    let n = change
+        .parse::<i64>()
+        .map_err(|_| anyhow!("Invalid volume {}, expected [+-]0-100", change))?;
+    let volume = match change.chars().next() {
+        Some('+') | Some('-') => current as i64 + n,
+        _ => n,
+    };
+    Ok(volume.clamp(0, 100) as u32)
+}
+
+// Turns `1:30`, `90` or `+10` into what seekcur expects
+fn seconds(time: &str) -> Result<String> {
+    let (sign, time) = match time.chars().next() {
+        Some(c @ '+') | Some(c @ '-') => (Some(c), &time[1..]),
+        _ => (None, time),
+    };
+
+    let secs = time.split(':').try_fold(0f64, |total, part| {
+        part.parse::<f64>()
+            .ok()
+            // the sign only goes in front, `inf` and `NaN` parse as well
+            .filter(|p| p.is_finite() && *p >= 0. && !part.starts_with('+'))
+            .map(|p| total * 60. + p)
+            .ok_or_else(|| anyhow!("Invalid time {}, expected [+-][mm:]ss", time))
+    })?;
+
+    Ok(match sign {
+        Some(sign) => format!("{}{}", sign, secs),
+        None => secs.to_string(),
+    })
+}
+
+// Completions for `word`, the words before it decide what is completed
+async fn candidates(
+    conn: &mut Connection,
+    words: &[&str],
+    word: &str,
+    line: &str,
+) -> Result<Vec<String>> {
+    let choices: &[&str] = match words {
+        [] => {
+            return Ok(COMMANDS
+                .iter()
+                .map(|(c, _)| *c)
+                .filter(|c| c.starts_with(word))
+                .map(|c| format!("{} ", c))
+                .collect())
+        }
+        ["add", ..] => {
+            let path = argument(line, "add").map_or("", |arg| &line[arg..]);
+            return uris(conn, path).await;
+        }
+        ["set"] => OPTIONS,
+        ["set", _] => SWITCHES,
+        ["output"] => OUTPUT_ACTIONS,
+        ["output", _] => {
+            let outputs = conn.command("outputs", &[]).await?;
+            return Ok(outputs
+                .into_iter()
+                .filter(|(k, v)| k == "outputid" && v.starts_with(word))
+                .map(|(_, v)| v)
+                .collect());
+        }
+        _ => &[],
+    };
+
+    Ok(choices
+        .iter()
+        .filter(|c| c.starts_with(word))
+        .map(|c| c.to_string())
+        .collect())
+}
+
+// Entries of the directory `path` is in, whose names start with the rest of `path`
+async fn uris(conn: &mut Connection, path: &str) -> Result<Vec<String>> {
+    let (dir, prefix) = match path.rfind('/') {
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => ("", path),
+    };
+
+    Ok(conn
+        .command("lsinfo", &[dir])
+        .await?
+        .into_iter()
+        .filter_map(|(k, v)| {
+            let name = v.rsplit('/').next().unwrap_or(&v).to_string();
+            match k.as_str() {
+                "directory" => Some(format!("{}/", name)),
+                "file" => Some(name),
+                _ => None,
+            }
+        })
+        .filter(|name| name.starts_with(prefix))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{self, Mpd};
+    use async_std::task;
+
+    #[test]
+    fn parses_seek_times() {
+        assert_eq!(seconds("+10").unwrap(), "+10");
+        assert_eq!(seconds("-10").unwrap(), "-10");
+        assert_eq!(seconds("1:30").unwrap(), "90");
+        assert_eq!(seconds("90.5").unwrap(), "90.5");
+        assert_eq!(seconds("-1:00").unwrap(), "-60");
+        for junk in [
+            "", "+", "abc", "1:x", "1:-30", "+-10", "inf", "NaN", "1e400",
+        ] {
+            assert!(seconds(junk).is_err(), "{:?} parsed", junk);
+        }
+    }
+
+    #[test]
+    fn parses_volumes() {
+        assert_eq!(volume(50, "+10").unwrap(), 60);
+        assert_eq!(volume(50, "-10").unwrap(), 40);
+        assert_eq!(volume(50, "80").unwrap(), 80);
+        assert_eq!(volume(95, "+10").unwrap(), 100);
+        assert_eq!(volume(5, "-10").unwrap(), 0);
+        assert_eq!(volume(50, "200").unwrap(), 100);
+        assert!(volume(50, "loud").is_err());
+        assert!(volume(50, "").is_err());
+    }
+
+    #[test]
+    fn finds_the_argument() {
+        assert_eq!(argument("add foo", "add"), Some(4));
+        assert_eq!(argument("  add   a/b c", "add"), Some(8));
+        assert_eq!(argument("add ", "add"), Some(4));
+        assert_eq!(argument("add", "add"), None);
+        assert_eq!(argument("added", "add"), None);
+        assert_eq!(argument("save add", "add"), None);
+    }
+
+    #[test]
+    fn completes_uris_after_leading_spaces() {
+        task::block_on(async {
+            let mpd = Mpd::start(vec![
+                mock::song("dir/one.flac", "One", "Alpha", "First"),
+                mock::song("dir/two.flac", "Two", "Alpha", "First"),
+            ])
+            .await;
+            let mut conn = Connection::new(mpd.addr).await.unwrap();
+            let mut messages = Messages::default();
+            let mut cmdline = CommandLine::default();
+            "  add dir/t".chars().for_each(|c| cmdline.push(c));
+
+            cmdline.complete(&mut conn, &mut messages).await.unwrap();
+            assert_eq!(cmdline.input(), "  add dir/two.flac");
+        });
+    }
+
+    #[test]
+    fn cycles_through_completions() {
+        task::block_on(async {
+            let mpd = Mpd::start(vec![
+                mock::song("dir/one.flac", "One", "Alpha", "First"),
+                mock::song("dir/sub/two.flac", "Two", "Alpha", "First"),
+            ])
+            .await;
+            let mut conn = Connection::new(mpd.addr).await.unwrap();
+            let mut messages = Messages::default();
+            let mut cmdline = CommandLine::default();
+
+            cmdline.push('s');
+            let mut seen = Vec::new();
+            for _ in 0..5 {
+                cmdline.complete(&mut conn, &mut messages).await.unwrap();
+                seen.push(cmdline.input().to_string());
+            }
+            assert_eq!(seen, ["save ", "seek ", "set ", "stop ", "save "]);
+
+            // directories end in a slash, which isn't where the next one goes
+            cmdline.open();
+            "add dir/".chars().for_each(|c| cmdline.push(c));
+            let mut seen = Vec::new();
+            for _ in 0..3 {
+                cmdline.complete(&mut conn, &mut messages).await.unwrap();
+                seen.push(cmdline.input().to_string());
+            }
+            assert_eq!(seen, ["add dir/sub/", "add dir/one.flac", "add dir/sub/"]);
+        });
+    }
+}
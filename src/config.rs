@@ -0,0 +1,238 @@
+use crate::keys;
+use anyhow::{Context, Result};
+use directories_next as dirs;
+use serde::Deserialize;
+use std::{fs, path::PathBuf};
+
+// Read from `config.toml` in the config dir, e.g. ~/.config/rmpc/config.toml
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // Same as MPD's music_directory, used to find files next to tracks
+    pub music_directory: Option<PathBuf>,
+    // Extra place to look for lyrics, defaults to the `lyrics` dir in the cache dir
+    pub lyrics_directory: Option<PathBuf>,
+    // Needs a fifo audio output in MPD's config
+    pub visualizer: Option<Visualizer>,
+    // Keep play counts and when songs were last played as stickers in MPD
+    pub record_plays: bool,
+    // `[[playlists]]`, queued with their key
+    pub playlists: Vec<SmartPlaylist>,
+    pub auto_dj: AutoDj,
+    pub notifications: Notifications,
+    // `[scrobble]`, listens are sent to ListenBrainz when it's there
+    pub scrobble: Option<Scrobble>,
+    pub hooks: Hooks,
+    pub keys: keys::Overrides,
+}
+
+// Keeps the queue going with songs similar to the last one
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AutoDj {
+    // on at startup, it can always be toggled with its key
+    pub enabled: bool,
+    // how many songs have to be left before adding more
+    pub below: u32,
+    // how many songs are added at once
+    pub add: usize,
+    // skip songs played in the last hours, needs record_plays
+    pub avoid_hours: Option<u32>,
+}
+
+impl Default for AutoDj {
+    fn default() -> AutoDj {
+        AutoDj {
+            enabled: false,
+            below: 3,
+            add: 5,
+            avoid_hours: Some(24),
+        }
+    }
+}
+
+// Desktop notifications when the song changes, needs the `notify` feature
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Notifications {
+    pub enabled: bool,
+    // `{title}`, `{artist}`, `{album}`, `{album_artist}`, `{genre}`, `{date}`, `{track}` and `{file}`
+    // are replaced with the song's tags
+    pub summary: String,
+    pub body: String,
+    pub cover: bool,
+    // also notify while the terminal rmpc runs in has focus
+    pub when_focused: bool,
+    // how long notifications stay in ms, the notification daemon decides if unset
+    pub timeout: Option<u32>,
+}
+
+impl Default for Notifications {
+    fn default() -> Notifications {
+        Notifications {
+            enabled: false,
+            summary: "{title}".to_string(),
+            body: "{artist}\n{album}".to_string(),
+            cover: true,
+            when_focused: false,
+            timeout: None,
+        }
+    }
+}
+
+// Shell commands run when something happens in MPD, the song's tags are in `RMPC_*` variables
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Hooks {
+    pub on_song_change: Option<String>,
+    pub on_play: Option<String>,
+    pub on_pause: Option<String>,
+    pub on_stop: Option<String>,
+    // playback stopped after the last song in the queue
+    pub on_queue_end: Option<String>,
+    // on startup and after reconnecting to MPD
+    pub on_connect: Option<String>,
+    // seconds before a hook that's still running is killed
+    pub timeout: u64,
+}
+
+impl Default for Hooks {
+    fn default() -> Hooks {
+        Hooks {
+            on_song_change: None,
+            on_play: None,
+            on_pause: None,
+            on_stop: None,
+            on_queue_end: None,
+            on_connect: None,
+            timeout: 10,
+        }
+    }
+}
+
+// Where listens are sent, anything speaking the ListenBrainz API works
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scrobble {
+    #[serde(default = "Scrobble::default_url")]
+    pub url: String,
+    // from the ListenBrainz settings page
+    pub token: String,
+}
+
+impl Scrobble {
+    fn default_url() -> String {
+        "https://api.listenbrainz.org".to_string()
+    }
+}
+
+// A playlist made from rules, the songs are picked again every time it's queued
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SmartPlaylist {
+    pub name: String,
+    // replaces the queue with the playlist
+    pub key: Option<String>,
+    // adds the playlist to the end of the queue
+    pub append_key: Option<String>,
+    // tags have to match exactly, ignoring case
+    pub artist: Option<String>,
+    pub album_artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub title: Option<String>,
+    pub from_year: Option<u32>,
+    pub to_year: Option<u32>,
+    // unrated songs count as 0
+    pub min_rating: Option<u8>,
+    pub max_rating: Option<u8>,
+    // days since the song was last played, needs record_plays
+    pub not_played_for: Option<u32>,
+    #[serde(default)]
+    pub random: bool,
+    pub limit: Option<usize>,
+    // keep adding songs from this playlist while it's playing and the queue runs low
+    #[serde(default)]
+    pub refill: bool,
+    // how many songs have to be left before refilling
+    #[serde(default = "SmartPlaylist::default_refill_below")]
+    pub refill_below: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Visualizer {
+    // `path` of the fifo audio output
+    pub fifo_path: PathBuf,
+    #[serde(default = "Visualizer::default_fps")]
+    pub fps: u32,
+    #[serde(default)]
+    pub style: VisualizerStyle,
+    // MPD's default fifo format is 44100:16:2
+    #[serde(default = "Visualizer::default_channels")]
+    pub channels: u16,
+}
+
+impl Visualizer {
+    fn default_fps() -> u32 {
+        30
+    }
+
+    fn default_channels() -> u16 {
+        2
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VisualizerStyle {
+    #[default]
+    Bars,
+    Wave,
+}
+
+impl SmartPlaylist {
+    fn default_refill_below() -> u32 {
+        5
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Config> {
+        let mut config = match Config::path() {
+            Some(path) if path.exists() => {
+                let text = fs::read_to_string(&path)
+                    .with_context(|| format!("Cannot read config file {}", path.display()))?;
+                toml::from_str(&text)
+                    .with_context(|| format!("Invalid config file {}", path.display()))?
+            }
+            _ => Config::default(),
+        };
+
+        config.music_directory = config.music_directory.map(expand);
+        if let Some(v) = &mut config.visualizer {
+            v.fifo_path = expand(v.fifo_path.clone());
+        }
+        config.lyrics_directory = config
+            .lyrics_directory
+            .map(expand)
+            .or_else(|| dirs().map(|d| d.cache_dir().join("lyrics")));
+        Ok(config)
+    }
+
+    pub fn path() -> Option<PathBuf> {
+        dirs().map(|d| d.config_dir().join("config.toml"))
+    }
+}
+
+pub fn dirs() -> Option<dirs::ProjectDirs> {
+    dirs::ProjectDirs::from("org", "abyss", "rmpc")
+}
+
+// Expands a leading `~` to the home directory
+fn expand(path: PathBuf) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::BaseDirs::new()) {
+        (Ok(rest), Some(base)) => base.home_dir().join(rest),
+        _ => path,
+    }
+}
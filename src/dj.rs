@@ -0,0 +1,165 @@
+use crate::{
+    config,
+    protocol::Connection,
+    smart::{self, Rng},
+    stickers::{self, Ratings},
+};
+use anyhow::Result;
+use async_mpd::{Filter, FilterExpr, MpdClient, Tag, Track};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+// how many of the last queued songs are never picked again
+const RECENT: usize = 200;
+
+// Picks songs like the one at the end of the queue whenever it runs low
+#[derive(Debug)]
+pub struct AutoDj {
+    enabled: bool,
+    // songs we queued or saw playing, newest last
+    recent: VecDeque<String>,
+}
+
+impl AutoDj {
+    pub fn new(config: &config::AutoDj) -> AutoDj {
+        AutoDj {
+            enabled: config.enabled,
+            recent: VecDeque::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn toggle(&mut self) -> bool {
+        self.enabled = !self.enabled;
+        self.enabled
+    }
+
+    pub fn played(&mut self, file: &str) {
+        if self.recent.back().map(String::as_str) != Some(file) {
+            self.recent.push_back(file.to_string());
+            if self.recent.len() > RECENT {
+                self.recent.pop_front();
+            }
+        }
+    }
+
+    // Adds `config.add` songs similar to `seed`, returns how many were added
+    pub async fn fill(
+        &mut self,
+        client: &mut MpdClient,
+        conn: &mut Connection,
+        ratings: &Ratings,
+        config: &config::AutoDj,
+        seed: &Track,
+        queued: &[Track],
+    ) -> Result<usize> {
+        let mut exclude = queued
+            .iter()
+            .map(|s| s.file.as_str())
+            .chain(self.recent.iter().map(String::as_str))
+            .collect::<HashSet<_>>();
+        exclude.insert(seed.file.as_str());
+
+        let recently_played = match config.avoid_hours {
+            Some(hours) => {
+                let since = chrono::Utc::now().timestamp() - hours as i64 * 3600;
+                stickers::last_played(conn)
+                    .await?
+                    .into_iter()
+                    .filter(|(_, t)| *t >= since)
+                    .map(|(f, _)| f)
+                    .collect()
+            }
+            None => HashSet::new(),
+        };
+
+        let candidates = candidates(client, seed).await?;
+        let mut weighted = candidates
+            .into_iter()
+            .filter(|(s, _)| !exclude.contains(s.file.as_str()))
+            .filter(|(s, _)| !recently_played.contains(&s.file))
+            .map(|(s, score)| {
+                // unrated songs are weighed like 2.5 stars
+                let rating = ratings.get(&s.file).map_or(1., |r| r as f64 / 2.5);
+                let weight = score as f64 * rating;
+                (s, weight)
+            })
+            .filter(|(_, w)| *w > 0.)
+            .collect::<Vec<_>>();
+
+        let mut rng = Rng::new();
+        let mut picked = Vec::new();
+        while picked.len() < config.add && !weighted.is_empty() {
+            let total = weighted.iter().map(|(_, w)| w).sum::<f64>();
+            let mut target = rng.next() as f64 / u64::MAX as f64 * total;
+            let i = weighted
+                .iter()
+                .position(|(_, w)| {
+                    target -= w;
+                    target <= 0.
+                })
+                .unwrap_or(weighted.len() - 1);
+            picked.push(weighted.swap_remove(i).0);
+        }
+
+        for song in &picked {
+            client.queue_add(&song.file).await?;
+            self.played(&song.file);
+        }
+        Ok(picked.len())
+    }
+}
+
+// Songs sharing the artist, album artist, genre or decade of `seed`, scored by how much they share
+async fn candidates(client: &mut MpdClient, seed: &Track) -> Result<Vec<(Track, u32)>> {
+    let decade = smart::year(seed).map(|y| (y / 10).to_string());
+    let searches = [
+        (Tag::Artist, seed.artist.clone(), 3),
+        (Tag::AlbumArtist, seed.album_artist.clone(), 2),
+        (Tag::Genre, seed.genre.clone(), 2),
+        // `199` matches every date in the nineties
+        (Tag::Date, decade, 1),
+    ];
+
+    let mut scores: HashMap<String, (Track, u32)> = HashMap::new();
+    for (tag, value, score) in searches.iter() {
+        let value = match value {
+            Some(v) if !v.is_empty() => v,
+            _ => continue,
+        };
+        let expr = match tag {
+            Tag::Date => FilterExpr::Contains(*tag, value.clone()),
+            _ => FilterExpr::Equals(*tag, value.clone()),
+        };
+        for song in client.search(&Filter::new().and(expr)).await? {
+            // dates only count if the year starts with the decade
+            if let Tag::Date = tag {
+                if !song
+                    .date
+                    .as_deref()
+                    .is_some_and(|d| d.starts_with(value.as_str()))
+                {
+                    continue;
+                }
+            }
+            scores
+                .entry(song.file.clone())
+                .or_insert_with(|| (song, 0))
+                .1 += score;
+        }
+    }
+
+    // nothing to go on, anything will do
+    if scores.is_empty() {
+        let all = Filter::new().and(FilterExpr::Contains(Tag::Any, String::new()));
+        return Ok(client
+            .search(&all)
+            .await?
+            .into_iter()
+            .map(|s| (s, 1))
+            .collect());
+    }
+    Ok(scores.into_values().collect())
+}
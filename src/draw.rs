@@ -1,13 +1,35 @@
-use crate::{play::Songs, search::Search, state::StatefulList};
+use crate::{
+    art::{AlbumArt, Cover, Protocol},
+    command::CommandLine,
+    config::VisualizerStyle,
+    help::Help,
+    keys::KeyMap,
+    lyrics::Lyrics,
+    message::{Message, Messages, Severity},
+    outputs::Outputs,
+    play::Songs,
+    search::Search,
+    state::StatefulList,
+    stats::{self, Library},
+    stickers::Ratings,
+    visualizer::Visualizer,
+    Mode, View,
+};
 use async_mpd::Status;
-use std::io;
+use chrono::TimeZone;
+use image::RgbImage;
 use tui::{
-    backend::CrosstermBackend,
-    layout::{Alignment, Rect},
-    style::{Color, Style},
+    backend::Backend,
+    buffer::Buffer,
+    layout::{Alignment, Constraint, Direction, Layout, Rect},
+    style::{Color, Modifier, Style},
+    symbols::Marker,
     terminal::Frame,
-    text::Span,
-    widgets::{Block, BorderType, Borders, Clear, LineGauge, Paragraph},
+    text::{Span, Spans},
+    widgets::{
+        Axis, BarChart, Block, BorderType, Borders, Cell, Chart, Clear, Dataset, GraphType,
+        LineGauge, Paragraph, Row, Table, TableState, Widget,
+    },
 };
 
 const SEARCH_BOX_HEIGHT: u16 = 3;
@@ -15,6 +37,144 @@ const SEARCH_BOX_HEIGHT: u16 = 3;
 // smallest size of heigh/width before crossterm/tui panics
 const MIN_SIZE: u16 = 2;
 
+// Everything a frame shows, borrowed from the app while it's drawn
+pub struct Screen<'a> {
+    pub songs: &'a StatefulList<Songs>,
+    pub results: &'a StatefulList<Songs>,
+    pub search: &'a Search,
+    pub mode: Mode,
+    pub view: View,
+    pub help: &'a Help,
+    pub keymap: &'a KeyMap,
+    pub cmdline: &'a CommandLine,
+    pub messages: &'a Messages,
+    pub connected: bool,
+    pub lyrics: &'a Lyrics,
+    pub outputs: &'a Outputs,
+    pub library: &'a Library,
+    pub ratings: &'a Ratings,
+    pub visualizer: Option<&'a mut Visualizer>,
+    pub art: &'a AlbumArt,
+}
+
+// Draws a whole frame, gives back where the cover goes when a graphics protocol draws it
+pub fn screen<B: Backend>(f: &mut Frame<'_, B>, screen: Screen<'_>) -> Option<Rect> {
+    let Screen {
+        songs: song_list,
+        results,
+        search: srch,
+        mode,
+        view,
+        help,
+        keymap,
+        cmdline,
+        messages,
+        connected,
+        lyrics,
+        outputs,
+        library,
+        ratings,
+        visualizer,
+        art,
+    } = screen;
+    let showing_help = mode == Mode::Help;
+    // help is drawn over whatever was there before it
+    let mode = match mode {
+        Mode::Help => help.previous(),
+        m => m,
+    };
+    let mut art_area = None;
+
+    let chunks = chunks(song_list, ratings, f);
+    let search_area = match &chunks {
+        DrawLayout::Normal { search, .. } | DrawLayout::Empty(_, search) => *search,
+    };
+
+    match chunks {
+        DrawLayout::Normal {
+            songs:
+                Chunks {
+                    list: list_area,
+                    tags: tags_area,
+                },
+            gauge: gauge_area,
+            ..
+        } => {
+            match view {
+                View::Queue => {
+                    let tag_text = song_list.tags(ratings);
+                    // popups would end up under or cut into the image
+                    if let (Some(text), Mode::Browsing | Mode::Command, false) =
+                        (&tag_text, mode, showing_help)
+                    {
+                        art_area = self::art_area(text, tags_area);
+                    }
+                    tags(tag_text, f, tags_area);
+                    if let (Some(area), Some(cover), Protocol::HalfBlock) =
+                        (art_area, art.current(), art.protocol())
+                    {
+                        self::art(cover, f, area);
+                    }
+                    list(song_list, ratings, f, list_area);
+                }
+                View::Lyrics => {
+                    self::lyrics(lyrics, song_list.status(), f, list_area.union(tags_area))
+                }
+                View::Visualizer => self::visualizer(visualizer, f, list_area.union(tags_area)),
+                View::Outputs => self::outputs(outputs, f, list_area.union(tags_area)),
+                View::Stats => stats(library, f, list_area.union(tags_area)),
+            }
+            gauge(song_list.status(), f, gauge_area);
+        }
+        DrawLayout::Empty(songs, _) => match view {
+            View::Queue => list(song_list, ratings, f, songs),
+            View::Lyrics => self::lyrics(lyrics, song_list.status(), f, songs),
+            View::Visualizer => self::visualizer(visualizer, f, songs),
+            View::Outputs => self::outputs(outputs, f, songs),
+            View::Stats => stats(library, f, songs),
+        },
+    }
+    if let Mode::Searching | Mode::Selecting = mode {
+        if f.size().height >= 3 {
+            search(results, ratings, f, search_area, srch);
+        }
+    }
+
+    if showing_help {
+        self::help(help, keymap, f);
+    } else if mode == Mode::Command {
+        let (x, y) = command_line(cmdline, f);
+        f.set_cursor(x, y);
+    } else {
+        if !connected {
+            // stays up until MPD is back, unlike messages
+            let message = Message {
+                text: "Disconnected from MPD, retrying…".to_string(),
+                severity: Severity::Warning,
+            };
+            self::message(&message, messages.len(), f);
+        } else if let Some(message) = messages.current() {
+            self::message(message, messages.pending(), f);
+        }
+
+        if let Mode::Searching | Mode::Selecting = mode {
+            let search_box = srch.get(search_area.width as usize);
+
+            let columns = ((search_area.x + 1) as usize + search_box.len()) as u16;
+            let rows = search_area.height / 2;
+
+            if !results.is_empty() {
+                if mode == Mode::Searching {
+                    f.set_cursor(columns, 2);
+                }
+            } else {
+                f.set_cursor(columns, rows);
+            }
+        }
+    }
+    art_area
+}
+
 // Playing layout
 //
 //       Song list             Tags for selected song
@@ -31,16 +191,20 @@ const MIN_SIZE: u16 = 2;
 //            /\
 //  Progress of current song
 
-pub fn list<'a>(
-    events: &mut StatefulList<Songs>,
-    f: &mut Frame<'a, CrosstermBackend<io::Stdout>>,
+pub fn list<B: Backend>(
+    events: &StatefulList<Songs>,
+    ratings: &Ratings,
+    f: &mut Frame<'_, B>,
     chunk: Rect,
 ) {
+    let mut title = vec![Span::styled(" Songs ", Style::default().fg(Color::White))];
+    title.extend(updating(events.status()));
+
     let list = events
-        .list()
+        .list(ratings)
         .block(
             Block::default()
-                .title([Span::styled(" Songs ", Style::default().fg(Color::White))].to_vec())
+                .title(title)
                 .borders(Borders::ALL)
                 .border_style(Style::default().fg(Color::Magenta))
                 .border_type(BorderType::Rounded),
@@ -50,11 +214,7 @@ pub fn list<'a>(
     f.render_stateful_widget(list, chunk, &mut *events.state());
 }
 
-pub fn gauge<'a>(
-    status: Option<&Status>,
-    f: &mut Frame<'a, CrosstermBackend<io::Stdout>>,
-    chunk: Rect,
-) {
+pub fn gauge<B: Backend>(status: Option<&Status>, f: &mut Frame<'_, B>, chunk: Rect) {
     if let Some(status) = status {
         let (elapsed, duration) = match (status.elapsed, status.duration) {
             (Some(e), Some(d)) => (e.as_secs_f64(), d.as_secs_f64()),
@@ -66,17 +226,25 @@ pub fn gauge<'a>(
         let gauge = LineGauge::default()
             .block(
                 Block::default()
+                    .title(updating(Some(status)).into_iter().collect::<Vec<_>>())
                     .borders(Borders::ALL)
                     .border_type(BorderType::Rounded),
             )
             .ratio(ratio);
         f.render_widget(gauge, chunk);
-    } else {
-        log::error!("Cannot get song status");
     }
 }
 
-pub fn tags(tags: Option<String>, f: &mut Frame<'_, CrosstermBackend<io::Stdout>>, chunk: Rect) {
+// Title for while MPD is scanning the music directory
+fn updating(status: Option<&Status>) -> Option<Span<'static>> {
+    let job = status?.updating_db?;
+    Some(Span::styled(
+        format!(" Updating database (job {}) ", job),
+        Style::default().fg(Color::Yellow),
+    ))
+}
+
+pub fn tags<B: Backend>(tags: Option<String>, f: &mut Frame<'_, B>, chunk: Rect) {
     if let Some(tags) = tags {
         let tags = Paragraph::new(&*tags)
             .block(
@@ -92,9 +260,477 @@ pub fn tags(tags: Option<String>, f: &mut Frame<'_, CrosstermBackend<io::Stdout>
     }
 }
 
-pub fn search<'a>(
-    list: &mut StatefulList<Songs>,
-    f: &mut Frame<'a, CrosstermBackend<io::Stdout>>,
+// Space left for the cover art below the tags, inside the tags border
+pub fn art_area(tags: &str, chunk: Rect) -> Option<Rect> {
+    // borders, tag lines and an empty line between tags and art
+    let used = tags.lines().count() as u16 + 3;
+    let area = Rect {
+        x: chunk.x + 1,
+        y: chunk.y + used,
+        width: chunk.width.saturating_sub(2),
+        height: chunk.height.saturating_sub(used + 1),
+    };
+
+    if area.width >= MIN_SIZE && area.height >= MIN_SIZE {
+        Some(area)
+    } else {
+        None
+    }
+}
+
+// Draws the cover with unicode half blocks, two pixels per cell
+pub fn art<B: Backend>(cover: &Cover, f: &mut Frame<'_, B>, chunk: Rect) {
+    let image = cover.fit(chunk.width as u32, chunk.height as u32 * 2);
+    f.render_widget(HalfBlocks(&image), chunk);
+}
+
+struct HalfBlocks<'a>(&'a RgbImage);
+
+impl Widget for HalfBlocks<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let (width, height) = self.0.dimensions();
+        let rows = height.div_ceil(2);
+
+        // center the image in the area
+        let x = area.x + (area.width.saturating_sub(width as u16)) / 2;
+        let y = area.y + (area.height.saturating_sub(rows as u16)) / 2;
+
+        for row in 0..rows.min(area.height as u32) {
+            for col in 0..width.min(area.width as u32) {
+                let rgb = |y: u32| {
+                    let p = self.0.get_pixel(col, y.min(height - 1));
+                    Color::Rgb(p[0], p[1], p[2])
+                };
+
+                buf.get_mut(x + col as u16, y + row as u16)
+                    .set_char('▀')
+                    .set_fg(rgb(row * 2))
+                    .set_bg(rgb(row * 2 + 1));
+            }
+        }
+    }
+}
+
+// Synced lyrics keep the current line in the middle and highlighted,
+// plain lyrics are scrolled by hand
+pub fn lyrics<B: Backend>(
+    lyrics: &Lyrics,
+    status: Option<&Status>,
+    f: &mut Frame<'_, B>,
+    chunk: Rect,
+) {
+    let block = Block::default()
+        .title(" Lyrics ")
+        .border_type(BorderType::Rounded)
+        .borders(Borders::ALL);
+
+    if lyrics.is_empty() {
+        let middle = chunk.height.saturating_sub(2) / 2;
+        let mut text = vec![Spans::default(); middle as usize];
+        text.push(Spans::from("No lyrics found for this song"));
+
+        f.render_widget(
+            Paragraph::new(text)
+                .block(block)
+                .alignment(Alignment::Center),
+            chunk,
+        );
+        return;
+    }
+
+    let current = status
+        .and_then(|s| s.elapsed)
+        .and_then(|e| lyrics.current(e));
+    let scroll = match current {
+        Some(i) => (i as u16).saturating_sub(chunk.height.saturating_sub(2) / 2),
+        None => lyrics.scroll(),
+    };
+
+    let text = lyrics
+        .lines()
+        .iter()
+        .enumerate()
+        .map(|(i, l)| {
+            if Some(i) == current {
+                Spans::from(Span::styled(
+                    l.text.as_str(),
+                    Style::default()
+                        .fg(Color::Magenta)
+                        .add_modifier(Modifier::BOLD),
+                ))
+            } else {
+                Spans::from(l.text.as_str())
+            }
+        })
+        .collect::<Vec<_>>();
+
+    f.render_widget(
+        Paragraph::new(text)
+            .block(block)
+            .alignment(Alignment::Center)
+            .scroll((scroll, 0)),
+        chunk,
+    );
+}
+
+// Every output with its plugin and a checkbox for whether it's enabled
+pub fn outputs<B: Backend>(outputs: &Outputs, f: &mut Frame<'_, B>, chunk: Rect) {
+    let rows = outputs.outputs().iter().map(|o| {
+        let enabled = if o.enabled { "[x]" } else { "[ ]" };
+        Row::new(vec![
+            Cell::from(enabled),
+            Cell::from(o.id.to_string()),
+            Cell::from(o.name.as_str()),
+            Cell::from(o.plugin.as_str()),
+        ])
+        .style(if o.enabled {
+            Style::default()
+        } else {
+            Style::default().fg(Color::DarkGray)
+        })
+    });
+
+    let table = Table::new(rows)
+        .header(
+            Row::new(vec!["", "ID", "Name", "Plugin"])
+                .style(Style::default().add_modifier(Modifier::BOLD)),
+        )
+        .block(
+            Block::default()
+                .title(" Outputs ")
+                .borders(Borders::ALL)
+                .border_style(Style::default().fg(Color::Magenta))
+                .border_type(BorderType::Rounded),
+        )
+        .widths(&[
+            Constraint::Length(3),
+            Constraint::Length(3),
+            Constraint::Percentage(60),
+            Constraint::Percentage(40),
+        ])
+        .column_spacing(2)
+        .highlight_style(Style::default().fg(Color::Magenta))
+        .highlight_symbol(">> ");
+
+    let mut state = TableState::default();
+    if !outputs.outputs().is_empty() {
+        state.select(Some(outputs.selected_index()));
+    }
+    f.render_stateful_widget(table, chunk, &mut state);
+}
+
+pub fn visualizer<B: Backend>(
+    visualizer: Option<&mut Visualizer>,
+    f: &mut Frame<'_, B>,
+    chunk: Rect,
+) {
+    let block = Block::default()
+        .title(" Visualizer ")
+        .border_type(BorderType::Rounded)
+        .borders(Borders::ALL);
+    let width = chunk.width.saturating_sub(2) as usize;
+
+    let visualizer = match visualizer {
+        Some(v) => v,
+        None => {
+            let middle = chunk.height.saturating_sub(2) / 2;
+            let mut text = vec![Spans::default(); middle as usize];
+            text.push(Spans::from("Set visualizer.fifo_path in config.toml"));
+            f.render_widget(
+                Paragraph::new(text)
+                    .block(block)
+                    .alignment(Alignment::Center),
+                chunk,
+            );
+            return;
+        }
+    };
+
+    match visualizer.style() {
+        VisualizerStyle::Bars => {
+            // bars are 2 wide with a gap of 1
+            let data = visualizer
+                .spectrum(width / 3)
+                .into_iter()
+                .map(|v| ("", v))
+                .collect::<Vec<_>>();
+            let bars = BarChart::default()
+                .block(block)
+                .data(&data)
+                .max(100)
+                .bar_width(2)
+                .bar_gap(1)
+                .bar_style(Style::default().fg(Color::Magenta))
+                // hides the values drawn on the bars
+                .value_style(Style::default().fg(Color::Magenta).bg(Color::Magenta));
+            f.render_widget(bars, chunk);
+        }
+        VisualizerStyle::Wave => {
+            // braille has 2 dots per cell horizontally
+            let points = visualizer.wave(width * 2);
+            let wave = Chart::new(vec![Dataset::default()
+                .marker(Marker::Braille)
+                .graph_type(GraphType::Line)
+                .style(Style::default().fg(Color::Magenta))
+                .data(&points)])
+            .block(block)
+            .x_axis(Axis::default().bounds([0., points.len() as f64]))
+            .y_axis(Axis::default().bounds([-1., 1.]));
+            f.render_widget(wave, chunk);
+        }
+    }
+}
+
+// Counts from MPD on top, genres, decades and the longest albums below
+pub fn stats<B: Backend>(library: &Library, f: &mut Frame<'_, B>, chunk: Rect) {
+    let block = |title| {
+        Block::default()
+            .title(title)
+            .border_type(BorderType::Rounded)
+            .borders(Borders::ALL)
+    };
+
+    let stats = match library.stats() {
+        Some(s) => s,
+        None => {
+            let middle = chunk.height.saturating_sub(2) / 2;
+            let mut text = vec![Spans::default(); middle as usize];
+            text.push(Spans::from("Going through the database…"));
+            f.render_widget(
+                Paragraph::new(text)
+                    .block(block(" Stats "))
+                    .alignment(Alignment::Center),
+                chunk,
+            );
+            return;
+        }
+    };
+
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(6), Constraint::Min(0)].as_ref())
+        .split(chunk);
+    let bottom = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints(
+            [
+                Constraint::Percentage(30),
+                Constraint::Percentage(30),
+                Constraint::Percentage(40),
+            ]
+            .as_ref(),
+        )
+        .split(chunks[1]);
+
+    let updated = chrono::Local
+        .timestamp_opt(stats.db_update as i64, 0)
+        .single()
+        .map_or("never".to_string(), |t| {
+            t.format("%Y-%m-%d %H:%M").to_string()
+        });
+    let bold = Style::default().add_modifier(Modifier::BOLD);
+    let summary = Table::new(vec![
+        Row::new(vec![
+            Cell::from("Artists").style(bold),
+            Cell::from(stats.artists.to_string()),
+            Cell::from("Database playtime").style(bold),
+            Cell::from(stats::duration(stats.db_playtime)),
+        ]),
+        Row::new(vec![
+            Cell::from("Albums").style(bold),
+            Cell::from(stats.albums.to_string()),
+            Cell::from("Played since start").style(bold),
+            Cell::from(stats::duration(stats.playtime)),
+        ]),
+        Row::new(vec![
+            Cell::from("Songs").style(bold),
+            Cell::from(stats.songs.to_string()),
+            Cell::from("Uptime").style(bold),
+            Cell::from(stats::duration(stats.uptime)),
+        ]),
+        Row::new(vec![
+            Cell::from(""),
+            Cell::from(""),
+            Cell::from("Last update").style(bold),
+            Cell::from(updated),
+        ]),
+    ])
+    .block(block(" Stats "))
+    .widths(&[
+        Constraint::Length(10),
+        Constraint::Length(10),
+        Constraint::Length(20),
+        Constraint::Min(0),
+    ]);
+    f.render_widget(summary, chunks[0]);
+
+    // genre names are too long for bar labels, so the bars are drawn as text
+    let most = library.genres().first().map_or(1, |(_, n)| *n).max(1);
+    let bar_width = bottom[0].width.saturating_sub(2) / 2;
+    let genres = Table::new(library.genres().iter().map(|(genre, n)| {
+        let bar = "█".repeat((*n * bar_width as u64 / most) as usize);
+        Row::new(vec![
+            Cell::from(genre.as_str()),
+            Cell::from(Span::styled(bar, Style::default().fg(Color::Magenta))),
+            Cell::from(n.to_string()),
+        ])
+    }))
+    .block(block(" Top genres "))
+    .widths(&[
+        Constraint::Percentage(40),
+        Constraint::Percentage(45),
+        Constraint::Percentage(15),
+    ]);
+    f.render_widget(genres, bottom[0]);
+
+    let decades = library
+        .decades()
+        .iter()
+        .map(|(d, n)| (d.as_str(), *n))
+        .collect::<Vec<_>>();
+    let width = bottom[1].width.saturating_sub(2) / (decades.len() as u16).max(1);
+    let decades = BarChart::default()
+        .block(block(" Decades "))
+        .data(&decades)
+        .bar_width(width.saturating_sub(1).clamp(1, 5))
+        .bar_gap(1)
+        .bar_style(Style::default().fg(Color::Magenta))
+        .value_style(Style::default().fg(Color::Black).bg(Color::Magenta));
+    f.render_widget(decades, bottom[1]);
+
+    let albums = Table::new(library.albums().iter().map(|(album, d)| {
+        Row::new(vec![
+            Cell::from(album.as_str()),
+            Cell::from(stats::duration(*d)),
+        ])
+    }))
+    .block(block(" Longest albums "))
+    .widths(&[Constraint::Percentage(75), Constraint::Percentage(25)]);
+    f.render_widget(albums, bottom[2]);
+}
+
+// Popup in the middle of the screen listing the bindings of the mode help was opened from
+pub fn help<B: Backend>(help: &Help, keymap: &KeyMap, f: &mut Frame<'_, B>) {
+    let term = f.size();
+    let rows = help.rows(keymap);
+
+    let width = (term.width * 3 / 4).max(term.width.min(40));
+    // borders and the filter line
+    let height = (rows.len() as u16 + 4).min(term.height);
+    let area = Rect {
+        x: (term.width - width) / 2,
+        y: (term.height - height) / 2,
+        width,
+        height,
+    };
+
+    let key_width = rows.iter().map(|(k, _)| k.len()).max().unwrap_or(0);
+    let mut text = rows
+        .iter()
+        .skip(help.scroll())
+        .map(|(keys, desc)| {
+            Spans::from(vec![
+                Span::styled(
+                    format!(" {:width$}   ", keys, width = key_width),
+                    Style::default().fg(Color::Magenta),
+                ),
+                Span::raw(*desc),
+            ])
+        })
+        .collect::<Vec<_>>();
+    if rows.is_empty() {
+        text.push(Spans::from(" No bindings match the filter"));
+    }
+
+    let filter = if help.is_filtering() || !help.filter().is_empty() {
+        format!(" /{} ", help.filter())
+    } else {
+        " / to filter ".to_string()
+    };
+
+    let title = format!(" Help: {} ", help.previous().name());
+    let block = Block::default()
+        .title(Span::styled(title, Style::default().fg(Color::White)))
+        .borders(Borders::ALL)
+        .border_style(Style::default().fg(Color::Magenta))
+        .border_type(BorderType::Rounded);
+    let inner = block.inner(area);
+
+    f.render_widget(Clear, area);
+    f.render_widget(block, area);
+    f.render_widget(
+        Paragraph::new(text),
+        Rect {
+            height: inner.height.saturating_sub(1),
+            ..inner
+        },
+    );
+    f.render_widget(
+        Paragraph::new(filter).alignment(Alignment::Right),
+        Rect {
+            y: inner.y + inner.height.saturating_sub(1),
+            height: inner.height.min(1),
+            ..inner
+        },
+    );
+}
+
+// The `:` prompt on the last line, gives back where the cursor goes
+pub fn command_line<B: Backend>(cmdline: &CommandLine, f: &mut Frame<'_, B>) -> (u16, u16) {
+    let term = f.size();
+    let line = Rect {
+        y: term.height.saturating_sub(1),
+        height: term.height.min(1),
+        ..term
+    };
+
+    // keep the end of long commands visible
+    let width = line.width.saturating_sub(2) as usize;
+    let input = cmdline.input();
+    let start = input
+        .char_indices()
+        .map(|(i, _)| i)
+        .find(|i| input[*i..].chars().count() <= width)
+        .unwrap_or(input.len());
+    let text = format!(":{}", &input[start..]);
+    let cursor = line.x + text.chars().count() as u16;
+
+    f.render_widget(Clear, line);
+    f.render_widget(Paragraph::new(text), line);
+    (cursor, line.y)
+}
+
+// The oldest notification on the last line, with how many are waiting behind it
+pub fn message<B: Backend>(message: &Message, pending: usize, f: &mut Frame<'_, B>) {
+    let term = f.size();
+    let line = Rect {
+        y: term.height.saturating_sub(1),
+        height: term.height.min(1),
+        ..term
+    };
+    let style = match message.severity {
+        Severity::Info => Style::default(),
+        Severity::Success => Style::default().fg(Color::Green),
+        Severity::Warning => Style::default().fg(Color::Yellow),
+        Severity::Error => Style::default().fg(Color::Red),
+    };
+
+    let mut spans = vec![Span::styled(message.text.as_str(), style)];
+    if pending > 0 {
+        spans.push(Span::styled(
+            format!(" (+{})", pending),
+            Style::default().fg(Color::DarkGray),
+        ));
+    }
+
+    f.render_widget(Clear, line);
+    f.render_widget(Paragraph::new(Spans::from(spans)), line);
+}
+
+pub fn search<B: Backend>(
+    list: &StatefulList<Songs>,
+    ratings: &Ratings,
+    f: &mut Frame<'_, B>,
     chunk: Rect,
     input: &Search,
 ) {
@@ -121,6 +757,7 @@ pub fn search<'a>(
             width,
         };
 
+        let search = clip(search, f.size());
         f.render_widget(Clear, search);
         f.render_widget(search_box, search);
     } else {
@@ -140,12 +777,12 @@ pub fn search<'a>(
         let results = Rect {
             x: chunk.x,
             y: SEARCH_BOX_HEIGHT + 1,
-            height: chunk.height - SEARCH_BOX_HEIGHT - 5,
+            height: chunk.height.saturating_sub(SEARCH_BOX_HEIGHT + 5),
             width,
         };
 
         let results_box = list
-            .list()
+            .list(ratings)
             .block(
                 Block::default()
                     .title([Span::styled(" Songs ", Style::default().fg(Color::White))].to_vec())
@@ -155,15 +792,26 @@ pub fn search<'a>(
             )
             .highlight_style(Style::default().fg(Color::Magenta))
             .highlight_symbol(">> ");
-        f.render_widget(Clear, clear);
-        f.render_widget(search_box, search);
-        f.render_stateful_widget(results_box, results, &mut *list.state());
+        let term = f.size();
+        f.render_widget(Clear, clip(clear, term));
+        f.render_widget(search_box, clip(search, term));
+        f.render_stateful_widget(results_box, clip(results, term), &mut *list.state());
+    }
+}
+
+// The part of `area` inside `to`, popups have fixed sizes that don't fit small terminals
+fn clip(area: Rect, to: Rect) -> Rect {
+    if area.intersects(to) {
+        area.intersection(to)
+    } else {
+        Rect::default()
     }
 }
 
-pub fn chunks<'a>(
-    events: &mut StatefulList<Songs>,
-    f: &mut Frame<'a, CrosstermBackend<io::Stdout>>,
+pub fn chunks<B: Backend>(
+    events: &StatefulList<Songs>,
+    ratings: &Ratings,
+    f: &mut Frame<'_, B>,
 ) -> DrawLayout {
     let term = f.size();
 
@@ -188,7 +836,7 @@ pub fn chunks<'a>(
             (songs, gauge)
         })
         .and_then(|(songs, gauge)| {
-            events.tags().and_then(|tags| {
+            events.tags(ratings).and_then(|tags| {
                 let longest = (tags.split('\n').fold(0, |mut l, s| {
                     if l < s.len() {
                         l = s.len();
@@ -242,11 +890,11 @@ fn search_box(f: Rect) -> Rect {
     let cells = f.width % 10;
     let width = if cells >= MIN_SIZE {
         f.width - cells
-    } else if cells < MIN_SIZE {
-        f.width - (cells + 10)
     } else {
-        5
+        f.width.saturating_sub(cells + 10)
     };
+    // too narrow to leave any room around it
+    let width = if width == 0 { f.width } else { width };
 
     let middle = f.width / 2;
 
@@ -273,3 +921,282 @@ pub struct Chunks {
     pub list: Rect,
     pub tags: Rect,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{keys::Overrides, mock, outputs, protocol::Connection};
+    use async_mpd::Track;
+    use async_std::task;
+    use std::{env, fs, path::PathBuf, time::Duration};
+    use tui::{backend::TestBackend, Terminal};
+
+    // Sizes from a normal terminal down to a sliver, none of them may panic
+    const SIZES: &[(u16, u16)] = &[
+        (120, 40),
+        (80, 24),
+        (45, 12),
+        (30, 8),
+        (20, 5),
+        (11, 3),
+        (9, 2),
+        (1, 1),
+        (0, 0),
+    ];
+
+    // Everything `Screen` borrows
+    #[derive(Default)]
+    struct App {
+        songs: StatefulList<Songs>,
+        results: StatefulList<Songs>,
+        search: Search,
+        mode: Mode,
+        view: View,
+        help: Help,
+        cmdline: CommandLine,
+        messages: Messages,
+        disconnected: bool,
+        lyrics: Lyrics,
+        outputs: Outputs,
+        library: Library,
+        ratings: Ratings,
+        art: AlbumArt,
+    }
+
+    impl App {
+        fn render(&self, width: u16, height: u16) -> String {
+            let keymap = KeyMap::new(&Overrides::default(), &[]).unwrap();
+            let mut term = Terminal::new(TestBackend::new(width, height)).unwrap();
+            term.draw(|f| {
+                screen(
+                    f,
+                    Screen {
+                        songs: &self.songs,
+                        results: &self.results,
+                        search: &self.search,
+                        mode: self.mode,
+                        view: self.view,
+                        help: &self.help,
+                        keymap: &keymap,
+                        cmdline: &self.cmdline,
+                        messages: &self.messages,
+                        connected: !self.disconnected,
+                        lyrics: &self.lyrics,
+                        outputs: &self.outputs,
+                        library: &self.library,
+                        ratings: &self.ratings,
+                        visualizer: None,
+                        art: &self.art,
+                    },
+                );
+            })
+            .unwrap();
+
+            let buffer = term.backend().buffer();
+            let mut text = String::new();
+            for y in 0..buffer.area.height {
+                let line = (0..buffer.area.width)
+                    .map(|x| buffer.get(x, y).symbol.as_str())
+                    .collect::<String>();
+                text.push_str(line.trim_end());
+                text.push('\n');
+            }
+            text
+        }
+    }
+
+    fn track(id: u32, title: &str) -> Track {
+        Track {
+            file: format!("music/{}.flac", id),
+            id: Some(id),
+            title: Some(title.to_string()),
+            artist: Some("Artist".to_string()),
+            album: Some("Album".to_string()),
+            date: Some("1999".to_string()),
+            duration: Duration::from_secs(200),
+            ..Track::default()
+        }
+    }
+
+    fn playing(songs: &mut StatefulList<Songs>, id: u32) {
+        songs.set_status(Some(Status {
+            state: "play".to_string(),
+            songid: Some(id),
+            elapsed: Some(Duration::from_secs(50)),
+            duration: Some(Duration::from_secs(200)),
+            ..Status::default()
+        }));
+    }
+
+    fn queue(count: u32) -> App {
+        let mut app = App::default();
+        let tracks = (1..=count)
+            .map(|i| track(i, &format!("Song {}", i)))
+            .collect::<Vec<_>>();
+        app.songs.set_songs(&tracks);
+        if count > 0 {
+            app.songs.select(0);
+            playing(&mut app.songs, 1);
+        }
+        app
+    }
+
+    // Compares with snapshots/<name>.txt, which is written instead with UPDATE_SNAPSHOTS=1
+    fn snapshot(name: &str, app: &App, (width, height): (u16, u16)) {
+        let actual = app.render(width, height);
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("src/snapshots")
+            .join(format!("{}.txt", name));
+
+        if env::var_os("UPDATE_SNAPSHOTS").is_some() {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, &actual).unwrap();
+            return;
+        }
+        let expected = fs::read_to_string(&path).unwrap_or_else(|_| {
+            panic!(
+                "No snapshot at {}, run with UPDATE_SNAPSHOTS=1 to write it:\n{}",
+                path.display(),
+                actual
+            )
+        });
+        assert!(
+            actual == expected,
+            "{} changed, run with UPDATE_SNAPSHOTS=1 if that's intended\nexpected:\n{}\nactual:\n{}",
+            name,
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn queue_views() {
+        snapshot("empty_queue", &queue(0), (60, 12));
+        snapshot("queue", &queue(3), (80, 16));
+
+        let mut app = queue(40);
+        app.songs.select_last();
+        snapshot("long_queue_scrolled", &app, (60, 12));
+
+        let mut app = queue(2);
+        app.songs
+            .set_songs(&[track(1, &"Very long title ".repeat(10)), track(2, "Short")]);
+        snapshot("long_titles", &app, (60, 10));
+
+        let mut app = queue(3);
+        app.songs.set_status(Some(Status {
+            updating_db: Some(7),
+            ..Status::default()
+        }));
+        snapshot("updating_database", &app, (80, 10));
+    }
+
+    #[test]
+    fn other_views() {
+        let mut app = queue(3);
+        app.view = View::Lyrics;
+        snapshot("no_lyrics", &app, (60, 10));
+        app.lyrics = Lyrics::parse(
+            "music/1.flac",
+            "[00:10.00]First line\n[00:45.00]Second line\n[01:30.00]Third line",
+        );
+        snapshot("synced_lyrics", &app, (60, 10));
+
+        app.view = View::Visualizer;
+        snapshot("no_visualizer", &app, (60, 10));
+
+        app.view = View::Stats;
+        snapshot("stats_loading", &app, (60, 10));
+
+        app.view = View::Outputs;
+        task::block_on(async {
+            let mpd = mock::Mpd::start(Vec::new()).await;
+            let mut conn = Connection::new(mpd.addr).await.unwrap();
+            app.outputs.set(outputs::fetch(&mut conn).await.unwrap());
+        });
+        app.outputs.next();
+        snapshot("outputs", &app, (60, 8));
+    }
+
+    #[test]
+    fn search() {
+        let mut app = queue(3);
+        app.mode = Mode::Searching;
+        app.search.push('x');
+        snapshot("search_without_results", &app, (60, 12));
+
+        app.search.clear();
+        app.search.push('s');
+        let found = [track(7, "Something"), track(8, "Sometimes")];
+        app.results.set_songs(&found);
+        snapshot("search_results", &app, (60, 12));
+
+        app.mode = Mode::Selecting;
+        app.results.select(0);
+        app.results.next();
+        snapshot("selecting_result", &app, (60, 12));
+    }
+
+    #[test]
+    fn popups() {
+        let mut app = queue(3);
+        app.help.open(Mode::Browsing);
+        app.mode = Mode::Help;
+        snapshot("help", &app, (80, 24));
+        app.help.set_filtering(true);
+        for c in "pla".chars() {
+            app.help.push(c);
+        }
+        snapshot("help_filtered", &app, (80, 12));
+
+        let mut app = queue(3);
+        app.mode = Mode::Command;
+        app.cmdline.open();
+        for c in "volume +5".chars() {
+            app.cmdline.push(c);
+        }
+        snapshot("command_line", &app, (60, 8));
+
+        let mut app = queue(3);
+        app.messages.success("Added 3 songs");
+        app.messages.error("Something went wrong");
+        snapshot("message", &app, (60, 8));
+
+        app.disconnected = true;
+        snapshot("disconnected", &app, (60, 8));
+    }
+
+    #[test]
+    fn tiny_terminals() {
+        snapshot("queue_tiny", &queue(3), (20, 5));
+
+        for &(width, height) in SIZES {
+            for count in [0, 3, 40] {
+                for view in [
+                    View::Queue,
+                    View::Lyrics,
+                    View::Visualizer,
+                    View::Outputs,
+                    View::Stats,
+                ] {
+                    for mode in [
+                        Mode::Browsing,
+                        Mode::Searching,
+                        Mode::Selecting,
+                        Mode::Help,
+                        Mode::Command,
+                    ] {
+                        let mut app = queue(count);
+                        app.view = view;
+                        app.mode = mode;
+                        app.search.push('s');
+                        app.results.set_songs(&[track(7, "Something")]);
+                        app.help.open(Mode::Browsing);
+                        app.messages.info("Hello");
+                        app.render(width, height);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use crate::{keys::KeyMap, Mode};
+
+// State of the help popup
+#[derive(Debug, Default)]
+pub struct Help {
+    // the mode help was opened from, its bindings are the ones shown
+    previous: Mode,
+    filter: String,
+    filtering: bool,
+    scroll: usize,
+}
+
+impl Help {
+    pub fn open(&mut self, previous: Mode) {
+        *self = Help {
+            previous,
+            ..Default::default()
+        };
+    }
+
+    pub fn previous(&self) -> Mode {
+        self.previous
+    }
+
+    pub fn filter(&self) -> &str {
+        &self.filter
+    }
+
+    pub fn is_filtering(&self) -> bool {
+        self.filtering
+    }
+
+    pub fn set_filtering(&mut self, filtering: bool) {
+        self.filtering = filtering;
+    }
+
+    pub fn push(&mut self, c: char) {
+        self.filter.push(c);
+        self.scroll = 0;
+    }
+
+    pub fn pop(&mut self) {
+        self.filter.pop();
+        self.scroll = 0;
+    }
+
+    pub fn scroll(&self) -> usize {
+        self.scroll
+    }
+
+    pub fn scroll_to(&mut self, scroll: usize, keymap: &KeyMap) {
+        self.scroll = scroll.min(self.rows(keymap).len().saturating_sub(1));
+    }
+
+    // Keys and descriptions of every binding matching the filter
+    pub fn rows<'a>(&self, keymap: &'a KeyMap) -> Vec<(String, &'a str)> {
+        let filter = self.filter.to_lowercase();
+
+        keymap
+            .actions(self.previous)
+            .into_iter()
+            .map(|(action, keys)| (keys.join(", "), keymap.description(action)))
+            .filter(|(keys, desc)| {
+                filter.is_empty()
+                    || keys.to_lowercase().contains(&filter)
+                    || desc.to_lowercase().contains(&filter)
+            })
+            .collect()
+    }
+}
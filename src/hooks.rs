@@ -0,0 +1,273 @@
+use crate::config;
+use async_mpd::{Status, Track};
+use async_std::{
+    future,
+    process::{Command, Stdio},
+    task,
+};
+use std::time::Duration;
+
+// What the hooks compare a new status against
+#[derive(Debug)]
+struct Seen {
+    state: String,
+    song: Option<u32>,
+    // whether another song was queued after it
+    next: bool,
+    // how much of the song was left to play
+    left: Option<Duration>,
+}
+
+impl Seen {
+    fn new(status: &Status) -> Seen {
+        Seen {
+            state: status.state.clone(),
+            song: status.songid,
+            next: status.nextsongid.is_some(),
+            left: status
+                .duration
+                .zip(status.elapsed)
+                .map(|(d, e)| d.saturating_sub(e)),
+        }
+    }
+}
+
+// How close to its end the last song has to have been for a stop to mean the queue ended.
+// The status is fetched every half a second while playing.
+const QUEUE_END: Duration = Duration::from_secs(2);
+
+// Which hooks `status` calls for, after `last` was seen
+fn events(last: &Seen, status: &Status) -> Vec<&'static str> {
+    let mut events = Vec::new();
+    if status.songid.is_some() && status.songid != last.song {
+        events.push("song_change");
+    }
+    if status.state != last.state {
+        events.push(match status.state.as_str() {
+            "play" => "play",
+            "pause" => "pause",
+            _ => "stop",
+        });
+
+        // stopping in the middle of the last song is someone pressing stop
+        let ended = last.left.is_some_and(|l| l <= QUEUE_END);
+        if status.state == "stop" && last.state == "play" && !last.next && ended {
+            events.push("queue_end");
+        }
+    }
+    events
+}
+
+// Runs the configured hooks when the song or playback state changes
+#[derive(Debug, Default)]
+pub struct Hooks {
+    last: Option<Seen>,
+}
+
+impl Hooks {
+    // After connecting, the status is how things were before, nothing happened yet
+    pub fn connected(
+        &mut self,
+        config: &config::Hooks,
+        status: Option<&Status>,
+        track: Option<&Track>,
+    ) {
+        self.last = status.map(Seen::new);
+        run("connect", &config.on_connect, config.timeout, status, track);
+    }
+
+    pub fn update(
+        &mut self,
+        config: &config::Hooks,
+        status: Option<&Status>,
+        track: Option<&Track>,
+    ) {
+        let status = match status {
+            Some(s) => s,
+            None => return,
+        };
+        let last = match self.last.replace(Seen::new(status)) {
+            Some(last) => last,
+            None => return,
+        };
+
+        for event in events(&last, status) {
+            let hook = match event {
+                "song_change" => &config.on_song_change,
+                "play" => &config.on_play,
+                "pause" => &config.on_pause,
+                "stop" => &config.on_stop,
+                _ => &config.on_queue_end,
+            };
+            run(event, hook, config.timeout, Some(status), track);
+        }
+    }
+}
+
+// Starts `hook` in the background with the song in `RMPC_*` variables, its output goes to the log
+fn run(
+    event: &'static str,
+    hook: &Option<String>,
+    timeout: u64,
+    status: Option<&Status>,
+    track: Option<&Track>,
+) {
+    let hook = match hook {
+        Some(hook) => hook.clone(),
+        None => return,
+    };
+
+    let mut env = vec![("RMPC_EVENT", event.to_string())];
+    if let Some(status) = status {
+        env.push(("RMPC_STATE", status.state.clone()));
+    }
+    if let Some(track) = track {
+        let tags = [
+            ("RMPC_FILE", Some(track.file.clone())),
+            ("RMPC_ARTIST", track.artist.clone()),
+            ("RMPC_TITLE", track.title.clone()),
+            ("RMPC_ALBUM", track.album.clone()),
+            ("RMPC_ALBUM_ARTIST", track.album_artist.clone()),
+            ("RMPC_GENRE", track.genre.clone()),
+            ("RMPC_DATE", track.date.clone()),
+            ("RMPC_TRACK", track.track.map(|t| t.to_string())),
+            ("RMPC_DURATION", Some(track.duration.as_secs().to_string())),
+        ];
+        env.extend(
+            tags.iter()
+                .filter_map(|(name, value)| Some((*name, value.clone()?))),
+        );
+    }
+
+    task::spawn(async move {
+        let output = shell(&hook)
+            .envs(env)
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output();
+
+        match future::timeout(Duration::from_secs(timeout), output).await {
+            Err(_) => log::warn!("Hook on_{} was killed after {}s: {}", event, timeout, hook),
+            Ok(Err(e)) => log::warn!("Cannot run hook on_{}: {}", event, e),
+            Ok(Ok(output)) => {
+                if output.status.success() {
+                    log::info!("Hook on_{} finished: {}", event, hook);
+                } else {
+                    log::warn!("Hook on_{} failed with {}: {}", event, output.status, hook);
+                }
+                for line in String::from_utf8_lossy(&output.stdout).lines() {
+                    log::info!("on_{}: {}", event, line);
+                }
+                for line in String::from_utf8_lossy(&output.stderr).lines() {
+                    log::warn!("on_{}: {}", event, line);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(state: &str, song: Option<u32>, next: Option<u32>, elapsed: u64) -> Status {
+        Status {
+            state: state.to_string(),
+            songid: song,
+            nextsongid: next,
+            elapsed: Some(Duration::from_secs(elapsed)),
+            duration: Some(Duration::from_secs(200)),
+            ..Status::default()
+        }
+    }
+
+    #[test]
+    fn picks_the_hooks_to_run() {
+        let cases: &[(Status, Status, &[&str])] = &[
+            // nothing changed
+            (
+                status("play", Some(1), Some(2), 10),
+                status("play", Some(1), Some(2), 11),
+                &[],
+            ),
+            (
+                status("play", Some(1), Some(2), 199),
+                status("play", Some(2), None, 0),
+                &["song_change"],
+            ),
+            (
+                status("stop", None, None, 0),
+                status("play", Some(1), None, 0),
+                &["song_change", "play"],
+            ),
+            (
+                status("play", Some(1), None, 50),
+                status("pause", Some(1), None, 50),
+                &["pause"],
+            ),
+            (
+                status("pause", Some(1), None, 50),
+                status("play", Some(1), None, 50),
+                &["play"],
+            ),
+            // the last song played to the end
+            (
+                status("play", Some(1), None, 199),
+                status("stop", None, None, 0),
+                &["stop", "queue_end"],
+            ),
+            // stop pressed in the middle of the last song
+            (
+                status("play", Some(1), None, 50),
+                status("stop", Some(1), None, 0),
+                &["stop"],
+            ),
+            // another song was queued after it
+            (
+                status("play", Some(1), Some(2), 199),
+                status("stop", Some(1), None, 0),
+                &["stop"],
+            ),
+            // paused at the end is not playing to the end
+            (
+                status("pause", Some(1), None, 199),
+                status("stop", Some(1), None, 0),
+                &["stop"],
+            ),
+        ];
+
+        for (last, now, expected) in cases {
+            assert_eq!(
+                events(&Seen::new(last), now),
+                *expected,
+                "{} -> {}",
+                last.state,
+                now.state
+            );
+        }
+    }
+
+    #[test]
+    fn waits_for_a_first_status() {
+        let mut hooks = Hooks::default();
+        let config = config::Hooks::default();
+        hooks.update(&config, None, None);
+        assert!(hooks.last.is_none());
+        hooks.update(&config, Some(&status("play", Some(1), None, 0)), None);
+        assert_eq!(hooks.last.as_ref().and_then(|l| l.song), Some(1));
+    }
+}
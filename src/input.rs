@@ -2,96 +2,409 @@ use crossterm::event::KeyCode;
 
 use anyhow::Result;
 
-use crate::{play::Songs, search::Search, state::StatefulList, Mode};
+use crate::{
+    app::{AppState, Effect, Msg},
+    command::Outcome,
+    keys::Action,
+    outputs,
+    protocol::Connection,
+    smart, stickers,
+    stickers::Ratings,
+};
 use async_mpd::MpdClient;
 
-pub async fn use_key(
+// Does what the state asked MPD for and passes on the answer, if there is one to pass on.
+// Whatever MPD changed because of it comes back through the idle connection as well.
+pub async fn execute(
     client: &mut MpdClient,
-    list: &mut StatefulList<Songs>,
-    results: &mut StatefulList<Songs>,
-    srch: &mut Search,
-    mode: &mut Mode,
-    code: KeyCode,
-) -> Result<Status> {
-    if let Mode::Searching = mode {
-        match code {
-            KeyCode::Char(c) => {
-                srch.push(c);
-                srch.search(client).await?;
-                results.set_songs(srch.results());
-            }
-            KeyCode::Enter | KeyCode::Tab => {
-                if !results.is_empty() {
-                    results.next();
-                    *mode = Mode::Selecting;
-                }
-            }
-            KeyCode::Backspace => {
-                srch.pop();
-                srch.search(client).await?;
-                results.set_songs(srch.results());
+    conn: &mut Connection,
+    state: &mut AppState,
+    effect: Effect,
+) -> Result<Option<Msg>> {
+    let msg = match effect {
+        Effect::FetchStatus => Msg::Status(Box::new(client.status().await?)),
+        Effect::FetchQueue => Msg::Queue(client.queue().await?),
+        Effect::FetchOutputs => Msg::Outputs(outputs::fetch(conn).await?),
+        Effect::FetchRatings => {
+            let mut ratings = Ratings::default();
+            ratings.load(conn).await?;
+            Msg::Ratings(ratings)
+        }
+        Effect::Search => match state.search.filter() {
+            Some(filter) => Msg::Found(client.search(&filter).await?),
+            None => Msg::Found(Vec::new()),
+        },
+        Effect::Play(id) => {
+            client.playid(id).await?;
+            return Ok(None);
+        }
+        Effect::TogglePause => {
+            match client.status().await?.state.as_str() {
+                "pause" => client.play().await?,
+                _ => client.pause().await?,
             }
-            KeyCode::Esc => *mode = Mode::Browsing,
-            _ => {}
-        }
-    } else if let Mode::Selecting = mode {
-        match code {
-            KeyCode::Char(c) => match c {
-                'j' => results.next(),
-                'k' => results.previous(),
-                'g' => results.select(0),
-                'G' => results.select_last(),
-                _ => {}
-            },
-            KeyCode::Up | KeyCode::BackTab => results.previous(),
-            KeyCode::Down | KeyCode::Tab => results.next(),
-            KeyCode::Enter => {
-                if let Some(s) = results.selected() {
-                    client.queue_add(&s.file).await?;
-                    let id = client.queue().await?.last().map(|s| s.id).flatten();
-                    if let Some(id) = id {
-                        client.playid(id).await?;
-                        srch.clear();
-                        results.clear()
-                    }
-                    *mode = Mode::Browsing;
+            return Ok(None);
+        }
+        Effect::AddAndPlay { file, title } => {
+            // addid answers with the new song's id, no need to fetch the whole queue for it
+            let added = conn.command("addid", &[&file]).await?;
+            match added.into_iter().find(|(k, _)| k == "Id") {
+                Some((_, id)) => {
+                    conn.command("playid", &[&id]).await?;
+                    Msg::Added(title)
                 }
+                None => return Ok(None),
             }
-            KeyCode::Esc => *mode = Mode::Searching,
-            _ => {}
-        }
-    } else {
-        match code {
-            KeyCode::Char(c) => match c {
-                'q' => return Ok(Status::Break),
-                'j' => list.next(),
-                'k' => list.previous(),
-                'g' => list.select(0),
-                'G' => list.select_last(),
-                'c' => client.queue_clear().await?,
-                '/' => *mode = Mode::Searching,
-                'p' => match client.status().await?.state.as_str() {
-                    "pause" => client.play().await?,
-                    _ => client.pause().await?,
-                },
-                _ => {}
-            },
-            KeyCode::Up | KeyCode::BackTab => list.previous(),
-            KeyCode::Down | KeyCode::Tab => list.next(),
-            KeyCode::Enter => {
-                if let Some(s) = list.selected() {
-                    if let Some(id) = s.id {
-                        client.playid(id).await?;
-                    }
-                }
+        }
+        Effect::ClearQueue => {
+            client.queue_clear().await?;
+            Msg::Cleared
+        }
+        Effect::SetOutput(action, id) => {
+            outputs::set(conn, action, id).await?;
+            return Ok(None);
+        }
+        Effect::OnlyOutput(id) => {
+            outputs::only(conn, id).await?;
+            Msg::OnlyOutput(id)
+        }
+        Effect::LoadPlaylist { playlist, replace } => {
+            let added = smart::queue(
+                client,
+                conn,
+                &state.ratings,
+                &state.playlists[playlist],
+                &state.song_list,
+                replace,
+            )
+            .await?;
+            Msg::Loaded {
+                playlist,
+                added,
+                replace,
             }
-            _ => {}
         }
-    }
-    Ok(Status::Continue)
+        Effect::Rate { file, rating } => {
+            stickers::rate(conn, &file, rating).await?;
+            return Ok(None);
+        }
+        Effect::Update(dir) => {
+            let job = client.update(dir.as_deref()).await?;
+            Msg::Updating { dir, job }
+        }
+        Effect::RunCommand => match state.cmdline.run(client, conn, &mut state.messages).await {
+            Outcome::Quit => Msg::Quit,
+            Outcome::Done(_) => return Ok(None),
+        },
+        Effect::CompleteCommand => {
+            state.cmdline.complete(conn, &mut state.messages).await?;
+            return Ok(None);
+        }
+        // nothing for MPD to do, the app handles these
+        #[cfg(feature = "lua")]
+        Effect::Script(_) => return Ok(None),
+        Effect::Quit => return Ok(None),
+    };
+    Ok(Some(msg))
+}
+
+// A key press, or an action asked for from outside of the terminal
+#[derive(Debug)]
+pub enum Input {
+    Key(KeyCode),
+    Action(Action),
 }
 
 pub enum Status {
     Continue,
     Break,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        dj::AutoDj,
+        keys::{KeyMap, Overrides},
+        message::Messages,
+        mock::{self, Mpd},
+        play::Songs,
+        state::StatefulList,
+        Mode, View,
+    };
+    use async_std::task;
+    use std::collections::VecDeque;
+    use KeyCode::{Backspace, Char, Enter, Esc};
+
+    // The state, connected to a mock MPD
+    struct Harness {
+        mpd: Mpd,
+        client: MpdClient,
+        conn: Connection,
+        state: AppState,
+    }
+
+    impl Harness {
+        // Starts with the whole library queued and the first song selected
+        async fn new() -> Harness {
+            let mpd = Mpd::start(vec![
+                mock::song("a/1.flac", "One", "Alpha", "First"),
+                mock::song("a/2.flac", "Two", "Alpha", "First"),
+                mock::song("b/3.flac", "Three", "Beta", "Second"),
+            ])
+            .await;
+            mpd.with(|s| {
+                for song in s.library.clone() {
+                    s.enqueue(song);
+                }
+            });
+
+            let keymap = KeyMap::new(&Overrides::default(), &[]).unwrap();
+            let mut h = Harness {
+                client: MpdClient::new(mpd.addr).await.unwrap(),
+                conn: Connection::new(mpd.addr).await.unwrap(),
+                mpd,
+                state: AppState::new(
+                    keymap,
+                    Messages::default(),
+                    Vec::new(),
+                    AutoDj::new(&Default::default()),
+                ),
+            };
+            h.sync().await;
+            h
+        }
+
+        // Runs the effects and feeds the answers back, like the app does
+        async fn run(&mut self, effects: Vec<Effect>) -> Status {
+            let mut effects = VecDeque::from(effects);
+            while let Some(effect) = effects.pop_front() {
+                if effect == Effect::Quit {
+                    return Status::Break;
+                }
+                let msg = execute(&mut self.client, &mut self.conn, &mut self.state, effect)
+                    .await
+                    .unwrap();
+                if let Some(msg) = msg {
+                    effects.extend(self.state.update(msg));
+                }
+            }
+            Status::Continue
+        }
+
+        async fn key(&mut self, code: KeyCode) -> Status {
+            let effects = self.state.update(Msg::Input(Input::Key(code)));
+            self.run(effects).await
+        }
+
+        async fn keys(&mut self, keys: &str) {
+            for c in keys.chars() {
+                self.key(Char(c)).await;
+            }
+        }
+
+        // What the event loop fetches once MPD says something changed
+        async fn sync(&mut self) {
+            let effects = self.state.update(Msg::Connected);
+            self.run(effects).await;
+        }
+
+        fn message(&self) -> Option<&str> {
+            self.state.messages.current().map(|m| m.text.as_str())
+        }
+    }
+
+    fn selected(list: &StatefulList<Songs>) -> Option<&str> {
+        list.selected().map(|s| s.file.as_str())
+    }
+
+    #[test]
+    fn plays_the_selected_song() {
+        task::block_on(async {
+            let mut h = Harness::new().await;
+            h.key(Char('j')).await;
+            h.key(Enter).await;
+
+            assert_eq!(
+                h.mpd.with(|s| s.playing().map(str::to_string)).as_deref(),
+                Some("a/2.flac")
+            );
+            h.sync().await;
+            assert_eq!(
+                h.state.song_list.playing().map(|s| s.file.as_str()),
+                Some("a/2.flac")
+            );
+            assert_eq!(selected(&h.state.song_list), Some("a/2.flac"));
+        });
+    }
+
+    #[test]
+    fn moves_through_the_queue() {
+        task::block_on(async {
+            let mut h = Harness::new().await;
+            h.key(Char('G')).await;
+            assert_eq!(selected(&h.state.song_list), Some("b/3.flac"));
+            // wraps around at the end
+            h.key(Char('j')).await;
+            assert_eq!(selected(&h.state.song_list), Some("a/1.flac"));
+            h.key(Char('k')).await;
+            assert_eq!(selected(&h.state.song_list), Some("b/3.flac"));
+            h.key(Char('g')).await;
+            assert_eq!(selected(&h.state.song_list), Some("a/1.flac"));
+        });
+    }
+
+    #[test]
+    fn searches_and_adds_a_result() {
+        task::block_on(async {
+            let mut h = Harness::new().await;
+            h.mpd.with(|s| s.queue.clear());
+            h.sync().await;
+
+            h.key(Char('/')).await;
+            assert_eq!(h.state.mode, Mode::Searching);
+            h.keys("t").await;
+            assert_eq!(h.state.results.len(), 2);
+            h.keys("hx").await;
+            assert!(h.state.results.is_empty());
+            h.key(Backspace).await;
+            assert_eq!(h.state.search.query(), "th");
+            assert_eq!(h.state.results.len(), 1);
+
+            h.key(Enter).await;
+            assert_eq!(h.state.mode, Mode::Selecting);
+            assert_eq!(selected(&h.state.results), Some("b/3.flac"));
+            h.key(Enter).await;
+
+            assert_eq!(h.state.mode, Mode::Browsing);
+            assert_eq!(h.mpd.with(|s| s.files().join(",")), "b/3.flac");
+            assert_eq!(h.mpd.with(|s| s.state.clone()), "play");
+            // played by the id addid gave back, without fetching the queue in between
+            let commands = h.mpd.commands();
+            let added = commands.iter().position(|c| c == "addid b/3.flac").unwrap();
+            assert_eq!(commands[added + 1], "playid 4");
+            assert_eq!(h.message(), Some("Added Three"));
+            assert!(h.state.results.is_empty());
+            assert_eq!(h.state.search.query(), "");
+        });
+    }
+
+    #[test]
+    fn leaves_search_without_results() {
+        task::block_on(async {
+            let mut h = Harness::new().await;
+            h.key(Char('/')).await;
+            // nothing to select yet
+            h.key(Enter).await;
+            assert_eq!(h.state.mode, Mode::Searching);
+            h.key(Esc).await;
+            assert_eq!(h.state.mode, Mode::Browsing);
+            assert!(h.mpd.commands().iter().all(|c| !c.starts_with("search")));
+        });
+    }
+
+    #[test]
+    fn toggles_pause() {
+        task::block_on(async {
+            let mut h = Harness::new().await;
+            h.key(Enter).await;
+            h.key(Char('p')).await;
+            assert_eq!(h.mpd.with(|s| s.state.clone()), "pause");
+            h.key(Char('p')).await;
+            assert_eq!(h.mpd.with(|s| s.state.clone()), "play");
+        });
+    }
+
+    #[test]
+    fn clears_the_queue() {
+        task::block_on(async {
+            let mut h = Harness::new().await;
+            h.key(Char('c')).await;
+            assert!(h.mpd.with(|s| s.queue.is_empty()));
+            assert_eq!(h.message(), Some("Cleared the queue"));
+            h.sync().await;
+            assert!(h.state.song_list.is_empty());
+        });
+    }
+
+    #[test]
+    fn rates_the_selected_song() {
+        task::block_on(async {
+            let mut h = Harness::new().await;
+            let sticker = |h: &Harness| {
+                h.mpd.with(|s| {
+                    s.stickers
+                        .get(&("a/2.flac".to_string(), "rating".to_string()))
+                        .cloned()
+                })
+            };
+            // two presses before MPD's sticker event
+            h.keys("j++").await;
+            assert_eq!(h.state.ratings.get("a/2.flac"), Some(2));
+            assert_eq!(sticker(&h).as_deref(), Some("2"));
+            h.sync().await;
+            assert_eq!(h.state.ratings.get("a/2.flac"), Some(2));
+
+            h.keys("--").await;
+            assert_eq!(h.state.ratings.get("a/2.flac"), None);
+            assert_eq!(sticker(&h), None);
+            h.sync().await;
+            assert_eq!(h.state.ratings.get("a/2.flac"), None);
+        });
+    }
+
+    #[test]
+    fn switches_outputs() {
+        task::block_on(async {
+            let mut h = Harness::new().await;
+            h.key(Char('4')).await;
+            assert_eq!(h.state.view, View::Outputs);
+
+            // keys act on outputs in this view, not on the queue
+            h.keys("jx").await;
+            let enabled = |h: &Harness| {
+                h.mpd
+                    .with(|s| s.outputs.iter().map(|o| o.enabled).collect::<Vec<_>>())
+            };
+            assert_eq!(enabled(&h), [false, true]);
+            assert_eq!(h.message(), Some("Only playing on Headphones"));
+            assert_eq!(selected(&h.state.song_list), Some("a/1.flac"));
+
+            h.sync().await;
+            h.key(Char('k')).await;
+            h.key(Char('t')).await;
+            assert_eq!(enabled(&h), [true, true]);
+        });
+    }
+
+    #[test]
+    fn runs_commands() {
+        task::block_on(async {
+            let mut h = Harness::new().await;
+            h.key(Char(':')).await;
+            assert_eq!(h.state.mode, Mode::Command);
+            h.keys("volume 80").await;
+            h.key(Enter).await;
+            assert_eq!(h.state.mode, Mode::Browsing);
+            assert_eq!(h.mpd.with(|s| s.volume), 80);
+
+            h.key(Char(':')).await;
+            h.keys("x").await;
+            h.key(Backspace).await;
+            // backspace on the empty prompt closes it
+            h.key(Backspace).await;
+            assert_eq!(h.state.mode, Mode::Browsing);
+        });
+    }
+
+    #[test]
+    fn quits() {
+        task::block_on(async {
+            let mut h = Harness::new().await;
+            let sent = h.mpd.commands().len();
+            assert!(matches!(h.key(Char('q')).await, Status::Break));
+            // quitting leaves MPD alone
+            assert_eq!(h.mpd.commands().len(), sent);
+        });
+    }
+}
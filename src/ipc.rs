@@ -0,0 +1,234 @@
+use crate::{keys::Action, EventMessage, View};
+use anyhow::{anyhow, Result};
+use async_std::{
+    channel::{self, Sender},
+    io::BufReader,
+    os::unix::net::{UnixListener, UnixStream},
+    prelude::*,
+    task,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{env, fs, path::PathBuf};
+
+// What a line sent to the socket asks for
+#[derive(Debug)]
+pub enum Command {
+    // `search <query>`, opens search with `query` typed in
+    Search(String),
+    // `jump-to-playing`, selects the playing song in the queue
+    JumpToPlaying,
+    // `view <queue|lyrics|visualizer|outputs|stats>`
+    View(View),
+    // `action <name>`, any action keys can be bound to, named like in the config
+    Action(Action),
+    // `command <line>`, same as typing it on the `:` prompt
+    Run(String),
+    // `state`, what the UI shows right now
+    State,
+}
+
+impl Command {
+    fn parse(line: &str) -> Result<Command> {
+        let (cmd, rest) = match line.split_once(' ') {
+            Some((cmd, rest)) => (cmd, rest.trim()),
+            None => (line, ""),
+        };
+        Ok(match (cmd, rest) {
+            ("search", query) => Command::Search(query.to_string()),
+            ("jump-to-playing", "") => Command::JumpToPlaying,
+            ("view", view) => Command::View(
+                View::from_name(view).ok_or_else(|| anyhow!("Unknown view: {}", view))?,
+            ),
+            ("action", name) => {
+                let name = serde::de::value::StrDeserializer::<serde::de::value::Error>::new(name);
+                Command::Action(Action::deserialize(name)?)
+            }
+            ("command", line) if !line.is_empty() => Command::Run(line.to_string()),
+            ("state", "") => Command::State,
+            _ => return Err(anyhow!("Unknown command: {}", line)),
+        })
+    }
+}
+
+// A command and where its answer goes
+#[derive(Debug)]
+pub struct Request {
+    pub command: Command,
+    pub reply: Sender<Result<Value>>,
+}
+
+// `rmpc.sock` in the runtime dir, taken down again when dropped
+pub struct Server {
+    path: PathBuf,
+}
+
+impl Server {
+    // Listens for lines on the socket and passes them on to the app, one JSON reply per line
+    pub async fn start(events: Sender<EventMessage>) -> Result<Server> {
+        let path = path();
+        if UnixStream::connect(&path).await.is_ok() {
+            return Err(anyhow!("{} is used by another rmpc", path.display()));
+        }
+        // left over from an rmpc that didn't get to clean up
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).await?;
+
+        task::spawn(async move {
+            let mut incoming = listener.incoming();
+            while let Some(stream) = incoming.next().await {
+                match stream {
+                    Ok(stream) => {
+                        task::spawn(serve(stream, events.clone()));
+                    }
+                    Err(e) => log::warn!("Cannot accept IPC connection: {}", e),
+                }
+            }
+        });
+        Ok(Server { path })
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+async fn serve(stream: UnixStream, events: Sender<EventMessage>) {
+    let mut lines = BufReader::new(&stream).lines();
+    let mut writer = &stream;
+
+    while let Some(Ok(line)) = lines.next().await {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let reply = match Command::parse(line) {
+            Ok(command) => {
+                let (reply, answer) = channel::bounded(1);
+                if events
+                    .send(EventMessage::Ipc(Request { command, reply }))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+                answer
+                    .recv()
+                    .await
+                    .unwrap_or_else(|_| Err(anyhow!("rmpc is shutting down")))
+            }
+            Err(e) => Err(e),
+        };
+        let reply = match reply {
+            Ok(Value::Null) => json!({ "ok": true }),
+            Ok(value) => json!({ "ok": true, "result": value }),
+            Err(e) => json!({ "ok": false, "error": e.to_string() }),
+        };
+        if writer
+            .write_all(format!("{}\n", reply).as_bytes())
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+pub fn path() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("rmpc.sock"),
+        // /tmp is shared with everyone else
+        None => env::temp_dir().join(format!("rmpc-{}.sock", unsafe { libc::getuid() })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(line: &str) -> String {
+        Command::parse(line).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert!(matches!(
+            Command::parse("search  some thing "),
+            Ok(Command::Search(q)) if q == "some thing"
+        ));
+        assert!(matches!(
+            Command::parse("view stats"),
+            Ok(Command::View(View::Stats))
+        ));
+        assert!(matches!(
+            Command::parse("command add dir"),
+            Ok(Command::Run(l)) if l == "add dir"
+        ));
+        assert!(matches!(
+            Command::parse("jump-to-playing"),
+            Ok(Command::JumpToPlaying)
+        ));
+        assert!(matches!(Command::parse("state"), Ok(Command::State)));
+        assert!(Command::parse("action toggle_pause").is_ok());
+    }
+
+    #[test]
+    fn rejects_bad_commands() {
+        assert_eq!(error("view bogus"), "Unknown view: bogus");
+        assert!(error("action bogus").contains("bogus"));
+        assert_eq!(error("command"), "Unknown command: command");
+        assert_eq!(error("command  "), "Unknown command: command  ");
+        assert_eq!(error("state now"), "Unknown command: state now");
+        assert_eq!(error("bogus"), "Unknown command: bogus");
+    }
+
+    #[async_std::test]
+    async fn answers_on_the_socket() {
+        let dir = env::temp_dir().join(format!("rmpc-ipc-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        env::set_var("XDG_RUNTIME_DIR", &dir);
+
+        let (s, events) = channel::unbounded();
+        let server = Server::start(s.clone()).await.unwrap();
+        assert_eq!(path(), dir.join("rmpc.sock"));
+        assert!(Server::start(s).await.is_err());
+
+        // stands in for the app
+        task::spawn(async move {
+            while let Ok(EventMessage::Ipc(request)) = events.recv().await {
+                let reply = match request.command {
+                    Command::State => Ok(json!({ "mode": "browsing" })),
+                    _ => Ok(Value::Null),
+                };
+                request.reply.send(reply).await.unwrap();
+            }
+        });
+
+        let stream = UnixStream::connect(path()).await.unwrap();
+        let mut lines = BufReader::new(&stream).lines();
+        let mut writer = &stream;
+        writer
+            .write_all(b"state\n\nview queue\nview bogus\n")
+            .await
+            .unwrap();
+        let mut replies = Vec::new();
+        for _ in 0..3 {
+            replies.push(lines.next().await.unwrap().unwrap());
+        }
+        // empty lines aren't answered
+        assert_eq!(
+            replies,
+            [
+                r#"{"ok":true,"result":{"mode":"browsing"}}"#,
+                r#"{"ok":true}"#,
+                r#"{"error":"Unknown view: bogus","ok":false}"#,
+            ]
+        );
+
+        drop(server);
+        assert!(!path().exists());
+        let _ = fs::remove_dir(&dir);
+    }
+}
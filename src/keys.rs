@@ -0,0 +1,367 @@
+use crate::{config::SmartPlaylist, Mode};
+use anyhow::{anyhow, Result};
+use crossterm::event::KeyCode;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+// Everything a key can be bound to, names are used as is in config.toml (snake_case)
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Quit,
+    Down,
+    Up,
+    Top,
+    Bottom,
+    Play,
+    TogglePause,
+    ClearQueue,
+    RateUp,
+    RateDown,
+    Update,
+    UpdateDirectory,
+    Search,
+    QueueView,
+    LyricsView,
+    VisualizerView,
+    OutputsView,
+    StatsView,
+    ToggleOutput,
+    EnableOutput,
+    DisableOutput,
+    ExclusiveOutput,
+    Help,
+    Command,
+    // smart playlists by their index in the config, bound with their own keys
+    #[serde(skip)]
+    LoadPlaylist(usize),
+    #[serde(skip)]
+    AppendPlaylist(usize),
+    // actions from Lua scripts, by the order they were bound in
+    #[cfg(feature = "lua")]
+    #[serde(skip)]
+    Script(usize),
+    ToggleAutoDj,
+    // search and selecting
+    Select,
+    Erase,
+    AddAndPlay,
+    Back,
+    // command line
+    Execute,
+    Complete,
+    // removes a default binding
+    Unbound,
+}
+
+impl Action {
+    pub fn description(&self) -> &'static str {
+        match self {
+            Action::Quit => "Quit rmpc",
+            Action::Down => "Move down",
+            Action::Up => "Move up",
+            Action::Top => "Go to the top",
+            Action::Bottom => "Go to the bottom",
+            Action::Play => "Play the selected song",
+            Action::TogglePause => "Pause or resume playback",
+            Action::ClearQueue => "Clear the queue",
+            Action::RateUp => "Rate the selected song higher",
+            Action::RateDown => "Rate the selected song lower",
+            Action::Update => "Update the database",
+            Action::UpdateDirectory => "Update the selected song's directory",
+            Action::Search => "Search",
+            Action::QueueView => "Show the queue",
+            Action::LyricsView => "Show lyrics of the playing song",
+            Action::VisualizerView => "Show the visualizer",
+            Action::OutputsView => "Show the audio outputs",
+            Action::StatsView => "Show library statistics",
+            Action::ToggleOutput => "Toggle the selected output",
+            Action::EnableOutput => "Enable the selected output",
+            Action::DisableOutput => "Disable the selected output",
+            Action::ExclusiveOutput => "Only play on the selected output",
+            Action::Help => "Show this help",
+            Action::Command => "Enter a command",
+            Action::LoadPlaylist(_) => "Replace the queue with a smart playlist",
+            Action::AppendPlaylist(_) => "Add a smart playlist to the queue",
+            #[cfg(feature = "lua")]
+            Action::Script(_) => "Run a Lua script",
+            Action::ToggleAutoDj => "Keep the queue filled with similar songs",
+            Action::Select => "Go to the results",
+            Action::Erase => "Delete the last character",
+            Action::AddAndPlay => "Add the selected song to the queue and play it",
+            Action::Back => "Go back",
+            Action::Execute => "Run the command",
+            Action::Complete => "Complete the command",
+            Action::Unbound => "Nothing",
+        }
+    }
+}
+
+// Overrides from the `[keys.<mode>]` tables in config.toml, e.g. `x = "clear_queue"`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Overrides {
+    pub browsing: HashMap<String, Action>,
+    pub selecting: HashMap<String, Action>,
+    pub searching: HashMap<String, Action>,
+    pub help: HashMap<String, Action>,
+    pub command: HashMap<String, Action>,
+}
+
+pub struct KeyMap {
+    bindings: HashMap<Mode, Vec<(KeyCode, Action)>>,
+    // for actions that say what they do themselves, like scripts
+    descriptions: HashMap<Action, String>,
+}
+
+impl KeyMap {
+    pub fn new(overrides: &Overrides, playlists: &[SmartPlaylist]) -> Result<KeyMap> {
+        use Action::*;
+        use KeyCode::{BackTab, Backspace, Char, Enter, Esc, Tab};
+
+        let mut bindings = HashMap::new();
+        bindings.insert(
+            Mode::Browsing,
+            vec![
+                (Char('q'), Quit),
+                (Char('j'), Action::Down),
+                (KeyCode::Down, Action::Down),
+                (Tab, Action::Down),
+                (Char('k'), Action::Up),
+                (KeyCode::Up, Action::Up),
+                (BackTab, Action::Up),
+                (Char('g'), Top),
+                (Char('G'), Bottom),
+                (Enter, Play),
+                (Char('p'), TogglePause),
+                (Char('c'), ClearQueue),
+                (Char('+'), RateUp),
+                (Char('-'), RateDown),
+                (Char('u'), Update),
+                (Char('U'), UpdateDirectory),
+                (Char('/'), Search),
+                (Char('1'), QueueView),
+                (Char('2'), LyricsView),
+                (Char('3'), VisualizerView),
+                (Char('4'), OutputsView),
+                (Char('5'), StatsView),
+                (Char('t'), ToggleOutput),
+                (Char('e'), EnableOutput),
+                (Char('d'), DisableOutput),
+                (Char('x'), ExclusiveOutput),
+                (Char('?'), Help),
+                (Char(':'), Command),
+                (Char('a'), ToggleAutoDj),
+            ],
+        );
+        bindings.insert(
+            Mode::Selecting,
+            vec![
+                (Char('j'), Action::Down),
+                (KeyCode::Down, Action::Down),
+                (Tab, Action::Down),
+                (Char('k'), Action::Up),
+                (KeyCode::Up, Action::Up),
+                (BackTab, Action::Up),
+                (Char('g'), Top),
+                (Char('G'), Bottom),
+                (Enter, AddAndPlay),
+                (Esc, Back),
+                (Char('?'), Help),
+            ],
+        );
+        // characters without a binding are typed into the search
+        bindings.insert(
+            Mode::Searching,
+            vec![
+                (Enter, Select),
+                (Tab, Select),
+                (Backspace, Erase),
+                (Esc, Back),
+            ],
+        );
+        // characters are typed into the filter while filtering
+        bindings.insert(
+            Mode::Help,
+            vec![
+                (Char('j'), Action::Down),
+                (KeyCode::Down, Action::Down),
+                (Char('k'), Action::Up),
+                (KeyCode::Up, Action::Up),
+                (Char('g'), Top),
+                (Char('G'), Bottom),
+                (Char('/'), Search),
+                (Esc, Back),
+                (Char('q'), Back),
+                (Char('?'), Back),
+            ],
+        );
+
+        // characters without a binding are typed into the prompt
+        bindings.insert(
+            Mode::Command,
+            vec![
+                (Enter, Execute),
+                (Tab, Complete),
+                (KeyCode::Up, Action::Up),
+                (KeyCode::Down, Action::Down),
+                (Backspace, Erase),
+                (Esc, Back),
+            ],
+        );
+
+        let mut map = KeyMap {
+            bindings,
+            descriptions: HashMap::new(),
+        };
+        for (i, playlist) in playlists.iter().enumerate() {
+            // named, so help tells the playlists apart
+            map.descriptions.insert(
+                LoadPlaylist(i),
+                format!("Replace the queue with {}", playlist.name),
+            );
+            map.descriptions.insert(
+                AppendPlaylist(i),
+                format!("Add {} to the queue", playlist.name),
+            );
+            if let Some(key) = &playlist.key {
+                map.bind(Mode::Browsing, parse(key)?, LoadPlaylist(i));
+            }
+            if let Some(key) = &playlist.append_key {
+                map.bind(Mode::Browsing, parse(key)?, AppendPlaylist(i));
+            }
+        }
+        for (mode, keys) in &[
+            (Mode::Browsing, &overrides.browsing),
+            (Mode::Selecting, &overrides.selecting),
+            (Mode::Searching, &overrides.searching),
+            (Mode::Help, &overrides.help),
+            (Mode::Command, &overrides.command),
+        ] {
+            for (key, action) in keys.iter() {
+                map.bind(*mode, parse(key)?, *action);
+            }
+        }
+        Ok(map)
+    }
+
+    fn bind(&mut self, mode: Mode, key: KeyCode, action: Action) {
+        let keys = self.bindings.entry(mode).or_default();
+        keys.retain(|(k, _)| *k != key);
+        if action != Action::Unbound {
+            keys.push((key, action));
+        }
+    }
+
+    // Binds an action that's only known at runtime in browsing mode
+    #[cfg(feature = "lua")]
+    pub fn bind_described(&mut self, key: &str, action: Action, description: String) -> Result<()> {
+        self.bind(Mode::Browsing, parse(key)?, action);
+        self.descriptions.insert(action, description);
+        Ok(())
+    }
+
+    pub fn description(&self, action: Action) -> &str {
+        self.descriptions
+            .get(&action)
+            .map_or(action.description(), String::as_str)
+    }
+
+    pub fn get(&self, mode: Mode, key: KeyCode) -> Option<Action> {
+        self.bindings
+            .get(&mode)
+            .and_then(|keys| keys.iter().find(|(k, _)| *k == key))
+            .map(|(_, a)| *a)
+    }
+
+    // Every action of `mode` with the keys bound to it, in the order they were bound
+    pub fn actions(&self, mode: Mode) -> Vec<(Action, Vec<String>)> {
+        let mut actions: Vec<(Action, Vec<String>)> = Vec::new();
+
+        for (key, action) in self.bindings.get(&mode).into_iter().flatten() {
+            match actions.iter_mut().find(|(a, _)| a == action) {
+                Some((_, keys)) => keys.push(name(*key)),
+                None => actions.push((*action, vec![name(*key)])),
+            }
+        }
+        actions
+    }
+}
+
+// Name of a key as written in config.toml
+pub fn name(key: KeyCode) -> String {
+    match key {
+        KeyCode::Char(' ') => "space".to_string(),
+        KeyCode::Char(c) => c.to_string(),
+        KeyCode::F(n) => format!("f{}", n),
+        KeyCode::Backspace => "backspace".to_string(),
+        KeyCode::Enter => "enter".to_string(),
+        KeyCode::Left => "left".to_string(),
+        KeyCode::Right => "right".to_string(),
+        KeyCode::Up => "up".to_string(),
+        KeyCode::Down => "down".to_string(),
+        KeyCode::Home => "home".to_string(),
+        KeyCode::End => "end".to_string(),
+        KeyCode::PageUp => "pageup".to_string(),
+        KeyCode::PageDown => "pagedown".to_string(),
+        KeyCode::Tab => "tab".to_string(),
+        KeyCode::BackTab => "backtab".to_string(),
+        KeyCode::Delete => "delete".to_string(),
+        KeyCode::Insert => "insert".to_string(),
+        KeyCode::Null => "null".to_string(),
+        KeyCode::Esc => "esc".to_string(),
+    }
+}
+
+pub fn parse(key: &str) -> Result<KeyCode> {
+    let mut chars = key.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Ok(KeyCode::Char(c));
+    }
+
+    Ok(match key.to_lowercase().as_str() {
+        "space" => KeyCode::Char(' '),
+        "backspace" => KeyCode::Backspace,
+        "enter" => KeyCode::Enter,
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "home" => KeyCode::Home,
+        "end" => KeyCode::End,
+        "pageup" => KeyCode::PageUp,
+        "pagedown" => KeyCode::PageDown,
+        "tab" => KeyCode::Tab,
+        "backtab" => KeyCode::BackTab,
+        "delete" => KeyCode::Delete,
+        "insert" => KeyCode::Insert,
+        "esc" => KeyCode::Esc,
+        k => match k.strip_prefix('f').and_then(|n| n.parse().ok()) {
+            Some(n) => KeyCode::F(n),
+            None => return Err(anyhow!("Unknown key `{}` in config", key)),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_playlists_in_descriptions() {
+        let playlists: Vec<SmartPlaylist> = ["Jazz", "Rock"]
+            .iter()
+            .map(|name| toml::from_str(&format!("name = \"{}\"", name)).unwrap())
+            .collect();
+        let keymap = KeyMap::new(&Overrides::default(), &playlists).unwrap();
+        assert_eq!(
+            keymap.description(Action::LoadPlaylist(0)),
+            "Replace the queue with Jazz"
+        );
+        assert_eq!(
+            keymap.description(Action::AppendPlaylist(1)),
+            "Add Rock to the queue"
+        );
+        assert_eq!(keymap.description(Action::Quit), "Quit rmpc");
+    }
+}
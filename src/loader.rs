@@ -0,0 +1,285 @@
+use crate::{
+    art::{self, Cover},
+    lyrics::{self, Lyrics},
+    protocol::Connection,
+    stats::Library,
+    EventMessage,
+};
+use anyhow::Result;
+use async_mpd::{MpdClient, Track};
+use async_std::{
+    channel::{self, Receiver, Sender},
+    task,
+};
+use std::net::SocketAddrV4;
+
+// What came back from a fetch, passed to the app as `EventMessage::Loaded`
+pub enum Loaded {
+    Art {
+        key: String,
+        cover: Result<Option<Cover>>,
+    },
+    Lyrics(Lyrics),
+    Library(Result<Library>),
+    // the cover for a song change notification, `track` is what it's about
+    #[cfg(feature = "notify")]
+    Notification {
+        track: Box<Track>,
+        cover: Result<Option<zbus::zvariant::Value<'static>>>,
+    },
+}
+
+// Fetches covers, lyrics and library stats away from the event loop, so a slow transfer
+// doesn't hold up input. Each kind has a task and a connection of its own.
+pub struct Loader {
+    art: Sender<(String, Track)>,
+    lyrics: Sender<Track>,
+    library: Sender<()>,
+    #[cfg(feature = "notify")]
+    notification: Sender<Track>,
+}
+
+impl Loader {
+    pub fn start(addr: SocketAddrV4, dirs: lyrics::Dirs, events: Sender<EventMessage>) -> Loader {
+        let (art, art_requests) = channel::unbounded();
+        let (lyrics, lyrics_requests) = channel::unbounded();
+        let (library, library_requests) = channel::unbounded();
+        #[cfg(feature = "notify")]
+        let (notification, notification_requests) = channel::unbounded();
+
+        task::spawn(covers(addr, art_requests, events.clone()));
+        task::spawn(lyrics_files(addr, dirs, lyrics_requests, events.clone()));
+        #[cfg(feature = "notify")]
+        task::spawn(notification_covers(
+            addr,
+            notification_requests,
+            events.clone(),
+        ));
+        task::spawn(libraries(addr, library_requests, events));
+        Loader {
+            art,
+            lyrics,
+            library,
+            #[cfg(feature = "notify")]
+            notification,
+        }
+    }
+
+    // The cover of `track`'s album, cached as `key`
+    pub fn art(&self, key: String, track: Track) {
+        let _ = self.art.try_send((key, track));
+    }
+
+    pub fn lyrics(&self, track: Track) {
+        let _ = self.lyrics.try_send(track);
+    }
+
+    pub fn library(&self) {
+        let _ = self.library.try_send(());
+    }
+
+    // The cover to show in the notification about `track`
+    #[cfg(feature = "notify")]
+    pub fn notification(&self, track: Track) {
+        let _ = self.notification.try_send(track);
+    }
+}
+
+async fn covers(
+    addr: SocketAddrV4,
+    requests: Receiver<(String, Track)>,
+    events: Sender<EventMessage>,
+) {
+    let mut conn = None;
+    while let Some((key, track)) = newest(&requests).await {
+        let cover = match connect(&mut conn, addr).await {
+            Ok(c) => art::cover(c, &track.file).await.map_err(Into::into),
+            Err(e) => Err(e),
+        };
+        let loaded = Loaded::Art { key, cover };
+        if events.send(EventMessage::Loaded(loaded)).await.is_err() {
+            break;
+        }
+    }
+}
+
+// Separate from `covers`, these are decoded for the notification server instead of a pane
+#[cfg(feature = "notify")]
+async fn notification_covers(
+    addr: SocketAddrV4,
+    requests: Receiver<Track>,
+    events: Sender<EventMessage>,
+) {
+    let mut conn = None;
+    while let Some(track) = newest(&requests).await {
+        let cover = match connect(&mut conn, addr).await {
+            Ok(c) => crate::notify::cover(c, &track).await,
+            Err(e) => Err(e),
+        };
+        let loaded = Loaded::Notification {
+            track: Box::new(track),
+            cover,
+        };
+        if events.send(EventMessage::Loaded(loaded)).await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn lyrics_files(
+    addr: SocketAddrV4,
+    dirs: lyrics::Dirs,
+    requests: Receiver<Track>,
+    events: Sender<EventMessage>,
+) {
+    let mut conn = None;
+    while let Some(track) = newest(&requests).await {
+        let lyrics = match connect(&mut conn, addr).await {
+            Ok(c) => lyrics::load(&dirs, c, &track).await,
+            // files next to the track or in the lyrics dir work without MPD
+            Err(e) => {
+                log::warn!("Cannot connect for lyrics: {}", e);
+                lyrics::load_files(&dirs, &track).unwrap_or_else(|| Lyrics::parse(&track.file, ""))
+            }
+        };
+        if events
+            .send(EventMessage::Loaded(Loaded::Lyrics(lyrics)))
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+async fn libraries(addr: SocketAddrV4, requests: Receiver<()>, events: Sender<EventMessage>) {
+    while newest(&requests).await.is_some() {
+        // asked for rarely, a connection isn't kept around for it
+        let library = async {
+            let mut client = MpdClient::new(addr).await?;
+            Ok(Library::load(&mut client).await?)
+        }
+        .await;
+        // the database changed while this loaded, the request queued since gets the new one
+        if !requests.is_empty() {
+            continue;
+        }
+        if events
+            .send(EventMessage::Loaded(Loaded::Library(library)))
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+// Waits for a request, then skips ahead to the newest one, the others are out of date by now
+async fn newest<T>(requests: &Receiver<T>) -> Option<T> {
+    let mut request = requests.recv().await.ok()?;
+    while let Ok(newer) = requests.try_recv() {
+        request = newer;
+    }
+    Some(request)
+}
+
+// Connects the first time it's needed, `Connection` reconnects by itself after that
+async fn connect(conn: &mut Option<Connection>, addr: SocketAddrV4) -> Result<&mut Connection> {
+    let c = match conn.take() {
+        Some(c) => c,
+        None => Connection::new(addr).await?,
+    };
+    Ok(conn.insert(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+    use async_std::future::timeout;
+    use std::time::Duration;
+
+    async fn next(events: &Receiver<EventMessage>) -> Loaded {
+        match timeout(Duration::from_secs(5), events.recv()).await {
+            Ok(Ok(EventMessage::Loaded(loaded))) => loaded,
+            _ => panic!("nothing loaded"),
+        }
+    }
+
+    #[async_std::test]
+    async fn only_the_newest_request_counts() {
+        let (s, r) = channel::unbounded();
+        for i in 0..3 {
+            s.send(i).await.unwrap();
+        }
+        assert_eq!(newest(&r).await, Some(2));
+        drop(s);
+        assert_eq!(newest(&r).await, None);
+    }
+
+    #[async_std::test]
+    async fn sends_back_what_it_loaded() {
+        let mpd = mock::Mpd::start(vec![mock::song("a.flac", "A", "Artist", "Album")]).await;
+        let (s, events) = channel::unbounded();
+        let loader = Loader::start(mpd.addr, lyrics::Dirs::default(), s);
+
+        loader.library();
+        match next(&events).await {
+            Loaded::Library(Ok(library)) => assert!(library.is_loaded()),
+            _ => panic!("expected library stats"),
+        }
+
+        let track = Track {
+            file: "a.flac".to_string(),
+            ..Default::default()
+        };
+        loader.lyrics(track);
+        match next(&events).await {
+            Loaded::Lyrics(lyrics) => assert_eq!(lyrics.file(), "a.flac"),
+            _ => panic!("expected lyrics"),
+        }
+    }
+
+    #[async_std::test]
+    async fn drops_a_library_that_changed_while_loading() {
+        let mpd = mock::Mpd::start(vec![mock::song("a.flac", "A", "Artist", "Album")]).await;
+        let (s, events) = channel::unbounded();
+        let loader = Loader::start(mpd.addr, lyrics::Dirs::default(), s);
+
+        // MPD can't answer while the state is held, the first load waits on it
+        mpd.with(|_| {
+            loader.library();
+            std::thread::sleep(Duration::from_millis(100));
+            loader.library();
+        });
+        assert!(matches!(next(&events).await, Loaded::Library(Ok(_))));
+        assert!(timeout(Duration::from_millis(200), events.recv())
+            .await
+            .is_err());
+        let loads = mpd.commands().iter().filter(|c| *c == "stats").count();
+        assert_eq!(loads, 2);
+    }
+
+    #[cfg(feature = "notify")]
+    #[async_std::test]
+    async fn notification_covers_come_back_with_their_track() {
+        let mpd = mock::Mpd::start(vec![mock::song("a.flac", "A", "Artist", "Album")]).await;
+        let (s, events) = channel::unbounded();
+        let loader = Loader::start(mpd.addr, lyrics::Dirs::default(), s);
+
+        let track = Track {
+            file: "a.flac".to_string(),
+            ..Default::default()
+        };
+        loader.notification(track);
+        match next(&events).await {
+            // the mock has no pictures
+            Loaded::Notification { track, cover } => {
+                assert_eq!(track.file, "a.flac");
+                assert!(cover.unwrap().is_none());
+            }
+            _ => panic!("expected a notification cover"),
+        }
+        assert!(mpd.commands().iter().any(|c| c.starts_with("albumart")));
+    }
+}
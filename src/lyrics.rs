@@ -0,0 +1,306 @@
+use crate::{config::Config, protocol::Connection};
+use async_mpd::Track;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    // `None` for lyrics without timestamps
+    pub time: Option<Duration>,
+    pub text: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Lyrics {
+    // file of the track these lyrics belong to
+    file: String,
+    lines: Vec<Line>,
+    // first line shown for lyrics without timestamps
+    scroll: u16,
+}
+
+impl Lyrics {
+    // Parses LRC lyrics, anything without timestamps is kept as plain text
+    pub fn parse(file: &str, text: &str) -> Lyrics {
+        let mut offset = 0i64;
+        let mut lines = Vec::new();
+
+        for line in text.lines() {
+            let mut rest = line.trim();
+            let mut times = Vec::new();
+
+            // a line can have more than one timestamp, e.g. [00:12.00][01:30.50]chorus
+            while let Some(end) = rest.strip_prefix('[').and_then(|r| r.find(']')) {
+                let tag = &rest[1..end + 1];
+                if let Some(t) = timestamp(tag) {
+                    times.push(t);
+                } else if let Some(ms) = tag.strip_prefix("offset:") {
+                    offset = ms.trim().parse().unwrap_or(0);
+                } else if !tag.contains(':') {
+                    // not a tag, just a line starting with a bracket
+                    break;
+                }
+                rest = &rest[end + 2..];
+            }
+
+            if times.is_empty() {
+                // metadata only lines would just leave a gap
+                if rest.is_empty() && line.trim().starts_with('[') {
+                    continue;
+                }
+                lines.push(Line {
+                    time: None,
+                    text: rest.to_string(),
+                });
+            } else {
+                lines.extend(times.into_iter().map(|t| Line {
+                    time: Some(t),
+                    text: rest.to_string(),
+                }));
+            }
+        }
+
+        // a positive offset means the lyrics show up sooner
+        if offset != 0 {
+            for line in &mut lines {
+                line.time = line.time.map(|t| {
+                    let ms = (t.as_millis() as i64).saturating_sub(offset);
+                    Duration::from_millis(ms.max(0) as u64)
+                });
+            }
+        }
+
+        if lines.iter().any(|l| l.time.is_some()) {
+            lines.retain(|l| l.time.is_some());
+            lines.sort_by_key(|l| l.time);
+        }
+
+        Lyrics {
+            file: file.to_string(),
+            lines,
+            scroll: 0,
+        }
+    }
+
+    pub fn file(&self) -> &str {
+        &self.file
+    }
+
+    pub fn lines(&self) -> &[Line] {
+        &self.lines
+    }
+
+    pub fn scroll(&self) -> u16 {
+        self.scroll
+    }
+
+    pub fn scroll_down(&mut self) {
+        if (self.scroll as usize) + 1 < self.lines.len() {
+            self.scroll += 1;
+        }
+    }
+
+    pub fn scroll_up(&mut self) {
+        self.scroll = self.scroll.saturating_sub(1);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.iter().all(|l| l.text.trim().is_empty())
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.lines.first().is_some_and(|l| l.time.is_some())
+    }
+
+    // Index of the line being sung at `elapsed`
+    pub fn current(&self, elapsed: Duration) -> Option<usize> {
+        if !self.is_synced() {
+            return None;
+        }
+
+        self.lines
+            .iter()
+            .rposition(|l| l.time.is_some_and(|t| t <= elapsed))
+    }
+}
+
+// Parses `mm:ss`, `mm:ss.xx` or `mm:ss:xx`
+fn timestamp(tag: &str) -> Option<Duration> {
+    let (min, sec) = tag.split_once(':')?;
+    let min: u64 = min.parse().ok()?;
+    let sec: f64 = sec.replacen(':', ".", 1).parse().ok()?;
+
+    // `inf`, `NaN`, `-1` and `1e300` parse as well, but aren't a duration
+    let sec = Duration::try_from_secs_f64(sec).ok()?;
+    Duration::from_secs(min.checked_mul(60)?).checked_add(sec)
+}
+
+// Where lyrics files are looked for, taken from the config so it can go to another task
+#[derive(Debug, Clone, Default)]
+pub struct Dirs {
+    music: Option<PathBuf>,
+    lyrics: Option<PathBuf>,
+}
+
+impl Dirs {
+    pub fn new(config: &Config) -> Dirs {
+        Dirs {
+            music: config.music_directory.clone(),
+            lyrics: config.lyrics_directory.clone(),
+        }
+    }
+}
+
+// Looks for lyrics next to the track, then in the lyrics dir, then in the file's tags.
+// Gives back empty lyrics if there are none anywhere.
+pub async fn load(dirs: &Dirs, conn: &mut Connection, track: &Track) -> Lyrics {
+    if let Some(lyrics) = load_files(dirs, track) {
+        return lyrics;
+    }
+
+    let text = match conn.command("readcomments", &[&track.file]).await {
+        Ok(comments) => comments
+            .into_iter()
+            .find(|(k, _)| {
+                k.eq_ignore_ascii_case("lyrics") || k.eq_ignore_ascii_case("unsyncedlyrics")
+            })
+            .map(|(_, text)| text),
+        Err(e) => {
+            log::warn!("Cannot read comments for {}: {}", track.file, e);
+            None
+        }
+    };
+    Lyrics::parse(&track.file, text.as_deref().unwrap_or_default())
+}
+
+// Lyrics from a file next to the track or in the lyrics dir
+pub fn load_files(dirs: &Dirs, track: &Track) -> Option<Lyrics> {
+    for path in candidates(dirs, track) {
+        if let Ok(text) = fs::read_to_string(&path) {
+            log::info!("Loaded lyrics from {}", path.display());
+            return Some(Lyrics::parse(&track.file, &text));
+        }
+    }
+    None
+}
+
+fn candidates(dirs: &Dirs, track: &Track) -> Vec<PathBuf> {
+    let file = Path::new(&track.file);
+    let mut paths = Vec::new();
+
+    if let Some(music) = &dirs.music {
+        let path = music.join(file);
+        paths.push(path.with_extension("lrc"));
+        paths.push(path.with_extension("txt"));
+    }
+
+    if let Some(dir) = &dirs.lyrics {
+        if let (Some(artist), Some(title)) = (&track.artist, &track.title) {
+            let name = format!("{} - {}", artist, title).replace('/', "_");
+            paths.push(dir.join(format!("{}.lrc", name)));
+            paths.push(dir.join(format!("{}.txt", name)));
+        }
+        if let Some(stem) = file.file_stem() {
+            let stem = stem.to_string_lossy();
+            paths.push(dir.join(format!("{}.lrc", stem)));
+            paths.push(dir.join(format!("{}.txt", stem)));
+        }
+    }
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn times(lyrics: &Lyrics) -> Vec<(Option<u64>, &str)> {
+        lyrics
+            .lines()
+            .iter()
+            .map(|l| (l.time.map(|t| t.as_millis() as u64), l.text.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn parses_timestamps() {
+        let lyrics = Lyrics::parse(
+            "a.flac",
+            "[ar:Someone]\n[00:12.50]First\n[01:02:25]Second\n[00:05]Intro",
+        );
+        assert!(lyrics.is_synced());
+        assert_eq!(
+            times(&lyrics),
+            [
+                (Some(5000), "Intro"),
+                (Some(12500), "First"),
+                (Some(62250), "Second")
+            ]
+        );
+    }
+
+    #[test]
+    fn repeats_lines_with_more_than_one_tag() {
+        let lyrics = Lyrics::parse("a.flac", "[00:10.00][00:30.00]Chorus\n[00:20.00]Verse");
+        assert_eq!(
+            times(&lyrics),
+            [
+                (Some(10000), "Chorus"),
+                (Some(20000), "Verse"),
+                (Some(30000), "Chorus")
+            ]
+        );
+    }
+
+    #[test]
+    fn applies_the_offset() {
+        let lyrics = Lyrics::parse("a.flac", "[offset:+500]\n[00:00.20]Zero\n[00:02.00]Two");
+        assert_eq!(times(&lyrics), [(Some(0), "Zero"), (Some(1500), "Two")]);
+
+        let lyrics = Lyrics::parse("a.flac", "[offset:-500]\n[00:02.00]Two");
+        assert_eq!(times(&lyrics), [(Some(2500), "Two")]);
+
+        let lyrics = Lyrics::parse("a.flac", "[offset:-9223372036854775808]\n[00:02.00]Two");
+        assert_eq!(times(&lyrics), [(Some(i64::MAX as u64), "Two")]);
+    }
+
+    #[test]
+    fn skips_bad_tags() {
+        let lyrics = Lyrics::parse(
+            "a.flac",
+            concat!(
+                "[00:inf]Infinite\n[00:NaN]Nothing\n[00:1e400]Huge\n[00:1e300]Too long\n",
+                "[00:-1]Negative\n[307445734561825861:00]Too many minutes\n",
+                "[307445734561825860:20]Just too long\n",
+                "[18446744073709551615:00]Most minutes\n[00:01.00]Fine",
+            ),
+        );
+        assert_eq!(times(&lyrics), [(Some(1000), "Fine")]);
+    }
+
+    #[test]
+    fn keeps_plain_text() {
+        let lyrics = Lyrics::parse("a.flac", "[ti:Title]\nFirst\n[not a tag\n\nLast");
+        assert!(!lyrics.is_synced());
+        assert_eq!(
+            times(&lyrics),
+            [
+                (None, "First"),
+                (None, "[not a tag"),
+                (None, ""),
+                (None, "Last")
+            ]
+        );
+        assert_eq!(lyrics.current(Duration::from_secs(10)), None);
+    }
+
+    #[test]
+    fn finds_the_current_line() {
+        let lyrics = Lyrics::parse("a.flac", "[00:10.00]One\n[00:20.00]Two");
+        assert_eq!(lyrics.current(Duration::from_secs(5)), None);
+        assert_eq!(lyrics.current(Duration::from_secs(10)), Some(0));
+        assert_eq!(lyrics.current(Duration::from_secs(25)), Some(1));
+    }
+}
@@ -1,33 +1,72 @@
+mod app;
+mod art;
+mod cli;
+mod command;
+mod config;
+mod dj;
 mod draw;
+mod help;
+mod hooks;
 mod input;
+#[cfg(unix)]
+mod ipc;
+mod keys;
+mod loader;
+mod lyrics;
+mod message;
+#[cfg(test)]
+mod mock;
+#[cfg(feature = "mpris")]
+mod mpris;
+#[cfg(feature = "notify")]
+mod notify;
+mod outputs;
 mod play;
+mod protocol;
+#[cfg(feature = "lua")]
+mod script;
+mod scrobble;
 mod search;
+mod smart;
 mod state;
+mod stats;
+mod stickers;
+mod terminal;
+mod visualizer;
 
 use anyhow::{Context, Result};
-use play::Songs;
-use state::StatefulList;
+use app::{AppState, Effect, Msg};
+use art::AlbumArt;
+use config::Config;
+use dj::AutoDj;
+use hooks::Hooks;
+use input::Input;
+use keys::KeyMap;
+use lyrics::Lyrics;
+use message::Messages;
+use protocol::Connection;
+use scrobble::Scrobbler;
+use stickers::Plays;
+use visualizer::Visualizer;
 
-use search::Search;
-
-use async_mpd::{Error, MpdClient, Subsystem};
+use async_mpd::{MpdClient, Subsystem};
 use async_std::{channel, prelude::*, stream, task};
 
-use crossterm::{
-    event::{DisableMouseCapture, EnableMouseCapture, Event, EventStream},
-    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
-    ExecutableCommand,
-};
-
-use directories_next as dirs;
+use crossterm::event::{Event, EventStream};
 
 use std::{
+    collections::VecDeque,
     io,
     net::{Ipv4Addr, SocketAddrV4},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
-use tui::{backend::CrosstermBackend, Terminal};
+use tui::{backend::CrosstermBackend, layout::Rect, Terminal};
 
 use structopt::StructOpt;
 
@@ -39,97 +78,244 @@ struct Opt {
 
     #[structopt(short, long, default_value = "6600")]
     port: u16,
+
+    /// Prints one JSON value per line from commands, for scripts and status bars
+    #[structopt(long, global = true)]
+    json: bool,
+
+    // runs a single command instead of the TUI
+    #[structopt(subcommand)]
+    cmd: Option<cli::Cmd>,
 }
 
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum Mode {
+    #[default]
     Browsing,
     Selecting,
     Searching,
+    Help,
+    Command,
 }
 
-impl Default for Mode {
-    fn default() -> Self {
-        Mode::Browsing
+impl Mode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mode::Browsing => "browsing",
+            Mode::Selecting => "selecting",
+            Mode::Searching => "searching",
+            Mode::Help => "help",
+            Mode::Command => "command",
+        }
+    }
+}
+
+// What takes up the space above the gauge
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub enum View {
+    #[default]
+    Queue,
+    Lyrics,
+    Visualizer,
+    Outputs,
+    Stats,
+}
+
+impl View {
+    pub fn name(&self) -> &'static str {
+        match self {
+            View::Queue => "queue",
+            View::Lyrics => "lyrics",
+            View::Visualizer => "visualizer",
+            View::Outputs => "outputs",
+            View::Stats => "stats",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<View> {
+        Some(match name {
+            "queue" => View::Queue,
+            "lyrics" => View::Lyrics,
+            "visualizer" => View::Visualizer,
+            "outputs" => View::Outputs,
+            "stats" => View::Stats,
+            _ => return None,
+        })
+    }
+}
+
+// Passes on what changed in MPD, and when the connection is lost and back again
+async fn listen(mut event_listener: MpdClient, s: channel::Sender<EventMessage>) {
+    loop {
+        let message = match event_listener.idle().await {
+            Ok(Some(u)) => EventMessage::Mpd(u),
+            // a subsystem async_mpd doesn't know about
+            Ok(None) => continue,
+            Err(e) => {
+                log::warn!("Lost connection to MPD: {}", e);
+                if s.send(EventMessage::Disconnected).await.is_err() {
+                    break;
+                }
+                protocol::reconnect(&mut event_listener).await;
+                EventMessage::Reconnected
+            }
+        };
+        if s.send(message).await.is_err() {
+            break;
+        }
     }
 }
 
 type Term = Terminal<CrosstermBackend<io::Stdout>>;
 
+// The state and everything around it that talks to the terminal, MPD or the rest of the system
 struct App {
-    song_list: StatefulList<Songs>,
-    results: StatefulList<Songs>,
-    search: Search,
-    mode: Mode,
+    state: AppState,
+    config: Config,
+    // only there if a fifo is configured
+    visualizer: Option<Visualizer>,
+    // whether the visualizer is on screen, frames only tick while it is
+    showing_visualizer: Arc<AtomicBool>,
+    plays: Plays,
+    hooks: Hooks,
+    // only there if `[scrobble]` is configured
+    scrobbler: Option<Scrobbler>,
+    #[cfg(feature = "mpris")]
+    mpris: Option<mpris::Mpris>,
+    // the control socket, gone once the app is dropped
+    #[cfg(unix)]
+    ipc: Option<ipc::Server>,
+    #[cfg(feature = "notify")]
+    notifier: Option<notify::Notifier>,
+    // only there if init.lua loaded
+    #[cfg(feature = "lua")]
+    scripts: Option<script::Scripts>,
+    art: AlbumArt,
+    // fetches covers, lyrics and library stats, started along with the connections
+    loader: Option<loader::Loader>,
+    // library stats were asked for and haven't come back yet
+    library_pending: bool,
+    // where the cover goes, set on every draw
+    art_area: Option<Rect>,
     term: Term,
+    // dropped last, even when `run` returns early the terminal is usable again
+    _guard: terminal::Guard,
 }
 
 impl App {
-    pub fn new() -> Result<App> {
+    pub fn new(config: Config) -> Result<App> {
+        let keymap = KeyMap::new(&config.keys, &config.playlists)?;
+        let messages = Messages::default();
+        // a broken script shouldn't keep rmpc from starting
+        #[cfg(feature = "lua")]
+        let (mut keymap, mut messages) = (keymap, messages);
+        #[cfg(feature = "lua")]
+        let scripts = match config::dirs().map(|d| script::Scripts::load(d.config_dir())) {
+            Some(Ok(scripts)) => match scripts.bind(&mut keymap) {
+                Ok(()) => Some(scripts),
+                Err(e) => {
+                    messages.warn(format!("Cannot bind script keys: {}", e));
+                    None
+                }
+            },
+            Some(Err(e)) => {
+                messages.warn(format!("Cannot load scripts: {}", e));
+                None
+            }
+            None => None,
+        };
+        let dj = AutoDj::new(&config.auto_dj);
+        // failed scrobbles wait in the data dir
+        let scrobbler = config
+            .scrobble
+            .as_ref()
+            .zip(config::dirs())
+            .map(|(scrobble, dirs)| Scrobbler::new(scrobble, dirs.data_dir().to_path_buf()));
         let stdout = io::stdout();
         let backend = CrosstermBackend::new(stdout);
+        let guard = terminal::Guard::enter()?;
         let mut term = Terminal::new(backend).context("Failed on TUI initialization")?;
-
-        enable_raw_mode()?;
-        term.backend_mut()
-            .execute(EnterAlternateScreen)?
-            .execute(EnableMouseCapture)?;
         term.clear()?;
         Ok(App {
             term,
-            search: Default::default(),
-            mode: Default::default(),
-            song_list: Default::default(),
-            results: Default::default(),
+            state: AppState::new(keymap, messages, config.playlists.clone(), dj),
+            visualizer: config.visualizer.as_ref().map(Visualizer::new),
+            showing_visualizer: Default::default(),
+            config,
+            plays: Default::default(),
+            hooks: Default::default(),
+            scrobbler,
+            #[cfg(feature = "mpris")]
+            mpris: None,
+            #[cfg(unix)]
+            ipc: None,
+            #[cfg(feature = "notify")]
+            notifier: None,
+            #[cfg(feature = "lua")]
+            scripts,
+            art: Default::default(),
+            loader: None,
+            library_pending: false,
+            art_area: None,
+            _guard: guard,
         })
     }
 
-    fn end(&mut self) -> Result<()> {
-        self.term.show_cursor()?;
-        disable_raw_mode()?;
-        self.term
-            .backend_mut()
-            .execute(LeaveAlternateScreen)?
-            .execute(DisableMouseCapture)?;
-        Ok(())
-    }
-
-    fn args() -> Opt {
-        Opt::from_args()
-    }
-
-    pub async fn run(mut self) -> Result<()> {
-        if let Some(dir) = dirs::ProjectDirs::from("org", "abyss", "rmpc") {
-            let mut data = dir.data_dir().to_path_buf();
-            std::fs::create_dir_all(&data)?;
-            data.push("rmpc.log");
-            simple_logging::log_to_file(data, log::LevelFilter::Info)?;
-        }
+    pub async fn run(mut self, addr: SocketAddrV4) -> Result<()> {
         log::info!("Starting up");
-        let opts = App::args();
-        let addr = SocketAddrV4::new(opts.ip, opts.port);
         let mut client = MpdClient::new(addr).await.context("Failed to start MPD client. Is it started and on 127.0.0.1:6600 or the specified port/ip?")?;
-        let mut event_listener = MpdClient::new(addr).await.context("Failed to start MPD client. Is it started and on 127.0.0.1:6600 or the specified port/ip?")?;
+        let event_listener = MpdClient::new(addr).await.context("Failed to start MPD client. Is it started and on 127.0.0.1:6600 or the specified port/ip?")?;
+        let mut conn = Connection::new(addr).await.context("Failed to start MPD client. Is it started and on 127.0.0.1:6600 or the specified port/ip?")?;
+
+        // Listening to MPD events
+        let (s, mut r) = channel::bounded(1);
+        let dirs = lyrics::Dirs::new(&self.config);
+        self.loader = Some(loader::Loader::start(addr, dirs, s.clone()));
 
         // start at the beginning of list
-        self.song_list.next();
+        self.state.song_list.next();
 
         // initial state
-        self.song_list.set_status(client.status().await.ok());
-        self.song_list
-            .set_songs(&client.queue().await.unwrap_or_default());
+        if let Err(e) = self.resync(&mut client, &mut conn).await {
+            self.state.messages.error(protocol::error_message(&e));
+        }
 
         self.draw().await?;
 
-        // Listening to MPD events
-        let (s, mut r) = channel::bounded(1);
         let s2 = s.clone();
         let s3 = s.clone();
+        let s4 = s.clone();
 
-        task::spawn(async move {
-            while let Some(u) = event_listener.idle().await.ok().flatten() {
-                s.send(EventMessage::Mpd(u)).await.unwrap();
+        #[cfg(feature = "mpris")]
+        match mpris::Mpris::new(s.clone(), self.config.music_directory.clone()).await {
+            Ok(mpris) => self.mpris = Some(mpris),
+            Err(e) => self
+                .state
+                .messages
+                .warn(format!("Cannot start MPRIS: {}", e)),
+        }
+
+        #[cfg(unix)]
+        match ipc::Server::start(s.clone()).await {
+            Ok(server) => self.ipc = Some(server),
+            Err(e) => self
+                .state
+                .messages
+                .warn(format!("Cannot listen for IPC commands: {}", e)),
+        }
+
+        #[cfg(feature = "notify")]
+        if self.config.notifications.enabled {
+            match notify::Notifier::new(self.state.song_list.status()).await {
+                Ok(notifier) => self.notifier = Some(notifier),
+                Err(e) => self
+                    .state
+                    .messages
+                    .warn(format!("Cannot send notifications: {}", e)),
             }
-        });
+        }
+
+        task::spawn(listen(event_listener, s));
 
         // Listening to term events
         let mut input = EventStream::new();
@@ -149,122 +335,594 @@ impl App {
             }
         });
 
+        // Listening to visualizer frames, separate from the gauge so it can go a lot faster
+        if let Some(v) = &self.visualizer {
+            let mut frames = stream::interval(v.frame_time());
+            let showing = self.showing_visualizer.clone();
+
+            task::spawn(async move {
+                while frames.next().await.is_some() {
+                    if !showing.load(Ordering::Relaxed) {
+                        continue;
+                    }
+                    // skip frames instead of holding up other events
+                    if let Err(channel::TrySendError::Closed(_)) = s4.try_send(EventMessage::Frame)
+                    {
+                        break;
+                    }
+                }
+            });
+        }
+
         // handling all events
         while let Some(u) = r.next().await {
+            let publish = !matches!(u, EventMessage::Frame);
             match u {
                 EventMessage::Term(e) => {
                     if let Event::Key(k) = e {
-                        if let Ok(b) = input::use_key(
-                            &mut client,
-                            &mut self.song_list,
-                            &mut self.results,
-                            &mut self.search,
-                            &mut self.mode,
-                            k.code,
-                        )
-                        .await
-                        {
-                            self.draw().await?;
-                            if let input::Status::Break = b {
-                                self.end()?;
+                        match self.input(&mut client, &mut conn, Input::Key(k.code)).await {
+                            Ok(input::Status::Break) => {
                                 break;
                             }
+                            Ok(_) => {}
+                            Err(e) => self.failed(&mut client, &mut conn, &e).await,
                         }
+                        self.draw().await?;
                     } else if let Event::Resize(_, _) = e {
+                        // the terminal gets cleared, so images need to be drawn again
+                        self.art.invalidate();
                         self.draw().await?;
                     }
                 }
                 EventMessage::Mpd(u) => {
                     self.draw().await?;
-                    match u {
-                        Subsystem::Player | Subsystem::Mixer => {
-                            let status = match client.status().await {
-                                Ok(s) => Some(s),
-                                Err(Error::Disconnected) => {
-                                    client.reconnect().await?;
-                                    None
-                                }
-                                _ => None,
-                            };
-                            self.song_list.set_status(status);
+                    // covers might have changed in the update as well
+                    if let Subsystem::Database = u {
+                        self.art.clear();
+                        // what's loading now is out of date, the loader drops it for this one
+                        if let Some(loader) = self.loader.as_ref().filter(|_| self.library_pending)
+                        {
+                            loader.library();
                         }
-                        Subsystem::Playlist | Subsystem::StoredPlaylist => {
-                            let queue = client.queue().await;
-                            self.song_list
-                                .set_songs(&queue.context("Can't set songs from update")?);
+                    }
+                    let effects = self.state.update(Msg::Changed(u));
+                    if let Err(e) = self.dispatch(&mut client, &mut conn, effects).await {
+                        self.failed(&mut client, &mut conn, &e).await;
+                    }
+                    self.draw().await?;
+                }
+                EventMessage::Disconnected => {
+                    self.state.update(Msg::Disconnected);
+                    self.draw().await?;
+                }
+                EventMessage::Reconnected => {
+                    self.reconnected(&mut client, &mut conn).await;
+                    self.draw().await?;
+                }
+                EventMessage::Frame => {
+                    if let View::Visualizer = self.state.view {
+                        self.draw().await?;
+                    }
+                }
+                EventMessage::Loaded(loaded) => {
+                    self.loaded(loaded).await;
+                    self.draw().await?;
+                }
+                EventMessage::Tick => {
+                    let expired = self.state.messages.tick();
+                    match self.state.song_list.status() {
+                        Some(u) if self.state.connected && u.state.as_str() == "play" => {
+                            self.state.song_list.set_status(client.status().await.ok());
+                            // keeps how far into the song playback got up to date for the hooks
+                            self.hooks.update(
+                                &self.config.hooks,
+                                self.state.song_list.status(),
+                                self.state.song_list.playing(),
+                            );
+                            self.record_play(&mut conn).await;
+                            self.scrobble();
+                            self.draw().await?;
                         }
+                        _ if expired => self.draw().await?,
                         _ => {}
                     }
                 }
-                EventMessage::Tick => {
-                    if let Some(u) = self.song_list.status() {
-                        if u.state.as_str() == "play" {
-                            self.song_list.set_status(client.status().await.ok());
+                #[cfg(unix)]
+                EventMessage::Ipc(request) => {
+                    let (reply, status) =
+                        match self.ipc(&mut client, &mut conn, request.command).await {
+                            Ok((value, status)) => (Ok(value), status),
+                            Err(e) => {
+                                self.failed(&mut client, &mut conn, &e).await;
+                                (Err(e), input::Status::Continue)
+                            }
+                        };
+                    // the other end might have hung up already
+                    let _ = request.reply.try_send(reply);
+                    if let input::Status::Break = status {
+                        break;
+                    }
+                    self.draw().await?;
+                }
+                #[cfg(feature = "mpris")]
+                EventMessage::Remote(line) => {
+                    match command::run(&mut client, &mut conn, &line).await {
+                        Ok(command::Outcome::Quit) => {
+                            break;
+                        }
+                        // whatever changed comes back through the idle connection
+                        Ok(_) => {}
+                        Err(e) => {
+                            self.state.messages.error(protocol::error_message(&e));
                             self.draw().await?;
                         }
                     }
                 }
             }
+            if publish {
+                self.publish().await;
+            }
+            self.showing_visualizer
+                .store(self.state.view == View::Visualizer, Ordering::Relaxed);
         }
         Ok(())
     }
 
-    async fn draw(&mut self) -> Result<()> {
-        let term = &mut self.term;
-        let song_list = &mut self.song_list;
-        let results = &mut self.results;
-        let srch = &mut self.search;
-        let mode = &self.mode;
-
-        term.draw(|f| {
-            let chunks = draw::chunks(song_list, f);
-            let search = match &chunks {
-                draw::DrawLayout::Normal { search, .. } | draw::DrawLayout::Empty(_, search) => {
-                    search
+    // Runs what a key press or the IPC socket asked for
+    async fn input(
+        &mut self,
+        client: &mut MpdClient,
+        conn: &mut Connection,
+        input: Input,
+    ) -> Result<input::Status> {
+        let effects = self.state.update(Msg::Input(input));
+        self.dispatch(client, conn, effects).await
+    }
+
+    // Has MPD do what the state asked for and feeds the answers back, until nothing is left
+    async fn dispatch(
+        &mut self,
+        client: &mut MpdClient,
+        conn: &mut Connection,
+        effects: Vec<Effect>,
+    ) -> Result<input::Status> {
+        let mut effects = VecDeque::from(effects);
+        while let Some(effect) = effects.pop_front() {
+            match effect {
+                Effect::Quit => return Ok(input::Status::Break),
+                #[cfg(feature = "lua")]
+                Effect::Script(i) => self.run_script(i, conn).await,
+                effect => {
+                    let msg = input::execute(client, conn, &mut self.state, effect).await?;
+                    let status = matches!(msg, Some(Msg::Status(_)));
+                    if let Some(msg) = msg {
+                        effects.extend(self.state.update(msg));
+                    }
+                    if status {
+                        self.status_changed(client, conn).await;
+                    }
                 }
-            };
+            }
+        }
+
+        self.load_art();
+        self.load_lyrics();
+        if self.state.view == View::Stats && !self.state.library.is_loaded() {
+            self.load_library();
+        }
+        Ok(input::Status::Continue)
+    }
 
-            if let draw::DrawLayout::Normal {
-                songs,
-                gauge,
-                search,
-            } = &chunks
+    // Lets hooks, scrobbling and everything else that follows playback know
+    async fn status_changed(&mut self, client: &mut MpdClient, conn: &mut Connection) {
+        self.hooks.update(
+            &self.config.hooks,
+            self.state.song_list.status(),
+            self.state.song_list.playing(),
+        );
+        self.record_play(conn).await;
+        self.scrobble();
+        self.notify().await;
+        self.refill_queue(client, conn).await;
+    }
+
+    #[cfg(feature = "lua")]
+    async fn run_script(&mut self, index: usize, conn: &mut Connection) {
+        if let Some(scripts) = &self.scripts {
+            let state = &mut self.state;
+            if let Err(e) = scripts
+                .run(index, conn, &state.song_list, &mut state.messages)
+                .await
             {
-                let draw::Chunks { list, tags } = songs;
-                draw::tags(song_list.tags(), f, *tags);
-                draw::gauge(song_list.status(), f, *gauge);
-                draw::list(song_list, f, *list);
-                if let Mode::Searching | Mode::Selecting = mode {
-                    if f.size().height >= 3 {
-                        draw::search(results, f, *search, srch);
+                state.messages.error(format!("Script failed: {}", e));
+            }
+        }
+    }
+
+    // Does what a line on the IPC socket asked for, returns what to answer
+    #[cfg(unix)]
+    async fn ipc(
+        &mut self,
+        client: &mut MpdClient,
+        conn: &mut Connection,
+        command: ipc::Command,
+    ) -> Result<(serde_json::Value, input::Status)> {
+        let effects = match command {
+            ipc::Command::Action(action) => self.state.update(Msg::Input(Input::Action(action))),
+            ipc::Command::View(view) => self.state.switch_view(view),
+            ipc::Command::Search(query) => {
+                let effects = self.state.search_for(&query);
+                self.dispatch(client, conn, effects).await?;
+                let found = self.state.results.len();
+                return Ok((found.into(), input::Status::Continue));
+            }
+            ipc::Command::JumpToPlaying => {
+                let pos = self.state.jump_to_playing();
+                self.load_art();
+                return Ok((pos.into(), input::Status::Continue));
+            }
+            ipc::Command::Run(line) => {
+                return match command::run(client, conn, &line).await? {
+                    command::Outcome::Quit => Ok((serde_json::Value::Null, input::Status::Break)),
+                    command::Outcome::Done(message) => {
+                        if let Some(m) = &message {
+                            self.state.messages.success(m.as_str());
+                        }
+                        Ok((message.into(), input::Status::Continue))
                     }
+                };
+            }
+            ipc::Command::State => return Ok((self.state.summary(), input::Status::Continue)),
+        };
+        let status = self.dispatch(client, conn, effects).await?;
+        Ok((serde_json::Value::Null, status))
+    }
+
+    // Shows what went wrong, unless MPD is gone and it's time to reconnect
+    async fn failed(&mut self, client: &mut MpdClient, conn: &mut Connection, e: &anyhow::Error) {
+        if protocol::is_disconnect(e) {
+            self.lost_connection(client, conn).await;
+        } else {
+            self.state.messages.error(protocol::error_message(e));
+        }
+    }
+
+    // Lets everything outside of rmpc know what is playing
+    async fn publish(&self) {
+        #[cfg(feature = "mpris")]
+        if let Some(mpris) = &self.mpris {
+            let playing = self.state.song_list.playing();
+            if let Err(e) = mpris.update(self.state.song_list.status(), playing).await {
+                log::warn!("Cannot update MPRIS: {}", e);
+            }
+        }
+    }
+
+    // Fetches everything shown from MPD again, after starting or reconnecting
+    async fn resync(&mut self, client: &mut MpdClient, conn: &mut Connection) -> Result<()> {
+        self.art.invalidate();
+        let effects = self.state.update(Msg::Connected);
+        self.dispatch(client, conn, effects).await?;
+        self.hooks.connected(
+            &self.config.hooks,
+            self.state.song_list.status(),
+            self.state.song_list.playing(),
+        );
+        Ok(())
+    }
+
+    // Called once the idle connection is back, the other two are most likely gone as well.
+    // Keeps trying until they're back too, otherwise nothing would try again later.
+    async fn reconnected(&mut self, client: &mut MpdClient, conn: &mut Connection) {
+        loop {
+            protocol::reconnect_commands(client, conn).await;
+            match self.resync(client, conn).await {
+                // gone again already
+                Err(e) if protocol::is_disconnect(&e) => {
+                    log::info!("Lost the connection again while resyncing: {}", e)
                 }
-            } else if let draw::DrawLayout::Empty(songs, search) = &chunks {
-                draw::list(song_list, f, *songs);
-                if let Mode::Searching | Mode::Selecting = mode {
-                    if f.size().height >= 3 {
-                        draw::search(results, f, *search, srch);
-                    }
+                Err(e) => {
+                    self.state.connected = true;
+                    self.state.messages.error(protocol::error_message(&e));
+                    return;
+                }
+                Ok(()) => {
+                    self.state.connected = true;
+                    self.state.messages.success("Reconnected to MPD");
+                    return;
                 }
             }
-            if let Mode::Searching | Mode::Selecting = mode {
-                let search_box = srch.get(search.width as usize);
+        }
+    }
 
-                let columns = ((search.x + 1) as usize + search_box.len()) as u16;
-                let rows = search.height / 2;
+    // A command failed because its connection was closed. MPD closes connections that are
+    // quiet for too long, so this is only an outage if the idle connection noticed as well.
+    async fn lost_connection(&mut self, client: &mut MpdClient, conn: &mut Connection) {
+        if !self.state.connected {
+            self.state
+                .messages
+                .warn("Not connected to MPD, still retrying…");
+            return;
+        }
+        match client.reconnect().await {
+            Ok(()) => {
+                self.state
+                    .messages
+                    .warn("The connection to MPD was reset, please try again");
+                if let Err(e) = self.resync(client, conn).await {
+                    log::warn!("Cannot resync after reconnecting: {}", e);
+                }
+            }
+            Err(_) => {
+                self.state.connected = false;
+                self.state.messages.error("Lost connection to MPD");
+            }
+        }
+    }
 
-                if !results.is_empty() {
-                    match mode {
-                        Mode::Selecting => {}
-                        Mode::Searching => f.set_cursor(columns, 2),
-                        _ => f.set_cursor(columns, 2),
-                    }
-                } else {
-                    f.set_cursor(columns, rows);
+    // Lets the desktop know the song changed
+    async fn notify(&mut self) {
+        #[cfg(feature = "notify")]
+        if let Some(notifier) = &mut self.notifier {
+            let config = &self.config.notifications;
+            let track = notifier
+                .update(
+                    config,
+                    self.state.song_list.status(),
+                    self.state.song_list.playing(),
+                )
+                .await;
+            match (track, &self.loader) {
+                (Some(track), Some(loader)) if config.cover => loader.notification(track),
+                (Some(track), _) => self.send_notification(&track, None).await,
+                (None, _) => {}
+            }
+        }
+    }
+
+    #[cfg(feature = "notify")]
+    async fn send_notification(
+        &mut self,
+        track: &async_mpd::Track,
+        cover: Option<zbus::zvariant::Value<'static>>,
+    ) {
+        if let Some(notifier) = &mut self.notifier {
+            if let Err(e) = notifier
+                .send(&self.config.notifications, track, cover)
+                .await
+            {
+                self.state
+                    .messages
+                    .warn(format!("Cannot send a notification: {}", e));
+            }
+        }
+    }
+
+    fn scrobble(&mut self) {
+        if let Some(scrobbler) = &mut self.scrobbler {
+            scrobbler.update(
+                self.state.song_list.status(),
+                self.state.song_list.playing(),
+            );
+        }
+    }
+
+    // Bumps the play count of the last song once it was played to the end
+    async fn record_play(&mut self, conn: &mut Connection) {
+        let finished = self.plays.update(
+            self.state.song_list.status(),
+            self.state.song_list.playing(),
+        );
+        if let (Some(file), true) = (finished, self.config.record_plays) {
+            if let Err(e) = stickers::record_play(conn, &file).await {
+                self.state
+                    .messages
+                    .warn(format!("Cannot record play of {}: {}", file, e));
+            }
+        }
+    }
+
+    // Adds songs from the refilling smart playlist once only a few are left,
+    // or similar ones if the auto-DJ is on
+    async fn refill_queue(&mut self, client: &mut MpdClient, conn: &mut Connection) {
+        if let Some(track) = self.state.song_list.playing() {
+            self.state.dj.played(&track.file);
+        }
+
+        let playlist = match self.state.refill {
+            Some(i) => &self.config.playlists[i],
+            None if self.state.dj.is_enabled() => return self.auto_dj(client, conn).await,
+            None => return,
+        };
+        let left = match self.state.song_list.status().and_then(smart::songs_left) {
+            Some(left) => left,
+            None => return,
+        };
+        if left >= playlist.refill_below {
+            return;
+        }
+
+        match smart::queue(
+            client,
+            conn,
+            &self.state.ratings,
+            playlist,
+            &self.state.song_list,
+            false,
+        )
+        .await
+        {
+            Ok(0) => {
+                self.state.refill = None;
+                self.state.messages.warn(format!(
+                    "Nothing left in {}, stopped refilling the queue",
+                    playlist.name
+                ));
+            }
+            Ok(n) => self
+                .state
+                .messages
+                .info(format!("Added {} songs from {}", n, playlist.name)),
+            Err(e) => {
+                self.state.refill = None;
+                self.state.messages.error(format!(
+                    "Cannot refill the queue from {}: {}",
+                    playlist.name,
+                    protocol::error_message(&e)
+                ));
+            }
+        }
+    }
+
+    async fn auto_dj(&mut self, client: &mut MpdClient, conn: &mut Connection) {
+        let config = &self.config.auto_dj;
+        let left = match self.state.song_list.status().and_then(smart::songs_left) {
+            Some(left) => left,
+            None => return,
+        };
+        // the last song queued leads to the next ones
+        let seed = match self.state.song_list.last() {
+            Some(seed) if left < config.below => seed.clone(),
+            _ => return,
+        };
+
+        let result = self
+            .state
+            .dj
+            .fill(
+                client,
+                conn,
+                &self.state.ratings,
+                config,
+                &seed,
+                &self.state.song_list,
+            )
+            .await;
+        match result {
+            Ok(0) => {
+                self.state.dj.toggle();
+                self.state
+                    .messages
+                    .warn("Auto-DJ found nothing new to play and turned off");
+            }
+            Ok(n) => self
+                .state
+                .messages
+                .info(format!("Auto-DJ added {} songs", n)),
+            Err(e) => {
+                self.state.dj.toggle();
+                self.state.messages.error(format!(
+                    "Auto-DJ turned off: {}",
+                    protocol::error_message(&e)
+                ));
+            }
+        }
+    }
+
+    // Big libraries take a while, the stats view says it's loading until they're back
+    fn load_library(&mut self) {
+        if let Some(loader) = self.loader.as_ref().filter(|_| !self.library_pending) {
+            self.library_pending = true;
+            loader.library();
+        }
+    }
+
+    // Shows the cover of the selected song's album
+    fn load_art(&mut self) {
+        let track = self.state.song_list.selected();
+        let key = self.art.show(track);
+        if let Some(((key, track), loader)) = key.zip(track).zip(self.loader.as_ref()) {
+            loader.art(key, track.clone());
+        }
+    }
+
+    // Loads the lyrics when the playing song changed
+    fn load_lyrics(&mut self) {
+        match self.state.song_list.playing() {
+            Some(track) if track.file != self.state.lyrics.file() => {
+                // empty until they're back, so they're only asked for once
+                self.state.lyrics = Lyrics::parse(&track.file, "");
+                if let Some(loader) = &self.loader {
+                    loader.lyrics(track.clone());
                 }
             }
-        })
-        .context("Error in rendering loop")?;
+            Some(_) => {}
+            None => self.state.lyrics = Lyrics::default(),
+        }
+    }
+
+    // Takes what the loader fetched
+    async fn loaded(&mut self, loaded: loader::Loaded) {
+        match loaded {
+            loader::Loaded::Art {
+                key,
+                cover: Ok(cover),
+            } => self.art.loaded(key, cover),
+            loader::Loaded::Art { key, cover: Err(e) } => {
+                self.art.failed(&key);
+                self.state
+                    .messages
+                    .warn(format!("Cannot load album art: {}", e));
+            }
+            // the song might have changed again while these were fetched
+            loader::Loaded::Lyrics(lyrics) => {
+                if self.state.song_list.playing().map(|t| t.file.as_str()) == Some(lyrics.file()) {
+                    self.state.lyrics = lyrics;
+                }
+            }
+            loader::Loaded::Library(library) => {
+                self.library_pending = false;
+                match library {
+                    Ok(library) => self.state.library = library,
+                    Err(e) => self
+                        .state
+                        .messages
+                        .error(format!("Cannot load library stats: {}", e)),
+                }
+            }
+            // a newer song has its own notification on the way
+            #[cfg(feature = "notify")]
+            loader::Loaded::Notification { track, cover } => {
+                if self.state.song_list.playing().map(|t| &t.file) != Some(&track.file) {
+                    return;
+                }
+                let cover = cover.unwrap_or_else(|e| {
+                    log::warn!("Cannot load the cover for {}: {}", track.file, e);
+                    None
+                });
+                self.send_notification(&track, cover).await;
+            }
+        }
+    }
+
+    async fn draw(&mut self) -> Result<()> {
+        let screen = draw::Screen {
+            songs: &self.state.song_list,
+            results: &self.state.results,
+            search: &self.state.search,
+            mode: self.state.mode,
+            view: self.state.view,
+            help: &self.state.help,
+            keymap: &self.state.keymap,
+            cmdline: &self.state.cmdline,
+            messages: &self.state.messages,
+            connected: self.state.connected,
+            lyrics: &self.state.lyrics,
+            outputs: &self.state.outputs,
+            library: &self.state.library,
+            ratings: &self.state.ratings,
+            visualizer: self.visualizer.as_mut(),
+            art: &self.art,
+        };
+        let art_area = &mut self.art_area;
+
+        self.term
+            .draw(|f| *art_area = draw::screen(f, screen))
+            .context("Error in rendering loop")?;
+
+        self.art
+            .render(self.term.backend_mut(), self.art_area)
+            .context("Error drawing album art")?;
 
         Ok(())
     }
@@ -274,13 +932,132 @@ impl App {
 enum EventMessage {
     Term(Event),
     Mpd(Subsystem),
+    // the idle connection was closed and is being retried
+    Disconnected,
+    Reconnected,
     Tick,
+    Frame,
+    // a cover, lyrics or library stats fetched in the background
+    Loaded(loader::Loaded),
+    // a line from the IPC socket
+    #[cfg(unix)]
+    Ipc(ipc::Request),
+    // a `:` command from outside of the terminal
+    #[cfg(feature = "mpris")]
+    Remote(String),
+}
+
+// Errors and what hooks print go to rmpc.log in the data dir, returns where that is
+fn start_logging() -> Result<Option<PathBuf>> {
+    let dir = match config::dirs() {
+        Some(dirs) => dirs.data_dir().to_path_buf(),
+        None => return Ok(None),
+    };
+    std::fs::create_dir_all(&dir)?;
+    let log = dir.join("rmpc.log");
+    simple_logging::log_to_file(&log, log::LevelFilter::Info)?;
+    Ok(Some(log))
 }
 
 #[async_std::main]
 async fn main() -> Result<()> {
-    let app = App::new()?;
-    app.run().await?;
+    let opts = Opt::from_args();
+    let addr = SocketAddrV4::new(opts.ip, opts.port);
+    // no raw mode or alternate screen, the output is meant for scripts
+    if let Some(cmd) = opts.cmd {
+        return cli::run(cmd, addr, opts.json, &mut io::stdout()).await;
+    }
+
+    let config = Config::load()?;
+    terminal::set_panic_hook(start_logging()?);
+    let app = App::new(config)?;
+    app.run(addr).await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::future;
+    use mock::Mpd;
+    use play::Songs;
+    use state::StatefulList;
+
+    async fn next(r: &channel::Receiver<EventMessage>) -> EventMessage {
+        future::timeout(Duration::from_secs(5), r.recv())
+            .await
+            .expect("no event from MPD")
+            .unwrap()
+    }
+
+    #[test]
+    fn passes_on_changes() {
+        task::block_on(async {
+            let mpd = Mpd::start(vec![mock::song("a/1.flac", "One", "Alpha", "First")]).await;
+            let (s, r) = channel::bounded(1);
+            task::spawn(listen(MpdClient::new(mpd.addr).await.unwrap(), s));
+
+            let mut client = MpdClient::new(mpd.addr).await.unwrap();
+            client.queue_add("a").await.unwrap();
+            client.playid(1).await.unwrap();
+
+            assert!(matches!(
+                next(&r).await,
+                EventMessage::Mpd(Subsystem::Playlist)
+            ));
+            assert!(matches!(
+                next(&r).await,
+                EventMessage::Mpd(Subsystem::Player)
+            ));
+
+            // what the event loop does with them
+            let mut list = StatefulList::<Songs>::default();
+            list.set_songs(&client.queue().await.unwrap());
+            list.set_status(Some(client.status().await.unwrap()));
+            assert_eq!(list.playing().map(|s| s.file.as_str()), Some("a/1.flac"));
+        });
+    }
+
+    #[test]
+    fn reconnects_when_mpd_is_gone() {
+        task::block_on(async {
+            let mpd = Mpd::start(Vec::new()).await;
+            let (s, r) = channel::bounded(1);
+            task::spawn(listen(MpdClient::new(mpd.addr).await.unwrap(), s));
+
+            // wait for the listener to be idling
+            while !mpd.commands().contains(&"idle".to_string()) {
+                task::sleep(Duration::from_millis(5)).await;
+            }
+            mpd.disconnect();
+            assert!(matches!(next(&r).await, EventMessage::Disconnected));
+            assert!(matches!(next(&r).await, EventMessage::Reconnected));
+
+            // and keeps listening on the new connection
+            mpd.with(|s| s.changed("mixer"));
+            assert!(matches!(
+                next(&r).await,
+                EventMessage::Mpd(Subsystem::Mixer)
+            ));
+        });
+    }
+
+    #[test]
+    fn keeps_reconnecting_commands() {
+        task::block_on(async {
+            let mpd = Mpd::start(Vec::new()).await;
+            let mut client = MpdClient::new(mpd.addr).await.unwrap();
+            let mut conn = Connection::new(mpd.addr).await.unwrap();
+
+            mpd.disconnect();
+            // the first try fails
+            mpd.with(|s| s.refuse = 1);
+            protocol::reconnect_commands(&mut client, &mut conn).await;
+
+            assert_eq!(mpd.with(|s| s.refuse), 0);
+            assert!(client.status().await.is_ok());
+            assert!(conn.command("status", &[]).await.is_ok());
+        });
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+// most messages waiting to be shown, older ones are dropped
+const QUEUE_SIZE: usize = 10;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Severity {
+    Info,
+    Success,
+    Warning,
+    Error,
+}
+
+impl Severity {
+    // how long a message stays on screen, errors are usually worth reading twice
+    fn duration(&self) -> Duration {
+        match self {
+            Severity::Info | Severity::Success => Duration::from_secs(3),
+            Severity::Warning => Duration::from_secs(5),
+            Severity::Error => Duration::from_secs(8),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Message {
+    pub text: String,
+    pub severity: Severity,
+}
+
+// Notifications shown one at a time on the last line, each until it expires
+#[derive(Debug, Default)]
+pub struct Messages {
+    queue: VecDeque<Message>,
+    // when the front of the queue got there, it's on screen from then on. Messages waiting
+    // behind it don't expire.
+    shown: Option<Instant>,
+}
+
+impl Messages {
+    pub fn push(&mut self, text: impl Into<String>, severity: Severity) {
+        let text = text.into();
+        match severity {
+            Severity::Error => log::error!("{}", text),
+            Severity::Warning => log::warn!("{}", text),
+            _ => log::info!("{}", text),
+        }
+
+        // the same message again just restarts the timer
+        if let Some(last) = self.queue.back() {
+            if last.text == text && last.severity == severity {
+                if self.queue.len() == 1 {
+                    self.shown = Some(Instant::now());
+                }
+                return;
+            }
+        }
+
+        if self.queue.len() >= QUEUE_SIZE {
+            self.dismiss();
+        }
+        self.queue.push_back(Message { text, severity });
+        if self.queue.len() == 1 {
+            self.shown = Some(Instant::now());
+        }
+    }
+
+    pub fn info(&mut self, text: impl Into<String>) {
+        self.push(text, Severity::Info);
+    }
+
+    pub fn success(&mut self, text: impl Into<String>) {
+        self.push(text, Severity::Success);
+    }
+
+    pub fn warn(&mut self, text: impl Into<String>) {
+        self.push(text, Severity::Warning);
+    }
+
+    pub fn error(&mut self, text: impl Into<String>) {
+        self.push(text, Severity::Error);
+    }
+
+    pub fn current(&self) -> Option<&Message> {
+        self.queue.front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    // Messages waiting behind the current one
+    pub fn pending(&self) -> usize {
+        self.queue.len().saturating_sub(1)
+    }
+
+    pub fn dismiss(&mut self) {
+        self.queue.pop_front();
+        self.shown = self.queue.front().map(|_| Instant::now());
+    }
+
+    // Drops the current message once it expired, returns whether anything changed
+    pub fn tick(&mut self) -> bool {
+        match (self.queue.front(), self.shown) {
+            (Some(m), Some(shown)) if shown.elapsed() >= m.severity.duration() => {
+                self.dismiss();
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // pretends the current message has been on screen for `secs`
+    fn age(messages: &mut Messages, secs: u64) {
+        messages.shown = Some(Instant::now() - Duration::from_secs(secs));
+    }
+
+    fn texts(messages: &Messages) -> Vec<&str> {
+        messages.queue.iter().map(|m| m.text.as_str()).collect()
+    }
+
+    #[test]
+    fn skips_repeated_messages() {
+        let mut messages = Messages::default();
+        messages.info("one");
+        age(&mut messages, 2);
+        messages.info("one");
+        assert_eq!(texts(&messages), ["one"]);
+        // the timer started again
+        assert!(!messages.tick());
+
+        messages.error("one");
+        messages.info("two");
+        messages.info("two");
+        assert_eq!(texts(&messages), ["one", "one", "two"]);
+        assert_eq!(messages.pending(), 2);
+    }
+
+    #[test]
+    fn drops_the_oldest_when_full() {
+        let mut messages = Messages::default();
+        for i in 0..=QUEUE_SIZE {
+            messages.info(i.to_string());
+        }
+        assert_eq!(messages.len(), QUEUE_SIZE);
+        assert_eq!(messages.current().unwrap().text, "1");
+    }
+
+    #[test]
+    fn expires_one_message_at_a_time() {
+        let mut messages = Messages::default();
+        assert!(!messages.tick());
+        messages.info("info");
+        messages.error("error");
+
+        age(&mut messages, 2);
+        assert!(!messages.tick());
+        age(&mut messages, 3);
+        assert!(messages.tick());
+        assert_eq!(messages.current().unwrap().text, "error");
+
+        // waiting didn't count, it's on screen for as long as errors are from now on
+        age(&mut messages, 7);
+        assert!(!messages.tick());
+        age(&mut messages, 8);
+        assert!(messages.tick());
+        assert!(messages.current().is_none());
+        assert!(!messages.tick());
+    }
+}
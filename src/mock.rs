@@ -0,0 +1,757 @@
+use async_std::{
+    channel::{self, Receiver},
+    future,
+    io::BufReader,
+    net::{SocketAddrV4, TcpListener, TcpStream},
+    prelude::*,
+    task,
+};
+use std::{
+    collections::HashMap,
+    net::Shutdown,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+// How often idling connections look for changes
+const POLL: Duration = Duration::from_millis(5);
+
+// A song in the mock's library
+#[derive(Debug, Clone)]
+pub struct Song {
+    pub file: String,
+    // tags named like MPD names them
+    pub tags: Vec<(&'static str, String)>,
+    pub duration: f64,
+}
+
+pub fn song(file: &str, title: &str, artist: &str, album: &str) -> Song {
+    Song {
+        file: file.to_string(),
+        tags: vec![
+            ("Title", title.to_string()),
+            ("Artist", artist.to_string()),
+            ("Album", album.to_string()),
+        ],
+        duration: 180.0,
+    }
+}
+
+impl Song {
+    fn tag(&self, name: &str) -> Option<&str> {
+        if name.eq_ignore_ascii_case("file") {
+            return Some(&self.file);
+        }
+        self.tags
+            .iter()
+            .find(|(t, _)| t.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    // `any` matches on every tag, like it does in MPD
+    fn matches(&self, tag: &str, value: &str, exact: bool) -> bool {
+        let is = |v: &str| {
+            if exact {
+                v == value
+            } else {
+                v.to_lowercase().contains(&value.to_lowercase())
+            }
+        };
+        if tag.eq_ignore_ascii_case("any") {
+            is(&self.file) || self.tags.iter().any(|(_, v)| is(v))
+        } else {
+            self.tag(tag).is_some_and(is)
+        }
+    }
+
+    fn pairs(&self, out: &mut Vec<(String, String)>) {
+        out.push(("file".to_string(), self.file.clone()));
+        for (tag, value) in &self.tags {
+            out.push((tag.to_string(), value.clone()));
+        }
+        out.push(("duration".to_string(), format!("{:.3}", self.duration)));
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Output {
+    pub id: u32,
+    pub name: String,
+    pub enabled: bool,
+}
+
+// Everything the mock knows, tests can look at it and change it through `Mpd::with`
+#[derive(Debug)]
+pub struct State {
+    pub library: Vec<Song>,
+    // songs and their ids
+    pub queue: Vec<(u32, Song)>,
+    next_id: u32,
+    // play, pause or stop
+    pub state: String,
+    // position in the queue
+    pub current: Option<usize>,
+    pub elapsed: f64,
+    pub volume: u8,
+    pub repeat: bool,
+    pub random: bool,
+    pub single: bool,
+    pub consume: bool,
+    pub outputs: Vec<Output>,
+    // file and sticker name to value
+    pub stickers: HashMap<(String, String), String>,
+    pub playlists: HashMap<String, Vec<String>>,
+    update_job: u32,
+    version: u32,
+    // every command sent to any connection, with its arguments
+    pub commands: Vec<String>,
+    // how many of the next connections are closed before the greeting, like MPD still starting
+    pub refuse: usize,
+    // subsystems in the order they changed, every connection idles through them
+    changes: Vec<&'static str>,
+}
+
+impl State {
+    fn new(library: Vec<Song>) -> State {
+        State {
+            library,
+            queue: Vec::new(),
+            next_id: 1,
+            state: "stop".to_string(),
+            current: None,
+            elapsed: 0.0,
+            volume: 50,
+            repeat: false,
+            random: false,
+            single: false,
+            consume: false,
+            outputs: vec![
+                Output {
+                    id: 0,
+                    name: "Speakers".to_string(),
+                    enabled: true,
+                },
+                Output {
+                    id: 1,
+                    name: "Headphones".to_string(),
+                    enabled: false,
+                },
+            ],
+            stickers: HashMap::new(),
+            playlists: HashMap::new(),
+            refuse: 0,
+            update_job: 0,
+            version: 1,
+            commands: Vec::new(),
+            changes: Vec::new(),
+        }
+    }
+
+    // Files in the queue, in order
+    pub fn files(&self) -> Vec<&str> {
+        self.queue.iter().map(|(_, s)| s.file.as_str()).collect()
+    }
+
+    pub fn playing(&self) -> Option<&str> {
+        let (_, song) = self.queue.get(self.current?)?;
+        Some(&song.file)
+    }
+
+    // Wakes up idling connections, like MPD does after a change
+    pub fn changed(&mut self, subsystem: &'static str) {
+        if subsystem == "playlist" {
+            self.version += 1;
+        }
+        self.changes.push(subsystem);
+    }
+
+    pub fn enqueue(&mut self, song: Song) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.queue.push((id, song));
+        self.changed("playlist");
+        id
+    }
+
+    fn play(&mut self, pos: usize) {
+        self.current = Some(pos);
+        self.state = "play".to_string();
+        self.elapsed = 0.0;
+        self.changed("player");
+    }
+
+    fn stop(&mut self) {
+        self.state = "stop".to_string();
+        self.elapsed = 0.0;
+        self.changed("player");
+    }
+
+    fn option(&mut self, value: &str) -> Result<bool, String> {
+        self.changed("options");
+        match value {
+            "1" => Ok(true),
+            "0" => Ok(false),
+            _ => Err(format!("Boolean (0/1) expected: {}", value)),
+        }
+    }
+
+    fn status(&self) -> Vec<(String, String)> {
+        let flag = |b: bool| if b { "1" } else { "0" }.to_string();
+        let mut out = vec![
+            ("volume".to_string(), self.volume.to_string()),
+            ("repeat".to_string(), flag(self.repeat)),
+            ("random".to_string(), flag(self.random)),
+            ("single".to_string(), flag(self.single)),
+            ("consume".to_string(), flag(self.consume)),
+            ("playlist".to_string(), self.version.to_string()),
+            ("playlistlength".to_string(), self.queue.len().to_string()),
+            ("mixrampdb".to_string(), "0.000000".to_string()),
+            ("state".to_string(), self.state.clone()),
+        ];
+        if let Some(pos) = self.current.filter(|&p| p < self.queue.len()) {
+            out.push(("song".to_string(), pos.to_string()));
+            out.push(("songid".to_string(), self.queue[pos].0.to_string()));
+            if let Some((id, _)) = self.queue.get(pos + 1) {
+                out.push(("nextsong".to_string(), (pos + 1).to_string()));
+                out.push(("nextsongid".to_string(), id.to_string()));
+            }
+            if self.state != "stop" {
+                out.push(("elapsed".to_string(), format!("{:.3}", self.elapsed)));
+                out.push((
+                    "duration".to_string(),
+                    format!("{:.3}", self.queue[pos].1.duration),
+                ));
+            }
+        }
+        out
+    }
+
+    fn entry(&self, pos: usize, out: &mut Vec<(String, String)>) {
+        let (id, song) = &self.queue[pos];
+        song.pairs(out);
+        out.push(("Pos".to_string(), pos.to_string()));
+        out.push(("Id".to_string(), id.to_string()));
+    }
+
+    // Songs matching either a filter expression or `tag value` pairs
+    fn find(&self, args: &[String], exact: bool) -> Result<Vec<Song>, String> {
+        let filters = match args {
+            [expr] if expr.starts_with('(') => filter(expr)?,
+            _ if !args.is_empty() && args.len() % 2 == 0 => args
+                .chunks(2)
+                .map(|pair| (pair[0].clone(), exact, pair[1].clone(), false))
+                .collect(),
+            _ => return Err("incorrect arguments".to_string()),
+        };
+        Ok(self
+            .library
+            .iter()
+            .filter(|song| {
+                filters
+                    .iter()
+                    .all(|(tag, exact, value, not)| song.matches(tag, value, *exact) != *not)
+            })
+            .cloned()
+            .collect())
+    }
+
+    fn sticker(&mut self, args: &[String]) -> Result<Vec<(String, String)>, String> {
+        let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+        match args.as_slice() {
+            ["get", "song", file, name] => {
+                match self.stickers.get(&(file.to_string(), name.to_string())) {
+                    Some(value) => Ok(vec![("sticker".to_string(), format!("{}={}", name, value))]),
+                    None => Err("no such sticker".to_string()),
+                }
+            }
+            ["set", "song", file, name, value] => {
+                self.stickers
+                    .insert((file.to_string(), name.to_string()), value.to_string());
+                self.changed("sticker");
+                Ok(Vec::new())
+            }
+            ["delete", "song", file, name] => {
+                match self.stickers.remove(&(file.to_string(), name.to_string())) {
+                    Some(_) => {
+                        self.changed("sticker");
+                        Ok(Vec::new())
+                    }
+                    None => Err("no such sticker".to_string()),
+                }
+            }
+            ["find", "song", dir, name] => {
+                let mut found = self
+                    .stickers
+                    .iter()
+                    .filter(|((file, n), _)| n == name && file.starts_with(dir))
+                    .collect::<Vec<_>>();
+                found.sort();
+                let mut out = Vec::new();
+                for ((file, _), value) in found {
+                    out.push(("file".to_string(), file.clone()));
+                    out.push(("sticker".to_string(), format!("{}={}", name, value)));
+                }
+                Ok(out)
+            }
+            _ => Err("bad request".to_string()),
+        }
+    }
+
+    // What `cmd` answers with, or the message of the ACK
+    fn run(&mut self, cmd: &str, args: &[String]) -> Result<Vec<(String, String)>, String> {
+        let arg = |i: usize| args.get(i).map(String::as_str).unwrap_or_default();
+        let mut out = Vec::new();
+
+        match cmd {
+            "ping" | "binarylimit" | "noidle" => {}
+            "status" => out = self.status(),
+            "currentsong" => {
+                if let Some(pos) = self.current.filter(|&p| p < self.queue.len()) {
+                    self.entry(pos, &mut out);
+                }
+            }
+            "playlistinfo" => {
+                for pos in 0..self.queue.len() {
+                    self.entry(pos, &mut out);
+                }
+            }
+            "stats" => {
+                let mut artists = self
+                    .library
+                    .iter()
+                    .filter_map(|s| s.tag("Artist"))
+                    .collect::<Vec<_>>();
+                artists.sort_unstable();
+                artists.dedup();
+                let mut albums = self
+                    .library
+                    .iter()
+                    .filter_map(|s| s.tag("Album"))
+                    .collect::<Vec<_>>();
+                albums.sort_unstable();
+                albums.dedup();
+                let playtime = self.library.iter().map(|s| s.duration).sum::<f64>();
+                out = vec![
+                    ("artists".to_string(), artists.len().to_string()),
+                    ("albums".to_string(), albums.len().to_string()),
+                    ("songs".to_string(), self.library.len().to_string()),
+                    ("uptime".to_string(), "100".to_string()),
+                    ("playtime".to_string(), "0".to_string()),
+                    ("db_playtime".to_string(), (playtime as u64).to_string()),
+                    ("db_update".to_string(), "0".to_string()),
+                ];
+            }
+            "add" => {
+                let uri = arg(0);
+                let songs = self
+                    .library
+                    .iter()
+                    .filter(|s| {
+                        uri.is_empty()
+                            || s.file == uri
+                            || s.file
+                                .starts_with(&format!("{}/", uri.trim_end_matches('/')))
+                    })
+                    .cloned()
+                    .collect::<Vec<_>>();
+                if songs.is_empty() {
+                    return Err("No such directory".to_string());
+                }
+                for song in songs {
+                    self.enqueue(song);
+                }
+            }
+            "addid" => {
+                let song = self
+                    .library
+                    .iter()
+                    .find(|s| s.file == arg(0))
+                    .cloned()
+                    .ok_or_else(|| "No such song".to_string())?;
+                let id = self.enqueue(song);
+                out.push(("Id".to_string(), id.to_string()));
+            }
+            "clear" => {
+                self.queue.clear();
+                self.current = None;
+                self.changed("playlist");
+                self.stop();
+            }
+            "delete" => {
+                let pos = arg(0).parse::<usize>().map_err(|e| e.to_string())?;
+                if pos >= self.queue.len() {
+                    return Err("Bad song index".to_string());
+                }
+                self.queue.remove(pos);
+                match self.current {
+                    Some(c) if c == pos => {
+                        self.current = None;
+                        self.stop();
+                    }
+                    Some(c) if c > pos => self.current = Some(c - 1),
+                    _ => {}
+                }
+                self.changed("playlist");
+            }
+            "save" => {
+                let files = self.files().iter().map(|f| f.to_string()).collect();
+                self.playlists.insert(arg(0).to_string(), files);
+                self.changed("stored_playlist");
+            }
+            "play" => {
+                let pos = match args.first() {
+                    Some(pos) => pos.parse::<usize>().map_err(|e| e.to_string())?,
+                    None => self.current.unwrap_or(0),
+                };
+                if pos >= self.queue.len() {
+                    // MPD doesn't mind being told to play an empty queue
+                    if args.is_empty() {
+                        return Ok(out);
+                    }
+                    return Err("Bad song index".to_string());
+                }
+                if self.state == "pause" && args.is_empty() {
+                    self.state = "play".to_string();
+                    self.changed("player");
+                } else {
+                    self.play(pos);
+                }
+            }
+            "playid" => {
+                let id = arg(0).parse::<u32>().map_err(|e| e.to_string())?;
+                let pos = self
+                    .queue
+                    .iter()
+                    .position(|(i, _)| *i == id)
+                    .ok_or_else(|| "No such song".to_string())?;
+                self.play(pos);
+            }
+            "pause" => {
+                if self.state != "stop" {
+                    let pause = match args.first() {
+                        Some(_) => self.option(arg(0))?,
+                        None => self.state == "play",
+                    };
+                    self.state = if pause { "pause" } else { "play" }.to_string();
+                    self.changed("player");
+                }
+            }
+            "stop" => self.stop(),
+            "next" => match self.current {
+                Some(c) if c + 1 < self.queue.len() => self.play(c + 1),
+                _ => {
+                    self.current = None;
+                    self.stop();
+                }
+            },
+            "previous" | "prev" => {
+                if let Some(c) = self.current {
+                    self.play(c.saturating_sub(1));
+                }
+            }
+            "seekcur" => {
+                let time = arg(0);
+                let secs = time.parse::<f64>().map_err(|e| e.to_string())?;
+                self.elapsed = if time.starts_with('+') || time.starts_with('-') {
+                    (self.elapsed + secs).max(0.0)
+                } else {
+                    secs
+                };
+                self.changed("player");
+            }
+            "setvol" => {
+                self.volume = arg(0).parse::<u8>().map_err(|e| e.to_string())?.min(100);
+                self.changed("mixer");
+            }
+            "repeat" => self.repeat = self.option(arg(0))?,
+            "random" => self.random = self.option(arg(0))?,
+            "single" => self.single = self.option(arg(0))?,
+            "consume" => self.consume = self.option(arg(0))?,
+            "search" | "find" => {
+                for song in self.find(args, cmd == "find")? {
+                    song.pairs(&mut out);
+                }
+            }
+            "listallinfo" => {
+                let dir = arg(0);
+                for song in self.library.iter().filter(|s| s.file.starts_with(dir)) {
+                    song.pairs(&mut out);
+                }
+            }
+            "lsinfo" => {
+                let dir = arg(0).trim_matches('/');
+                let mut dirs = Vec::new();
+                for song in &self.library {
+                    let rest = match dir {
+                        "" => song.file.as_str(),
+                        _ => match song.file.strip_prefix(&format!("{}/", dir)) {
+                            Some(rest) => rest,
+                            None => continue,
+                        },
+                    };
+                    match rest.split_once('/') {
+                        Some((sub, _)) => {
+                            let sub = match dir {
+                                "" => sub.to_string(),
+                                _ => format!("{}/{}", dir, sub),
+                            };
+                            if !dirs.contains(&sub) {
+                                dirs.push(sub);
+                            }
+                        }
+                        None => song.pairs(&mut out),
+                    }
+                }
+                let dirs = dirs
+                    .into_iter()
+                    .map(|d| ("directory".to_string(), d))
+                    .collect::<Vec<_>>();
+                out = dirs.into_iter().chain(out).collect();
+            }
+            // the update is done right away, nothing on disk changes anyway
+            "update" | "rescan" => {
+                self.update_job += 1;
+                out.push(("updating_db".to_string(), self.update_job.to_string()));
+                self.changed("update");
+                self.changed("database");
+            }
+            "outputs" => {
+                for o in &self.outputs {
+                    out.push(("outputid".to_string(), o.id.to_string()));
+                    out.push(("outputname".to_string(), o.name.clone()));
+                    out.push(("plugin".to_string(), "null".to_string()));
+                    out.push((
+                        "outputenabled".to_string(),
+                        if o.enabled { "1" } else { "0" }.to_string(),
+                    ));
+                }
+            }
+            "enableoutput" | "disableoutput" | "toggleoutput" => {
+                let id = arg(0).parse::<u32>().map_err(|e| e.to_string())?;
+                let output = self
+                    .outputs
+                    .iter_mut()
+                    .find(|o| o.id == id)
+                    .ok_or_else(|| "No such audio output".to_string())?;
+                output.enabled = match cmd {
+                    "enableoutput" => true,
+                    "disableoutput" => false,
+                    _ => !output.enabled,
+                };
+                self.changed("output");
+            }
+            "sticker" => out = self.sticker(args)?,
+            _ => return Err(format!("unknown command \"{}\"", cmd)),
+        }
+        Ok(out)
+    }
+}
+
+// `((Title contains "foo") AND !(Artist == "bar"))` as tag, whether it's exact, value and
+// whether it's negated
+fn filter(expr: &str) -> Result<Vec<(String, bool, String, bool)>, String> {
+    let inner = expr
+        .strip_prefix('(')
+        .and_then(|e| e.strip_suffix(')'))
+        .ok_or_else(|| format!("Bad filter: {}", expr))?;
+
+    inner
+        .split(" AND ")
+        .map(|part| {
+            let (not, part) = match part.strip_prefix('!') {
+                Some(part) => (true, part),
+                None => (false, part),
+            };
+            let part = part
+                .strip_prefix('(')
+                .and_then(|p| p.strip_suffix(')'))
+                .ok_or_else(|| format!("Bad filter: {}", part))?;
+            let (tag, rest) = part
+                .split_once(' ')
+                .ok_or_else(|| format!("Bad filter: {}", part))?;
+            let (op, value) = rest
+                .split_once(' ')
+                .ok_or_else(|| format!("Bad filter: {}", part))?;
+            let exact = match op {
+                "==" => true,
+                "contains" => false,
+                _ => return Err(format!("Unknown filter operator: {}", op)),
+            };
+            Ok((
+                tag.to_string(),
+                exact,
+                value.trim_matches('"').to_string(),
+                not,
+            ))
+        })
+        .collect()
+}
+
+// Splits a command line into words, `"quoted \"words\""` are one
+fn split(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c == ' ' {
+            chars.next();
+            continue;
+        }
+        let mut word = String::new();
+        if c == '"' {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => word.extend(chars.next()),
+                    '"' => break,
+                    c => word.push(c),
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c == ' ' {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+        }
+        words.push(word);
+    }
+    words
+}
+
+// An MPD in the same process, with a library that only exists in memory. Speaks enough of the
+// protocol for everything rmpc sends, and wakes up `idle` whenever the state changes.
+pub struct Mpd {
+    pub addr: SocketAddrV4,
+    state: Arc<Mutex<State>>,
+    connections: Arc<Mutex<Vec<TcpStream>>>,
+}
+
+impl Mpd {
+    pub async fn start(library: Vec<Song>) -> Mpd {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = match listener.local_addr().unwrap() {
+            std::net::SocketAddr::V4(addr) => addr,
+            addr => panic!("Not an IPv4 address: {}", addr),
+        };
+        let state = Arc::new(Mutex::new(State::new(library)));
+
+        let connections = Arc::new(Mutex::new(Vec::new()));
+
+        let (shared, open) = (state.clone(), connections.clone());
+        task::spawn(async move {
+            let mut incoming = listener.incoming();
+            while let Some(Ok(stream)) = incoming.next().await {
+                let refused = {
+                    let mut state = shared.lock().unwrap();
+                    let refused = state.refuse > 0;
+                    state.refuse = state.refuse.saturating_sub(1);
+                    refused
+                };
+                if refused {
+                    let _ = stream.shutdown(Shutdown::Both);
+                    continue;
+                }
+                open.lock().unwrap().push(stream.clone());
+                task::spawn(serve(stream, shared.clone()));
+            }
+        });
+        Mpd {
+            addr,
+            state,
+            connections,
+        }
+    }
+
+    pub fn with<T>(&self, f: impl FnOnce(&mut State) -> T) -> T {
+        f(&mut self.state.lock().unwrap())
+    }
+
+    // Closes every connection, like MPD restarting. New ones are still accepted.
+    pub fn disconnect(&self) {
+        for stream in self.connections.lock().unwrap().drain(..) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    // Commands sent so far, like `playid "3"` is `playid 3`
+    pub fn commands(&self) -> Vec<String> {
+        self.with(|s| s.commands.clone())
+    }
+}
+
+async fn serve(stream: TcpStream, state: Arc<Mutex<State>>) {
+    let mut writer = &stream;
+    // changes from before the connection don't concern it
+    let mut seen = state.lock().unwrap().changes.len();
+
+    // read on the side, so idle can wait for `noidle` and changes at the same time
+    let (sender, lines) = channel::unbounded();
+    let reader = stream.clone();
+    task::spawn(async move {
+        let mut reader = BufReader::new(reader).lines();
+        while let Some(Ok(line)) = reader.next().await {
+            if sender.send(line).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    if writer.write_all(b"OK MPD 0.23.5\n").await.is_err() {
+        return;
+    }
+    while let Ok(line) = lines.recv().await {
+        let mut words = split(line.trim_end());
+        if words.is_empty() {
+            continue;
+        }
+        let cmd = words.remove(0);
+
+        let reply = if cmd == "idle" {
+            state.lock().unwrap().commands.push(cmd.clone());
+            match idle(&lines, &state, &mut seen).await {
+                Some(reply) => reply,
+                None => return,
+            }
+        } else {
+            let mut state = state.lock().unwrap();
+            state.commands.push(
+                std::iter::once(cmd.as_str())
+                    .chain(words.iter().map(String::as_str))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            );
+            match state.run(&cmd, &words) {
+                Ok(pairs) => {
+                    let mut reply = String::new();
+                    for (k, v) in pairs {
+                        reply.push_str(&format!("{}: {}\n", k, v));
+                    }
+                    reply + "OK\n"
+                }
+                Err(e) => format!("ACK [5@0] {{{}}} {}\n", cmd, e),
+            }
+        };
+        if writer.write_all(reply.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+// Waits for the next change the connection hasn't seen yet, one per `idle` like async_mpd
+// expects. `noidle` ends it early with nothing changed.
+async fn idle(lines: &Receiver<String>, state: &Mutex<State>, seen: &mut usize) -> Option<String> {
+    loop {
+        if let Some(&subsystem) = state.lock().unwrap().changes.get(*seen) {
+            *seen += 1;
+            return Some(format!("changed: {}\nOK\n", subsystem));
+        }
+        match future::timeout(POLL, lines.recv()).await {
+            Err(_) => {}
+            Ok(Err(_)) => return None,
+            Ok(Ok(line)) if line.trim() == "noidle" => return Some("OK\n".to_string()),
+            Ok(Ok(line)) => log::warn!("Command while idling: {}", line),
+        }
+    }
+}
@@ -0,0 +1,516 @@
+use crate::EventMessage;
+use async_mpd::{Status, Track};
+use async_std::{channel::Sender, task};
+use std::{collections::HashMap, convert::TryFrom, path::PathBuf};
+use zbus::{
+    dbus_interface,
+    zvariant::{ObjectPath, OwnedValue, Value},
+    Connection, ConnectionBuilder, SignalContext,
+};
+
+const NAME: &str = "org.mpris.MediaPlayer2.rmpc";
+const PATH: &str = "/org/mpris/MediaPlayer2";
+
+// What the player interface shows, copied out of MPD's status and the playing track
+#[derive(Debug, Default, Clone, PartialEq)]
+struct State {
+    state: String,
+    // microseconds, like everything in MPRIS
+    position: i64,
+    volume: Option<u8>,
+    random: bool,
+    repeat: bool,
+    single: bool,
+    metadata: Option<Metadata>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Metadata {
+    id: u32,
+    file: String,
+    length: i64,
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    album_artist: Option<String>,
+    genre: Option<String>,
+    track: Option<u32>,
+}
+
+// org.mpris.MediaPlayer2, rmpc can't raise its own terminal
+struct Root {
+    events: Sender<EventMessage>,
+}
+
+#[dbus_interface(name = "org.mpris.MediaPlayer2")]
+impl Root {
+    fn raise(&self) {}
+
+    fn quit(&self) {
+        send(&self.events, "quit");
+    }
+
+    #[dbus_interface(property)]
+    fn can_quit(&self) -> bool {
+        true
+    }
+
+    #[dbus_interface(property)]
+    fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn identity(&self) -> &str {
+        "rmpc"
+    }
+
+    #[dbus_interface(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    #[dbus_interface(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+// org.mpris.MediaPlayer2.Player, every method turns into a `:` command run by the app
+struct Player {
+    events: Sender<EventMessage>,
+    music_directory: Option<PathBuf>,
+    state: State,
+}
+
+#[dbus_interface(name = "org.mpris.MediaPlayer2.Player")]
+impl Player {
+    fn next(&self) {
+        send(&self.events, "next");
+    }
+
+    fn previous(&self) {
+        send(&self.events, "prev");
+    }
+
+    fn pause(&self) {
+        send(&self.events, "pause");
+    }
+
+    fn play_pause(&self) {
+        send(&self.events, "toggle");
+    }
+
+    fn stop(&self) {
+        send(&self.events, "stop");
+    }
+
+    fn play(&self) {
+        send(&self.events, "play");
+    }
+
+    fn seek(&self, offset: i64) {
+        let sign = if offset < 0 { "-" } else { "+" };
+        let secs = offset.unsigned_abs() as f64 / 1e6;
+        send(&self.events, &format!("seek {}{}", sign, secs));
+    }
+
+    fn set_position(&self, track_id: ObjectPath<'_>, position: i64) {
+        // ignored if the track changed in the meantime, as the spec says
+        let current = self.state.metadata.as_ref().map(|m| track_path(m.id));
+        if current.as_deref() == Some(track_id.as_str()) && position >= 0 {
+            send(&self.events, &format!("seek {}", position as f64 / 1e6));
+        }
+    }
+
+    fn open_uri(&self, _uri: &str) -> zbus::fdo::Result<()> {
+        Err(zbus::fdo::Error::NotSupported(
+            "Opening URIs is not supported".to_string(),
+        ))
+    }
+
+    #[dbus_interface(signal)]
+    async fn seeked(ctxt: &SignalContext<'_>, position: i64) -> zbus::Result<()>;
+
+    #[dbus_interface(property)]
+    fn playback_status(&self) -> &str {
+        match self.state.state.as_str() {
+            "play" => "Playing",
+            "pause" => "Paused",
+            _ => "Stopped",
+        }
+    }
+
+    #[dbus_interface(property)]
+    fn loop_status(&self) -> &str {
+        match (self.state.repeat, self.state.single) {
+            (true, true) => "Track",
+            (true, false) => "Playlist",
+            _ => "None",
+        }
+    }
+
+    #[dbus_interface(property)]
+    fn set_loop_status(&self, status: String) {
+        let (repeat, single) = match status.as_str() {
+            "Track" => ("on", "on"),
+            "Playlist" => ("on", "off"),
+            _ => ("off", "off"),
+        };
+        send(&self.events, &format!("set repeat {}", repeat));
+        send(&self.events, &format!("set single {}", single));
+    }
+
+    #[dbus_interface(property)]
+    fn shuffle(&self) -> bool {
+        self.state.random
+    }
+
+    #[dbus_interface(property)]
+    fn set_shuffle(&self, shuffle: bool) {
+        let value = if shuffle { "on" } else { "off" };
+        send(&self.events, &format!("set random {}", value));
+    }
+
+    #[dbus_interface(property)]
+    fn metadata(&self) -> HashMap<String, OwnedValue> {
+        let m = match &self.state.metadata {
+            Some(m) => m,
+            None => return HashMap::new(),
+        };
+
+        let mut map = HashMap::new();
+        let mut insert = |key: &str, value: Value<'_>| {
+            map.insert(key.to_string(), OwnedValue::from(value));
+        };
+        if let Ok(path) = ObjectPath::try_from(track_path(m.id)) {
+            insert("mpris:trackid", path.into());
+        }
+        insert("mpris:length", m.length.into());
+        if let Some(title) = &m.title {
+            insert("xesam:title", title.as_str().into());
+        }
+        if let Some(artist) = &m.artist {
+            insert("xesam:artist", vec![artist.as_str()].into());
+        }
+        if let Some(album) = &m.album {
+            insert("xesam:album", album.as_str().into());
+        }
+        if let Some(artist) = &m.album_artist {
+            insert("xesam:albumArtist", vec![artist.as_str()].into());
+        }
+        if let Some(genre) = &m.genre {
+            insert("xesam:genre", vec![genre.as_str()].into());
+        }
+        if let Some(track) = m.track {
+            insert("xesam:trackNumber", (track as i32).into());
+        }
+        if let Some(dir) = &self.music_directory {
+            let url = format!("file://{}", dir.join(&m.file).display());
+            insert("xesam:url", url.into());
+        }
+        map
+    }
+
+    #[dbus_interface(property)]
+    fn volume(&self) -> f64 {
+        self.state.volume.map_or(0., |v| v as f64 / 100.)
+    }
+
+    #[dbus_interface(property)]
+    fn set_volume(&self, volume: f64) {
+        let volume = (volume.clamp(0., 1.) * 100.).round();
+        send(&self.events, &format!("volume {}", volume));
+    }
+
+    // never signaled as it changes all the time, clients ask for it when they need it
+    #[dbus_interface(property)]
+    fn position(&self) -> i64 {
+        self.state.position
+    }
+
+    #[dbus_interface(property)]
+    fn rate(&self) -> f64 {
+        1.
+    }
+
+    #[dbus_interface(property)]
+    fn minimum_rate(&self) -> f64 {
+        1.
+    }
+
+    #[dbus_interface(property)]
+    fn maximum_rate(&self) -> f64 {
+        1.
+    }
+
+    #[dbus_interface(property)]
+    fn can_go_next(&self) -> bool {
+        true
+    }
+
+    #[dbus_interface(property)]
+    fn can_go_previous(&self) -> bool {
+        true
+    }
+
+    #[dbus_interface(property)]
+    fn can_play(&self) -> bool {
+        true
+    }
+
+    #[dbus_interface(property)]
+    fn can_pause(&self) -> bool {
+        true
+    }
+
+    #[dbus_interface(property)]
+    fn can_seek(&self) -> bool {
+        self.state.metadata.is_some()
+    }
+
+    #[dbus_interface(property)]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+// rmpc on the session bus, as org.mpris.MediaPlayer2.rmpc
+pub struct Mpris {
+    conn: Connection,
+}
+
+impl Mpris {
+    pub async fn new(
+        events: Sender<EventMessage>,
+        music_directory: Option<PathBuf>,
+    ) -> zbus::Result<Mpris> {
+        Mpris::serve(ConnectionBuilder::session()?, events, music_directory).await
+    }
+
+    async fn serve(
+        builder: ConnectionBuilder<'_>,
+        events: Sender<EventMessage>,
+        music_directory: Option<PathBuf>,
+    ) -> zbus::Result<Mpris> {
+        let root = Root {
+            events: events.clone(),
+        };
+        let player = Player {
+            events,
+            music_directory,
+            state: State::default(),
+        };
+        let conn = builder
+            .name(NAME)?
+            .serve_at(PATH, root)?
+            .serve_at(PATH, player)?
+            .build()
+            .await?;
+        Ok(Mpris { conn })
+    }
+
+    // Signals every property that changed since the last update
+    pub async fn update(&self, status: Option<&Status>, track: Option<&Track>) -> zbus::Result<()> {
+        let new = state(status, track);
+        let iface = self
+            .conn
+            .object_server()
+            .interface::<_, Player>(PATH)
+            .await?;
+        let mut player = iface.get_mut().await;
+        let old = std::mem::replace(&mut player.state, new.clone());
+        let ctxt = iface.signal_context();
+
+        if old.state != new.state {
+            player.playback_status_changed(ctxt).await?;
+        }
+        if old.metadata != new.metadata {
+            player.metadata_changed(ctxt).await?;
+            player.can_seek_changed(ctxt).await?;
+        }
+        if old.volume != new.volume {
+            player.volume_changed(ctxt).await?;
+        }
+        if old.random != new.random {
+            player.shuffle_changed(ctxt).await?;
+        }
+        if (old.repeat, old.single) != (new.repeat, new.single) {
+            player.loop_status_changed(ctxt).await?;
+        }
+        // a jump that normal playback doesn't explain, 2s leaves room for the polling interval
+        let expected = old.position..=old.position + 2_000_000;
+        if old.metadata == new.metadata && !expected.contains(&new.position) {
+            Player::seeked(ctxt, new.position).await?;
+        }
+        Ok(())
+    }
+}
+
+fn state(status: Option<&Status>, track: Option<&Track>) -> State {
+    let status = match status {
+        Some(s) => s,
+        None => return State::default(),
+    };
+
+    State {
+        state: status.state.clone(),
+        position: status.elapsed.map_or(0, |e| e.as_micros() as i64),
+        volume: status.volume,
+        random: status.random,
+        repeat: status.repeat,
+        single: status.single != "0",
+        metadata: track.map(|t| Metadata {
+            id: t.id.unwrap_or_default(),
+            file: t.file.clone(),
+            length: status.duration.unwrap_or(t.duration).as_micros() as i64,
+            title: t.title.clone(),
+            artist: t.artist.clone(),
+            album: t.album.clone(),
+            album_artist: t.album_artist.clone(),
+            genre: t.genre.clone(),
+            track: t.track,
+        }),
+    }
+}
+
+fn track_path(id: u32) -> String {
+    format!("/org/rmpc/track/{}", id)
+}
+
+// Hands `command` to the app without waiting, the app might be busy updating us
+fn send(events: &Sender<EventMessage>, command: &str) {
+    let events = events.clone();
+    let command = command.to_string();
+    task::spawn(async move {
+        if events.send(EventMessage::Remote(command)).await.is_err() {
+            log::warn!("Cannot run a command from MPRIS, rmpc is shutting down");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::channel;
+    use std::{
+        io::{BufRead, BufReader},
+        process::{Child, Command, Stdio},
+    };
+    use zbus::fdo::PropertiesProxy;
+
+    // A session bus of our own, so the tests neither need nor disturb a desktop
+    struct Bus(Child);
+
+    impl Bus {
+        fn start() -> Option<(Bus, String)> {
+            let mut child = Command::new("dbus-daemon")
+                .args(["--session", "--print-address", "--nofork"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .ok()?;
+            let mut address = String::new();
+            BufReader::new(child.stdout.take()?)
+                .read_line(&mut address)
+                .ok()?;
+            Some((Bus(child), address.trim().to_string()))
+        }
+    }
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    #[async_std::test]
+    async fn seeks_by_the_whole_range() {
+        let (s, r) = channel::unbounded();
+        let player = Player {
+            events: s,
+            music_directory: None,
+            state: State::default(),
+        };
+        for (offset, command) in [
+            (2_500_000, "seek +2.5"),
+            (-1_000_000, "seek -1"),
+            (i64::MIN, "seek -9223372036854.775"),
+        ] {
+            player.seek(offset);
+            match r.recv().await.unwrap() {
+                EventMessage::Remote(c) => assert_eq!(c, command),
+                _ => panic!("expected a remote command"),
+            }
+        }
+    }
+
+    // run with `cargo test --features mpris -- --ignored` where dbus-daemon is installed
+    #[test]
+    #[ignore = "needs dbus-daemon"]
+    fn player_on_private_bus() {
+        let (_bus, address) = Bus::start().expect("cannot start dbus-daemon");
+
+        task::block_on(async {
+            let (s, r) = channel::bounded(1);
+            let mpris = Mpris::serve(
+                ConnectionBuilder::address(address.as_str()).unwrap(),
+                s,
+                None,
+            )
+            .await
+            .unwrap();
+            let client = ConnectionBuilder::address(address.as_str())
+                .unwrap()
+                .build()
+                .await
+                .unwrap();
+
+            client
+                .call_method(
+                    Some(NAME),
+                    PATH,
+                    Some("org.mpris.MediaPlayer2.Player"),
+                    "PlayPause",
+                    &(),
+                )
+                .await
+                .unwrap();
+            match r.recv().await.unwrap() {
+                EventMessage::Remote(command) => assert_eq!(command, "toggle"),
+                _ => panic!("expected a remote command"),
+            }
+
+            let status = Status {
+                state: "play".to_string(),
+                volume: Some(40),
+                ..Status::default()
+            };
+            mpris.update(Some(&status), None).await.unwrap();
+
+            let properties = PropertiesProxy::builder(&client)
+                .destination(NAME)
+                .unwrap()
+                .path(PATH)
+                .unwrap()
+                .build()
+                .await
+                .unwrap();
+            let player =
+                zbus::names::InterfaceName::try_from("org.mpris.MediaPlayer2.Player").unwrap();
+            let playback = properties
+                .get(player.clone(), "PlaybackStatus")
+                .await
+                .unwrap();
+            assert_eq!(String::try_from(playback).unwrap(), "Playing");
+            let volume = properties.get(player, "Volume").await.unwrap();
+            assert_eq!(f64::try_from(volume).unwrap(), 0.4);
+        });
+    }
+}
@@ -0,0 +1,179 @@
+use crate::{art, config::Notifications, protocol::Connection};
+use anyhow::Result;
+use async_mpd::{Status, Track};
+use async_std::task;
+use std::{collections::HashMap, env, process::Command};
+use zbus::zvariant::Value;
+
+const DESTINATION: &str = "org.freedesktop.Notifications";
+const PATH: &str = "/org/freedesktop/Notifications";
+
+// notification servers scale the image down anyway
+const COVER_SIZE: u32 = 128;
+
+// Sends a desktop notification through org.freedesktop.Notifications whenever the song changes
+pub struct Notifier {
+    conn: zbus::Connection,
+    // MPD's id of the song we last notified about
+    song: Option<u32>,
+    // so a new notification replaces the last one instead of piling up
+    id: u32,
+}
+
+impl Notifier {
+    // `status` is what's playing now, it was there before us so it's not announced
+    pub async fn new(status: Option<&Status>) -> zbus::Result<Notifier> {
+        Ok(Notifier {
+            conn: zbus::Connection::session().await?,
+            song: status.and_then(|s| s.songid),
+            id: 0,
+        })
+    }
+
+    // The song to announce, if `status` moved on to one that's playing. Its cover is fetched
+    // by the loader, `send` posts the notification once it's back.
+    pub async fn update(
+        &mut self,
+        config: &Notifications,
+        status: Option<&Status>,
+        track: Option<&Track>,
+    ) -> Option<Track> {
+        let song = status.and_then(|s| s.songid);
+        if song == self.song {
+            return None;
+        }
+        self.song = song;
+
+        let track = match (status, track) {
+            (Some(s), Some(t)) if s.state == "play" => t,
+            _ => return None,
+        };
+        if !config.when_focused && focused().await {
+            return None;
+        }
+        Some(track.clone())
+    }
+
+    pub async fn send(
+        &mut self,
+        config: &Notifications,
+        track: &Track,
+        cover: Option<Value<'static>>,
+    ) -> Result<()> {
+        let mut hints = HashMap::new();
+        if let Some(cover) = cover {
+            hints.insert("image-data", cover);
+        }
+        let summary = format(&config.summary, track);
+        let body = escape(&format(&config.body, track));
+        let timeout = config.timeout.map_or(-1, |t| t as i32);
+
+        let reply = self
+            .conn
+            .call_method(
+                Some(DESTINATION),
+                PATH,
+                Some(DESTINATION),
+                "Notify",
+                &(
+                    "rmpc",
+                    self.id,
+                    "audio-x-generic",
+                    summary,
+                    body,
+                    Vec::<&str>::new(),
+                    hints,
+                    timeout,
+                ),
+            )
+            .await?;
+        self.id = reply.body()?;
+        Ok(())
+    }
+}
+
+// The album cover as raw pixels, laid out like the `image-data` hint wants them
+pub async fn cover(mpd: &mut Connection, track: &Track) -> Result<Option<Value<'static>>> {
+    let data = match art::fetch(mpd, &track.file).await? {
+        Some(data) => data,
+        None => return Ok(None),
+    };
+    let image = match image::load_from_memory(&data) {
+        Ok(image) => image.thumbnail(COVER_SIZE, COVER_SIZE).to_rgb8(),
+        Err(e) => {
+            log::warn!("Cannot decode cover for {}: {}", track.file, e);
+            return Ok(None);
+        }
+    };
+    let (width, height) = image.dimensions();
+    Ok(Some(Value::from((
+        width as i32,
+        height as i32,
+        // row stride
+        width as i32 * 3,
+        // has alpha
+        false,
+        // bits per sample
+        8,
+        // channels
+        3,
+        image.into_raw(),
+    ))))
+}
+
+// Fills in `{tag}`s in `template`, songs without a title go by their file name
+fn format(template: &str, track: &Track) -> String {
+    let file_name = track.file.rsplit('/').next().unwrap_or_default();
+    let tags = [
+        ("{title}", track.title.as_deref().unwrap_or(file_name)),
+        ("{artist}", track.artist.as_deref().unwrap_or_default()),
+        ("{album}", track.album.as_deref().unwrap_or_default()),
+        (
+            "{album_artist}",
+            track.album_artist.as_deref().unwrap_or_default(),
+        ),
+        ("{genre}", track.genre.as_deref().unwrap_or_default()),
+        ("{date}", track.date.as_deref().unwrap_or_default()),
+        ("{file}", &track.file),
+    ];
+    let number = track.track.map(|n| n.to_string()).unwrap_or_default();
+    tags.iter()
+        .fold(template.replace("{track}", &number), |s, (tag, value)| {
+            s.replace(tag, value)
+        })
+}
+
+// bodies can have markup, tags shouldn't turn into it
+fn escape(body: &str) -> String {
+    body.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+// Whether the terminal we run in is the active window. Only X11 terminals tell us
+// their window, through WINDOWID, anywhere else we can't know and always notify.
+async fn focused() -> bool {
+    let window = match env::var("WINDOWID")
+        .ok()
+        .and_then(|w| w.parse::<u64>().ok())
+    {
+        Some(w) => w,
+        None => return false,
+    };
+    let output = task::spawn_blocking(|| {
+        Command::new("xprop")
+            .args(["-root", "_NET_ACTIVE_WINDOW"])
+            .output()
+    })
+    .await;
+
+    // _NET_ACTIVE_WINDOW(WINDOW): window id # 0x3a00007
+    match output {
+        Ok(o) if o.status.success() => {
+            let active = String::from_utf8_lossy(&o.stdout);
+            let active = active.trim().rsplit(' ').next().unwrap_or_default();
+            u64::from_str_radix(active.trim_start_matches("0x"), 16).ok() == Some(window)
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,95 @@
+use crate::protocol::Connection;
+use async_mpd::Error;
+
+#[derive(Debug, Clone)]
+pub struct Output {
+    pub id: u32,
+    pub name: String,
+    pub plugin: String,
+    pub enabled: bool,
+}
+
+// MPD's audio outputs and which one is selected in the outputs view
+#[derive(Debug, Default)]
+pub struct Outputs {
+    outputs: Vec<Output>,
+    selected: usize,
+}
+
+impl Outputs {
+    // Keeps the selection, unless there are fewer outputs now
+    pub fn set(&mut self, outputs: Vec<Output>) {
+        self.outputs = outputs;
+        self.selected = self.selected.min(self.outputs.len().saturating_sub(1));
+    }
+
+    pub fn outputs(&self) -> &[Output] {
+        &self.outputs
+    }
+
+    pub fn selected(&self) -> Option<&Output> {
+        self.outputs.get(self.selected)
+    }
+
+    pub fn selected_index(&self) -> usize {
+        self.selected
+    }
+
+    pub fn next(&mut self) {
+        if !self.outputs.is_empty() {
+            self.selected = (self.selected + 1) % self.outputs.len();
+        }
+    }
+
+    pub fn previous(&mut self) {
+        if !self.outputs.is_empty() {
+            self.selected = self
+                .selected
+                .checked_sub(1)
+                .unwrap_or(self.outputs.len() - 1);
+        }
+    }
+
+    pub fn select(&mut self, index: usize) {
+        self.selected = index.min(self.outputs.len().saturating_sub(1));
+    }
+}
+
+pub async fn fetch(conn: &mut Connection) -> Result<Vec<Output>, Error> {
+    let mut outputs: Vec<Output> = Vec::new();
+
+    // every output starts with its id, the other keys belong to the last one
+    for (k, v) in conn.command("outputs", &[]).await? {
+        match (k.as_str(), outputs.last_mut()) {
+            ("outputid", _) => outputs.push(Output {
+                id: v.parse().unwrap_or_default(),
+                name: String::new(),
+                plugin: String::new(),
+                enabled: false,
+            }),
+            ("outputname", Some(o)) => o.name = v,
+            ("plugin", Some(o)) => o.plugin = v,
+            ("outputenabled", Some(o)) => o.enabled = v == "1",
+            _ => {}
+        }
+    }
+    Ok(outputs)
+}
+
+// `toggle`, `enable` or `disable` for output `id`
+pub async fn set(conn: &mut Connection, action: &str, id: u32) -> Result<(), Error> {
+    conn.command(&format!("{}output", action), &[&id.to_string()])
+        .await?;
+    Ok(())
+}
+
+// Enables `id` and disables every other output, enabling first so playback never goes silent
+pub async fn only(conn: &mut Connection, id: u32) -> Result<(), Error> {
+    set(conn, "enable", id).await?;
+    for output in fetch(conn).await? {
+        if output.id != id && output.enabled {
+            set(conn, "disable", output.id).await?;
+        }
+    }
+    Ok(())
+}
@@ -16,6 +16,12 @@ impl Songs {
         self.status = status;
     }
 
+    // The song MPD is currently playing, if it's in the queue
+    pub fn playing(&self) -> Option<&Track> {
+        let id = self.status.as_ref()?.songid?;
+        self.songs.iter().find(|s| s.id == Some(id))
+    }
+
     pub fn set_songs(&mut self, songs: &[Track]) {
         self.songs.clear();
         self.songs.extend_from_slice(songs);
@@ -0,0 +1,299 @@
+use async_mpd::{Error, MpdClient};
+use async_std::{
+    io::BufReader,
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    prelude::*,
+    task,
+};
+use std::time::Duration;
+
+// Commands that only read, sending one of these again can't do anything twice
+const READ_ONLY: &[&str] = &[
+    "binarylimit",
+    "count",
+    "currentsong",
+    "find",
+    "list",
+    "listallinfo",
+    "listplaylist",
+    "listplaylistinfo",
+    "listplaylists",
+    "lsinfo",
+    "outputs",
+    "ping",
+    "playlistid",
+    "playlistinfo",
+    "readcomments",
+    "search",
+    "stats",
+    "status",
+];
+
+// Largest chunk MPD will send for binary responses, the default (8 KiB) makes
+// fetching cover art painfully slow
+const BINARY_LIMIT: &str = "1048576";
+
+// Milliseconds between reconnection attempts, the last one is repeated until MPD is back
+const BACKOFF: &[u64] = &[250, 500, 1000, 2000, 5000];
+
+// Raw connection for the commands async_mpd doesn't expose (albumart, readpicture, ...).
+// Errors reuse `async_mpd::Error` so both connections can be handled the same way.
+pub struct Connection {
+    reader: BufReader<TcpStream>,
+    addr: SocketAddr,
+}
+
+impl Connection {
+    pub async fn new<A: ToSocketAddrs>(addr: A) -> Result<Connection, Error> {
+        let stream = TcpStream::connect(addr).await?;
+        let addr = stream.peer_addr()?;
+        let mut conn = Connection {
+            reader: BufReader::new(stream),
+            addr,
+        };
+        conn.handshake().await?;
+        Ok(conn)
+    }
+
+    pub async fn reconnect(&mut self) -> Result<(), Error> {
+        self.reader = BufReader::new(TcpStream::connect(self.addr).await?);
+        self.handshake().await
+    }
+
+    async fn handshake(&mut self) -> Result<(), Error> {
+        let version = self.read_line().await?;
+        if !version.starts_with("OK MPD") {
+            return Err(Error::ResponseError {
+                reply: version,
+                errmsg: "Expected MPD greeting".to_string(),
+            });
+        }
+
+        // older servers don't know about binarylimit, they just get small chunks
+        if let Err(e) = self.try_command("binarylimit", &[BINARY_LIMIT]).await {
+            log::info!("Server does not support binarylimit: {}", e);
+        }
+        Ok(())
+    }
+
+    // Runs `cmd` and returns every `key: value` pair of the response
+    pub async fn command(
+        &mut self,
+        cmd: &str,
+        args: &[&str],
+    ) -> Result<Vec<(String, String)>, Error> {
+        match self.try_command(cmd, args).await {
+            // MPD drops connections that are quiet for too long, so try again once. Changes
+            // might have gone through before the connection did, those aren't sent twice.
+            Err(e @ (Error::Disconnected | Error::IOError(_))) => {
+                self.reconnect().await?;
+                if repeatable(cmd, args) {
+                    self.try_command(cmd, args).await
+                } else {
+                    Err(e)
+                }
+            }
+            r => r,
+        }
+    }
+
+    // Runs a command with a binary response (albumart, readpicture), fetching all the chunks.
+    // Returns `None` if the server has no data for `uri`.
+    pub async fn binary(&mut self, cmd: &str, uri: &str) -> Result<Option<Vec<u8>>, Error> {
+        match self.try_binary(cmd, uri).await {
+            Err(Error::Disconnected) | Err(Error::IOError(_)) => {
+                self.reconnect().await?;
+                self.try_binary(cmd, uri).await
+            }
+            r => r,
+        }
+    }
+
+    async fn try_command(
+        &mut self,
+        cmd: &str,
+        args: &[&str],
+    ) -> Result<Vec<(String, String)>, Error> {
+        self.send(cmd, args).await?;
+
+        let mut pairs = Vec::new();
+        loop {
+            let line = self.read_line().await?;
+            if line == "OK" {
+                return Ok(pairs);
+            }
+            pairs.push(Connection::pair(line)?);
+        }
+    }
+
+    async fn try_binary(&mut self, cmd: &str, uri: &str) -> Result<Option<Vec<u8>>, Error> {
+        let mut data = Vec::new();
+
+        loop {
+            let offset = data.len().to_string();
+            self.send(cmd, &[uri, &offset]).await?;
+
+            let mut size = None;
+            let mut chunk = 0;
+            loop {
+                let line = self.read_line().await?;
+                if line == "OK" {
+                    break;
+                }
+
+                let (key, value) = Connection::pair(line)?;
+                match key.as_str() {
+                    "size" => size = value.parse::<usize>().ok(),
+                    "binary" => {
+                        chunk = value.parse().unwrap_or(0);
+                        let start = data.len();
+                        data.resize(start + chunk, 0);
+                        self.reader.read_exact(&mut data[start..]).await?;
+                        // binary data is followed by a newline
+                        self.read_line().await?;
+                    }
+                    _ => {}
+                }
+            }
+
+            match size {
+                // readpicture answers with an empty response when there is no picture
+                None => return Ok(None),
+                Some(size) if data.len() >= size || chunk == 0 => return Ok(Some(data)),
+                _ => {}
+            }
+        }
+    }
+
+    async fn send(&mut self, cmd: &str, args: &[&str]) -> Result<(), Error> {
+        let mut line = String::from(cmd);
+        for arg in args {
+            line.push_str(" \"");
+            for c in arg.chars() {
+                if c == '"' || c == '\\' {
+                    line.push('\\');
+                }
+                line.push(c);
+            }
+            line.push('"');
+        }
+        line.push('\n');
+
+        self.reader.get_mut().write_all(line.as_bytes()).await?;
+        Ok(())
+    }
+
+    async fn read_line(&mut self) -> Result<String, Error> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line).await? == 0 {
+            return Err(Error::Disconnected);
+        }
+
+        let line = line.trim_end_matches('\n').to_string();
+        if line.starts_with("ACK ") {
+            return Err(Error::CommandError { msg: line });
+        }
+        Ok(line)
+    }
+
+    fn pair(line: String) -> Result<(String, String), Error> {
+        match line.find(": ") {
+            Some(i) => Ok((line[..i].to_string(), line[i + 2..].to_string())),
+            None => Err(Error::ResponseError {
+                reply: line,
+                errmsg: "Expected key: value pair".to_string(),
+            }),
+        }
+    }
+}
+
+// Readable message for errors from either connection, MPD's own message for ACKs
+pub fn error_message(e: &anyhow::Error) -> String {
+    match e.downcast_ref::<Error>() {
+        // ACK [50@0] {play} No such song
+        Some(Error::CommandError { msg }) => msg
+            .split_once("} ")
+            .map_or(msg.as_str(), |(_, m)| m)
+            .to_string(),
+        _ => e.to_string(),
+    }
+}
+
+fn repeatable(cmd: &str, args: &[&str]) -> bool {
+    match cmd {
+        // `sticker set` and `sticker delete` change things
+        "sticker" => matches!(args.first(), Some(&"get") | Some(&"list") | Some(&"find")),
+        _ => READ_ONLY.contains(&cmd),
+    }
+}
+
+// Whether `e` means the connection is gone, rather than MPD refusing a command
+pub fn is_disconnect(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<Error>(),
+        Some(Error::Disconnected) | Some(Error::IOError(_))
+    ) || e.downcast_ref::<std::io::Error>().is_some()
+}
+
+// Reconnects `client`, waiting longer after every failed attempt, only returns once it worked
+pub async fn reconnect(client: &mut MpdClient) {
+    for attempt in 0.. {
+        match client.reconnect().await {
+            Ok(()) => return,
+            Err(e) => log::info!("Reconnecting failed (attempt {}): {}", attempt + 1, e),
+        }
+        backoff(attempt).await;
+    }
+}
+
+// Same for both connections commands go through
+pub async fn reconnect_commands(client: &mut MpdClient, conn: &mut Connection) {
+    for attempt in 0.. {
+        // async_mpd's reconnect doesn't notice a connection closed before the greeting, a
+        // status does
+        let result = async {
+            client.reconnect().await?;
+            client.status().await?;
+            conn.reconnect().await
+        }
+        .await;
+        match result {
+            Ok(()) => return,
+            Err(e) => log::info!(
+                "Reconnecting commands failed (attempt {}): {}",
+                attempt + 1,
+                e
+            ),
+        }
+        backoff(attempt).await;
+    }
+}
+
+async fn backoff(attempt: usize) {
+    let delay = BACKOFF[attempt.min(BACKOFF.len() - 1)];
+    task::sleep(Duration::from_millis(delay)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    #[async_std::test]
+    async fn only_repeats_commands_that_read() {
+        let mpd = mock::Mpd::start(vec![mock::song("a.flac", "A", "Artist", "Album")]).await;
+        let mut conn = Connection::new(mpd.addr).await.unwrap();
+
+        mpd.disconnect();
+        assert!(conn.command("status", &[]).await.is_ok());
+
+        mpd.disconnect();
+        let set = ["set", "song", "a.flac", "rating", "4"];
+        assert!(conn.command("sticker", &set).await.is_err());
+        // but the connection is back for the next one
+        assert!(conn.command("sticker", &set).await.is_ok());
+        let sets = mpd.commands();
+        let sets = sets.iter().filter(|c| c.starts_with("sticker set"));
+        assert_eq!(sets.count(), 1);
+    }
+}
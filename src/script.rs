@@ -0,0 +1,309 @@
+use crate::{
+    keys::{Action, KeyMap},
+    message::Messages,
+    play::Songs,
+    protocol::Connection,
+    state::StatefulList,
+};
+use anyhow::Result;
+use async_mpd::{Status, Track};
+use mlua::{
+    Function, IntoLua, IntoLuaMulti, Lua, MultiValue, RegistryKey, Table, ThreadStatus, Value,
+    Variadic,
+};
+use std::{cell::RefCell, fs, path::Path, rc::Rc};
+
+// The rest of `rmpc`, each one yields a request to `Scripts::run` and returns its answer
+const REQUESTS: &str = r#"
+local function request(...)
+    local ok, result = coroutine.yield(...)
+    if not ok then
+        error(result, 2)
+    end
+    return result
+end
+
+function rmpc.queue() return request("queue") end
+function rmpc.status() return request("status") end
+function rmpc.selected() return request("selected") end
+function rmpc.mpd(cmd, ...) return request("mpd", cmd, ...) end
+function rmpc.songs(cmd, ...) return request("songs", cmd, ...) end
+function rmpc.message(text, severity) return request("message", text, severity or "info") end
+"#;
+
+// A function a script bound to a key with `rmpc.bind`
+struct Binding {
+    key: String,
+    description: String,
+    function: RegistryKey,
+}
+
+// Actions written in Lua, from init.lua in the config dir
+pub struct Scripts {
+    lua: Lua,
+    bindings: Rc<RefCell<Vec<Binding>>>,
+}
+
+impl Scripts {
+    // Runs init.lua, which binds its actions to keys. Nothing else is there while it runs.
+    pub fn load(dir: &Path) -> Result<Scripts> {
+        let lua = Lua::new();
+        let bindings = Rc::new(RefCell::new(Vec::new()));
+
+        let rmpc = lua.create_table()?;
+        let registered = bindings.clone();
+        let bind = lua.create_function(
+            move |lua, (key, description, function): (String, String, Function)| {
+                registered.borrow_mut().push(Binding {
+                    key,
+                    description,
+                    function: lua.create_registry_value(function)?,
+                });
+                Ok(())
+            },
+        )?;
+        rmpc.set("bind", bind)?;
+        lua.globals().set("rmpc", rmpc)?;
+        lua.load(REQUESTS).set_name("requests").exec()?;
+
+        // `require` finds other scripts next to init.lua
+        let package: Table = lua.globals().get("package")?;
+        let path: String = package.get("path")?;
+        package.set("path", format!("{}/?.lua;{}", dir.display(), path))?;
+
+        let init = dir.join("init.lua");
+        if let Ok(code) = fs::read_to_string(&init) {
+            lua.load(&code).set_name(init.to_string_lossy()).exec()?;
+        }
+        drop(package);
+        Ok(Scripts { lua, bindings })
+    }
+
+    // Script keys are bound last, so they win over everything else
+    pub fn bind(&self, keymap: &mut KeyMap) -> Result<()> {
+        for (i, binding) in self.bindings.borrow().iter().enumerate() {
+            keymap.bind_described(&binding.key, Action::Script(i), binding.description.clone())?;
+        }
+        Ok(())
+    }
+
+    // Calls the action bound as `index`. Everything but `rmpc.bind` only works while it runs.
+    // The action runs as a coroutine that yields what it asks for, so MPD commands are awaited
+    // here instead of blocking inside Lua.
+    pub async fn run(
+        &self,
+        index: usize,
+        conn: &mut Connection,
+        list: &StatefulList<Songs>,
+        messages: &mut Messages,
+    ) -> Result<()> {
+        let function: Function = {
+            let bindings = self.bindings.borrow();
+            self.lua.registry_value(&bindings[index].function)?
+        };
+        let thread = self.lua.create_thread(function)?;
+
+        let mut reply = MultiValue::new();
+        loop {
+            let request: Variadic<String> = thread.resume(reply)?;
+            if thread.status() != ThreadStatus::Resumable {
+                return Ok(());
+            }
+            // errors are raised in the script, it can catch them with pcall
+            reply = match self.answer(&request, conn, list, messages).await {
+                Ok(value) => (true, value).into_lua_multi(&self.lua)?,
+                Err(e) => (false, e.to_string()).into_lua_multi(&self.lua)?,
+            };
+        }
+    }
+
+    async fn answer(
+        &self,
+        request: &[String],
+        conn: &mut Connection,
+        list: &StatefulList<Songs>,
+        messages: &mut Messages,
+    ) -> mlua::Result<Value<'_>> {
+        let lua = &self.lua;
+        match request {
+            [name] if name == "queue" => {
+                let songs = list
+                    .iter()
+                    .map(|t| song(lua, t))
+                    .collect::<mlua::Result<Vec<_>>>()?;
+                lua.create_sequence_from(songs)?.into_lua(lua)
+            }
+            [name] if name == "status" => list
+                .status()
+                .map(|s| status(lua, s))
+                .transpose()?
+                .into_lua(lua),
+            [name] if name == "selected" => list
+                .selected()
+                .map(|t| song(lua, t))
+                .transpose()?
+                .into_lua(lua),
+            // raw `{key, value}` pairs of the response
+            [name, cmd, args @ ..] if name == "mpd" => {
+                let pairs = mpd(conn, cmd, args)
+                    .await?
+                    .into_iter()
+                    .map(|(k, v)| lua.create_sequence_from([k, v]))
+                    .collect::<mlua::Result<Vec<_>>>()?;
+                lua.create_sequence_from(pairs)?.into_lua(lua)
+            }
+            // the response split into songs, tags are lowercase and their values strings
+            [name, cmd, args @ ..] if name == "songs" => {
+                let mut songs = Vec::new();
+                for (k, v) in mpd(conn, cmd, args).await? {
+                    if k == "file" {
+                        songs.push(lua.create_table()?);
+                    }
+                    if let Some(song) = songs.last() {
+                        song.set(k.to_lowercase(), v)?;
+                    }
+                }
+                lua.create_sequence_from(songs)?.into_lua(lua)
+            }
+            [name, text, severity] if name == "message" => {
+                let text = text.clone();
+                match severity.as_str() {
+                    "success" => messages.success(text),
+                    "warning" => messages.warn(text),
+                    "error" => messages.error(text),
+                    _ => messages.info(text),
+                }
+                Ok(Value::Nil)
+            }
+            _ => Err(mlua::Error::RuntimeError(format!(
+                "Cannot do {}",
+                request.join(" ")
+            ))),
+        }
+    }
+}
+
+async fn mpd(
+    conn: &mut Connection,
+    cmd: &str,
+    args: &[String],
+) -> mlua::Result<Vec<(String, String)>> {
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    conn.command(cmd, &args)
+        .await
+        .map_err(mlua::Error::external)
+}
+
+// Tags are named like MPD names them, in lowercase
+fn song<'lua>(lua: &'lua Lua, track: &Track) -> mlua::Result<Table<'lua>> {
+    let song = lua.create_table()?;
+    song.set("file", track.file.as_str())?;
+    song.set("title", track.title.as_deref())?;
+    song.set("artist", track.artist.as_deref())?;
+    song.set("album", track.album.as_deref())?;
+    song.set("albumartist", track.album_artist.as_deref())?;
+    song.set("genre", track.genre.as_deref())?;
+    song.set("date", track.date.as_deref())?;
+    song.set("track", track.track)?;
+    song.set("id", track.id)?;
+    song.set("duration", track.duration.as_secs_f64())?;
+    Ok(song)
+}
+
+fn status<'lua>(lua: &'lua Lua, status: &Status) -> mlua::Result<Table<'lua>> {
+    let table = lua.create_table()?;
+    table.set("state", status.state.as_str())?;
+    table.set("volume", status.volume)?;
+    table.set("repeat", status.repeat)?;
+    table.set("random", status.random)?;
+    table.set("single", status.single != "0")?;
+    table.set("consume", status.consume)?;
+    table.set("song", status.song)?;
+    table.set("songid", status.songid)?;
+    table.set("elapsed", status.elapsed.map(|e| e.as_secs_f64()))?;
+    table.set("duration", status.duration.map(|d| d.as_secs_f64()))?;
+    table.set("playlistlength", status.playlistlength)?;
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        keys::Overrides,
+        mock::{self, Mpd},
+        Mode,
+    };
+    use crossterm::event::KeyCode;
+
+    const INIT: &str = r#"
+rmpc.bind("x", "Turn it up", function()
+    rmpc.mpd("setvol", 70)
+    local songs = rmpc.songs("playlistinfo")
+    local selected = rmpc.selected()
+    rmpc.message(#songs .. " songs, " .. selected.title .. " " .. rmpc.status().state, "success")
+    -- MPD errors can be caught
+    local ok = pcall(rmpc.mpd, "bogus")
+    rmpc.message(ok and "not caught" or "caught")
+end)
+"#;
+
+    #[async_std::test]
+    async fn binds_and_runs_actions() {
+        let dir = std::env::temp_dir().join(format!("rmpc-lua-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("init.lua"), INIT).unwrap();
+        let scripts = Scripts::load(&dir).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut keymap = KeyMap::new(&Overrides::default(), &[]).unwrap();
+        scripts.bind(&mut keymap).unwrap();
+        assert_eq!(
+            keymap.get(Mode::Browsing, KeyCode::Char('x')),
+            Some(Action::Script(0))
+        );
+        assert_eq!(keymap.description(Action::Script(0)), "Turn it up");
+
+        let mpd = Mpd::start(vec![
+            mock::song("a/1.flac", "One", "Alpha", "First"),
+            mock::song("a/2.flac", "Two", "Alpha", "First"),
+        ])
+        .await;
+        mpd.with(|s| {
+            for song in s.library.clone() {
+                s.enqueue(song);
+            }
+        });
+        let mut conn = Connection::new(mpd.addr).await.unwrap();
+        let mut list = StatefulList::<Songs>::default();
+        list.set_songs(&[Track {
+            file: "a/1.flac".to_string(),
+            title: Some("One".to_string()),
+            ..Track::default()
+        }]);
+        list.set_status(Some(Status {
+            state: "pause".to_string(),
+            ..Status::default()
+        }));
+        list.next();
+        let mut messages = Messages::default();
+
+        scripts
+            .run(0, &mut conn, &list, &mut messages)
+            .await
+            .unwrap();
+        assert_eq!(mpd.with(|s| s.volume), 70);
+        assert_eq!(messages.current().unwrap().text, "2 songs, One pause");
+        messages.dismiss();
+        assert_eq!(messages.current().unwrap().text, "caught");
+    }
+
+    #[test]
+    fn fails_with_a_broken_init() {
+        let dir = std::env::temp_dir().join(format!("rmpc-lua-broken-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("init.lua"), "rmpc.bind(").unwrap();
+        assert!(Scripts::load(&dir).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,462 @@
+use crate::config;
+use async_mpd::{Status, Track};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    slice,
+    sync::mpsc,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// listens that couldn't be sent yet, one per line
+const QUEUE_FILE: &str = "scrobbles.jsonl";
+
+// the most listens ListenBrainz takes in one request
+const BATCH: usize = 100;
+
+// songs are scrobbled once half of them or this much was played
+const SCROBBLE_AFTER: Duration = Duration::from_secs(240);
+
+// anything shorter is never scrobbled
+const MIN_LENGTH: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Listen {
+    // when the song started, left out for `playing_now`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    listened_at: Option<i64>,
+    track_metadata: Metadata,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Metadata {
+    artist_name: String,
+    track_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    release_name: Option<String>,
+    additional_info: AdditionalInfo,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct AdditionalInfo {
+    duration_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tracknumber: Option<u32>,
+    submission_client: String,
+    submission_client_version: String,
+}
+
+#[derive(Debug, PartialEq)]
+enum Job {
+    PlayingNow(Listen),
+    Listen(Listen),
+}
+
+// Submits listens in the background, keeping the ones that can't be sent on disk
+pub struct Scrobbler {
+    jobs: mpsc::Sender<Job>,
+    tracker: Tracker,
+}
+
+impl Scrobbler {
+    // Starts by sending whatever was left over from last time
+    pub fn new(config: &config::Scrobble, data_dir: PathBuf) -> Scrobbler {
+        let client = Client::new(config, data_dir.join(QUEUE_FILE));
+        let (jobs, r) = mpsc::channel();
+        thread::spawn(move || {
+            client.flush();
+            for job in r {
+                client.run(job);
+            }
+        });
+        Scrobbler {
+            jobs,
+            tracker: Tracker::default(),
+        }
+    }
+
+    // Called with every status we get, while playing that's every tick
+    pub fn update(&mut self, status: Option<&Status>, track: Option<&Track>) {
+        if let Some(job) = self.tracker.update(status, track, now()) {
+            // only fails if the thread panicked, which is logged already
+            let _ = self.jobs.send(job);
+        }
+    }
+}
+
+// The playing song and how much of it was actually listened to
+#[derive(Debug)]
+struct Playing {
+    id: u32,
+    listen: Listen,
+    duration: Duration,
+    elapsed: Duration,
+    played: Duration,
+    scrobbled: bool,
+}
+
+// Follows statuses to tell when a song starts and when it was played long enough to scrobble
+#[derive(Debug, Default)]
+struct Tracker {
+    current: Option<Playing>,
+}
+
+impl Tracker {
+    fn update(&mut self, status: Option<&Status>, track: Option<&Track>, now: i64) -> Option<Job> {
+        let (status, track) = match (status, track) {
+            (Some(s), Some(t)) if s.state != "stop" => (s, t),
+            _ => {
+                self.current = None;
+                return None;
+            }
+        };
+        let id = status.songid?;
+        let elapsed = status.elapsed.unwrap_or_default();
+
+        match &mut self.current {
+            Some(current) if current.id == id => {
+                // seeking ahead isn't listening, and MPD only moves while playing
+                let step = elapsed.checked_sub(current.elapsed).unwrap_or_default();
+                if status.state == "play" && step <= Duration::from_secs(5) {
+                    current.played += step;
+                }
+                current.elapsed = elapsed;
+
+                let needed = SCROBBLE_AFTER.min(current.duration / 2);
+                if !current.scrobbled && current.duration >= MIN_LENGTH && current.played >= needed
+                {
+                    current.scrobbled = true;
+                    return Some(Job::Listen(current.listen.clone()));
+                }
+                None
+            }
+            _ => {
+                self.current = None;
+                let listen = listen(track, now - elapsed.as_secs() as i64)?;
+                let mut playing_now = listen.clone();
+                playing_now.listened_at = None;
+                self.current = Some(Playing {
+                    id,
+                    listen,
+                    duration: status.duration.unwrap_or(track.duration),
+                    elapsed,
+                    played: Duration::default(),
+                    scrobbled: false,
+                });
+                Some(Job::PlayingNow(playing_now))
+            }
+        }
+    }
+}
+
+// ListenBrainz wants at least an artist and a title
+fn listen(track: &Track, listened_at: i64) -> Option<Listen> {
+    Some(Listen {
+        listened_at: Some(listened_at),
+        track_metadata: Metadata {
+            artist_name: track.artist.clone()?,
+            track_name: track.title.clone()?,
+            release_name: track.album.clone(),
+            additional_info: AdditionalInfo {
+                duration_ms: track.duration.as_millis() as u64,
+                tracknumber: track.track,
+                submission_client: "rmpc".to_string(),
+                submission_client_version: env!("CARGO_PKG_VERSION").to_string(),
+            },
+        },
+    })
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+#[derive(Serialize)]
+struct Submission<'a> {
+    listen_type: &'a str,
+    payload: &'a [Listen],
+}
+
+// Talks to the server, blocking, so it lives on its own thread
+struct Client {
+    url: String,
+    token: String,
+    queue: PathBuf,
+}
+
+impl Client {
+    fn new(config: &config::Scrobble, queue: PathBuf) -> Client {
+        Client {
+            url: format!("{}/1/submit-listens", config.url.trim_end_matches('/')),
+            token: config.token.clone(),
+            queue,
+        }
+    }
+
+    fn run(&self, job: Job) {
+        match job {
+            // only means something right now, so it's never queued
+            Job::PlayingNow(listen) => {
+                if let Err(e) = self.send("playing_now", &[listen]) {
+                    log::warn!("Cannot send the playing song: {}", e);
+                }
+            }
+            Job::Listen(listen) => match self.send("single", slice::from_ref(&listen)) {
+                // a good time to send what piled up while we were offline
+                Ok(()) => self.flush(),
+                Err(e) if retry(&e) => {
+                    log::warn!("Cannot scrobble, trying again later: {}", e);
+                    self.enqueue(&[listen]);
+                }
+                Err(e) => log::warn!("Scrobble was rejected: {}", e),
+            },
+        }
+    }
+
+    fn send(&self, listen_type: &str, payload: &[Listen]) -> Result<(), Box<ureq::Error>> {
+        let body = serde_json::to_string(&Submission {
+            listen_type,
+            payload,
+        })
+        .expect("listens are always valid json");
+        ureq::post(&self.url)
+            .set("Authorization", &format!("Token {}", self.token))
+            .set("Content-Type", "application/json")
+            .timeout(Duration::from_secs(10))
+            .send_string(&body)?;
+        Ok(())
+    }
+
+    fn enqueue(&self, listens: &[Listen]) {
+        if listens.is_empty() {
+            return;
+        }
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.queue)
+            .and_then(|mut file| {
+                listens.iter().try_for_each(|l| {
+                    let line = serde_json::to_string(l).expect("listens are always valid json");
+                    writeln!(file, "{}", line)
+                })
+            });
+        if let Err(e) = result {
+            log::error!("Cannot save scrobbles to {}: {}", self.queue.display(), e);
+        }
+    }
+
+    // Sends every queued listen, keeping the ones that still can't be sent
+    fn flush(&self) {
+        let queued = match fs::read_to_string(&self.queue) {
+            Ok(queued) => queued,
+            Err(_) => return,
+        };
+        let listens = queued
+            .lines()
+            .filter_map(|l| serde_json::from_str(l).ok())
+            .collect::<Vec<Listen>>();
+
+        let mut left = Vec::new();
+        for batch in listens.chunks(BATCH) {
+            if !left.is_empty() {
+                left.extend_from_slice(batch);
+                continue;
+            }
+            match self.send("import", batch) {
+                Ok(()) => log::info!("Sent {} queued scrobbles", batch.len()),
+                Err(e) if retry(&e) => {
+                    log::warn!("Cannot send queued scrobbles: {}", e);
+                    left.extend_from_slice(batch);
+                }
+                Err(e) => log::warn!("Dropped {} queued scrobbles: {}", batch.len(), e),
+            }
+        }
+
+        if let Err(e) = fs::remove_file(&self.queue) {
+            log::error!("Cannot clear {}: {}", self.queue.display(), e);
+            return;
+        }
+        self.enqueue(&left);
+    }
+}
+
+// the server being down or busy is worth another try, anything else it won't take anyway
+fn retry(e: &ureq::Error) -> bool {
+    match e {
+        ureq::Error::Transport(_) => true,
+        ureq::Error::Status(code, _) => *code == 429 || *code >= 500,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader, Read},
+        net::TcpListener,
+    };
+
+    fn track() -> Track {
+        Track {
+            file: "a/b.flac".to_string(),
+            artist: Some("Artist".to_string()),
+            title: Some("Title".to_string()),
+            album: Some("Album".to_string()),
+            duration: Duration::from_secs(300),
+            ..Track::default()
+        }
+    }
+
+    fn status(state: &str, id: u32, elapsed: u64) -> Status {
+        Status {
+            state: state.to_string(),
+            songid: Some(id),
+            elapsed: Some(Duration::from_secs(elapsed)),
+            duration: Some(Duration::from_secs(300)),
+            ..Status::default()
+        }
+    }
+
+    // Answers every request with `code` and hands over the bodies it got
+    fn server(code: u16) -> (String, mpsc::Receiver<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (s, r) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let (mut length, mut auth) = (0, String::new());
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim();
+                    if line.is_empty() {
+                        break;
+                    }
+                    let (name, value) = line.split_once(": ").unwrap_or((line, ""));
+                    match name.to_lowercase().as_str() {
+                        "content-length" => length = value.parse().unwrap(),
+                        "authorization" => auth = value.to_string(),
+                        _ => {}
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                s.send((auth, String::from_utf8(body).unwrap())).unwrap();
+                write!(
+                    stream,
+                    "HTTP/1.1 {} X\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{{}}",
+                    code
+                )
+                .unwrap();
+            }
+        });
+        (url, r)
+    }
+
+    fn client(url: &str, name: &str) -> Client {
+        let queue = std::env::temp_dir().join(format!("rmpc-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&queue);
+        let config = config::Scrobble {
+            url: url.to_string(),
+            token: "secret".to_string(),
+        };
+        Client::new(&config, queue)
+    }
+
+    fn queued(client: &Client) -> usize {
+        fs::read_to_string(&client.queue).map_or(0, |q| q.lines().count())
+    }
+
+    #[test]
+    fn scrobbles_after_half_or_four_minutes() {
+        let mut tracker = Tracker::default();
+        let track = track();
+        let job = tracker.update(Some(&status("play", 1, 0)), Some(&track), 1000);
+        assert!(matches!(job, Some(Job::PlayingNow(l)) if l.listened_at.is_none()));
+
+        // seeking doesn't count as listening
+        assert_eq!(
+            tracker.update(Some(&status("play", 1, 200)), Some(&track), 1001),
+            None
+        );
+        for elapsed in (202..=348).step_by(2) {
+            assert_eq!(
+                tracker.update(Some(&status("play", 1, elapsed)), Some(&track), 0),
+                None
+            );
+        }
+        match tracker.update(Some(&status("play", 1, 350)), Some(&track), 0) {
+            Some(Job::Listen(l)) => assert_eq!(l.listened_at, Some(1000)),
+            job => panic!("expected a listen, got {:?}", job),
+        }
+        // only once
+        assert_eq!(
+            tracker.update(Some(&status("play", 1, 352)), Some(&track), 0),
+            None
+        );
+    }
+
+    #[test]
+    fn submits_to_server() {
+        let (url, requests) = server(200);
+        let client = client(&url, "submit");
+        let listen = listen(&track(), 1000).unwrap();
+        client.run(Job::Listen(listen));
+
+        let (auth, body) = requests.recv().unwrap();
+        assert_eq!(auth, "Token secret");
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["listen_type"], "single");
+        assert_eq!(body["payload"][0]["listened_at"], 1000);
+        assert_eq!(body["payload"][0]["track_metadata"]["track_name"], "Title");
+        assert_eq!(queued(&client), 0);
+    }
+
+    #[test]
+    fn queues_while_offline() {
+        // nothing listens here once the listener is dropped
+        let url = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let offline = client(&url, "offline");
+        offline.run(Job::Listen(listen(&track(), 1000).unwrap()));
+        offline.run(Job::Listen(listen(&track(), 2000).unwrap()));
+        offline.run(Job::PlayingNow(listen(&track(), 3000).unwrap()));
+        assert_eq!(queued(&offline), 2);
+
+        let (url, requests) = server(200);
+        let online = Client {
+            url: format!("{}/1/submit-listens", url),
+            ..offline
+        };
+        online.flush();
+        let (_, body) = requests.recv().unwrap();
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["listen_type"], "import");
+        assert_eq!(body["payload"].as_array().unwrap().len(), 2);
+        assert_eq!(queued(&online), 0);
+    }
+
+    #[test]
+    fn keeps_queue_on_server_errors() {
+        let (url, requests) = server(503);
+        let client = client(&url, "errors");
+        client.run(Job::Listen(listen(&track(), 1000).unwrap()));
+        requests.recv().unwrap();
+        assert_eq!(queued(&client), 1);
+
+        client.flush();
+        requests.recv().unwrap();
+        assert_eq!(queued(&client), 1);
+        fs::remove_file(&client.queue).unwrap();
+    }
+}
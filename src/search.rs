@@ -1,6 +1,6 @@
-use crate::play::Songs;
-use anyhow::Result;
-use async_mpd::{Filter, FilterExpr, MpdClient, Tag, Track};
+use crate::{play::Songs, stickers::Ratings};
+use async_mpd::{Filter, FilterExpr, Tag, Track};
+use std::ops::RangeInclusive;
 
 #[derive(Debug)]
 pub struct Search {
@@ -16,24 +16,35 @@ impl Search {
         }
     }
 
-    pub async fn search(&mut self, client: &mut MpdClient) -> Result<()> {
-        if !self.current.is_empty() {
-            // get all songs with provided title, can be expanded to more stuff I am sure
-            let filter = Filter::new().and(FilterExpr::Contains(Tag::Title, self.current.clone()));
+    // What to ask MPD for, nothing if the query is empty
+    pub fn filter(&self) -> Option<Filter> {
+        let (title, rating) = parse(&self.current);
 
-            // set songs to result of search
-            self.results.set_songs(&client.search(&filter).await?);
+        // get all songs with provided title, can be expanded to more stuff I am sure.
+        // Only a rating matches every song with a title, the ratings narrow it down.
+        if !title.is_empty() || rating.is_some() {
+            Some(Filter::new().and(FilterExpr::Contains(Tag::Title, title)))
         } else {
-            self.results.set_songs(&[]);
+            None
         }
+    }
 
-        Ok(())
+    // Keeps the songs MPD found that have the rating asked for
+    pub fn found(&mut self, mut songs: Vec<Track>, ratings: &Ratings) {
+        if let (_, Some(rating)) = parse(&self.current) {
+            songs.retain(|s| rating.contains(&ratings.get(&s.file).unwrap_or(0)));
+        }
+        self.results.set_songs(&songs);
     }
 
     pub fn results(&self) -> &[Track] {
         &self.results
     }
 
+    pub fn query(&self) -> &str {
+        &self.current
+    }
+
     pub fn push(&mut self, c: char) {
         self.current.push(c);
     }
@@ -59,6 +70,32 @@ impl Search {
     }
 }
 
+// Splits `rating>=4`, `rating<=2` or `rating=3` off the rest of the query, unrated songs count as 0
+fn parse(query: &str) -> (String, Option<RangeInclusive<u8>>) {
+    let mut rating = None;
+    let mut title = Vec::new();
+
+    for word in query.split(' ') {
+        let range = word.strip_prefix("rating").and_then(|r| {
+            let (range, n): (fn(u8) -> RangeInclusive<u8>, _) =
+                if let Some(n) = r.strip_prefix(">=") {
+                    (|n| n..=5, n)
+                } else if let Some(n) = r.strip_prefix("<=") {
+                    (|n| 0..=n, n)
+                } else {
+                    (|n| n..=n, r.strip_prefix('=')?)
+                };
+            n.parse().ok().map(range)
+        });
+
+        match range {
+            Some(r) => rating = Some(r),
+            None => title.push(word),
+        }
+    }
+    (title.join(" ").trim().to_string(), rating)
+}
+
 impl Default for Search {
     fn default() -> Self {
         Search::new()
@@ -0,0 +1,199 @@
+use crate::{
+    config::SmartPlaylist,
+    protocol::Connection,
+    stickers::{self, Ratings},
+};
+use anyhow::Result;
+use async_mpd::{Filter, FilterExpr, MpdClient, Status, Tag, Track};
+use std::{
+    collections::HashSet,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Songs matching `playlist`'s rules in the order they should be queued, skipping `exclude`
+pub async fn evaluate(
+    client: &mut MpdClient,
+    conn: &mut Connection,
+    ratings: &Ratings,
+    playlist: &SmartPlaylist,
+    exclude: &HashSet<&str>,
+) -> Result<Vec<Track>> {
+    let tags = [
+        (Tag::Artist, &playlist.artist),
+        (Tag::AlbumArtist, &playlist.album_artist),
+        (Tag::Album, &playlist.album),
+        (Tag::Genre, &playlist.genre),
+        (Tag::Title, &playlist.title),
+    ];
+    let mut filter = Filter::new();
+    for (tag, value) in tags.iter() {
+        if let Some(value) = value {
+            filter = filter.and(FilterExpr::Equals(*tag, value.clone()));
+        }
+    }
+    // MPD wants at least one expression, every song has a file name
+    if filter.to_query().is_none() {
+        filter = filter.and(FilterExpr::Contains(Tag::Any, String::new()));
+    }
+
+    let mut songs = client.search(&filter).await?;
+    songs.retain(|s| !exclude.contains(s.file.as_str()));
+
+    if playlist.min_rating.is_some() || playlist.max_rating.is_some() {
+        let range = playlist.min_rating.unwrap_or(0)..=playlist.max_rating.unwrap_or(5);
+        songs.retain(|s| range.contains(&ratings.get(&s.file).unwrap_or(0)));
+    }
+
+    if let Some(days) = playlist.not_played_for {
+        let since = chrono::Utc::now().timestamp() - days as i64 * 86400;
+        let played = stickers::last_played(conn).await?;
+        songs.retain(|s| played.get(&s.file).map_or(true, |t| *t < since));
+    }
+
+    if playlist.from_year.is_some() || playlist.to_year.is_some() {
+        let years = playlist.from_year.unwrap_or(0)..=playlist.to_year.unwrap_or(u32::MAX);
+        songs.retain(|s| year(s).is_some_and(|y| years.contains(&y)));
+    }
+
+    if playlist.random {
+        Rng::new().shuffle(&mut songs);
+    }
+    if let Some(limit) = playlist.limit {
+        songs.truncate(limit);
+    }
+    Ok(songs)
+}
+
+// Songs queued after the playing one, `None` unless something is playing. Saturates, a
+// status can be behind a queue that was just cleared.
+pub fn songs_left(status: &Status) -> Option<u32> {
+    if status.state != "play" {
+        return None;
+    }
+    Some(
+        status
+            .playlistlength
+            .saturating_sub(status.song.map_or(0, |p| p + 1)),
+    )
+}
+
+// Adds the songs of `playlist` to the queue, after clearing it if `replace` is set.
+// Returns how many songs were added, the queue is left alone if nothing matched.
+pub async fn queue(
+    client: &mut MpdClient,
+    conn: &mut Connection,
+    ratings: &Ratings,
+    playlist: &SmartPlaylist,
+    queued: &[Track],
+    replace: bool,
+) -> Result<usize> {
+    let exclude = if replace {
+        HashSet::new()
+    } else {
+        queued.iter().map(|s| s.file.as_str()).collect()
+    };
+    let songs = evaluate(client, conn, ratings, playlist, &exclude).await?;
+    if songs.is_empty() {
+        return Ok(0);
+    }
+
+    if replace {
+        client.queue_clear().await?;
+    }
+    for song in &songs {
+        client.queue_add(&song.file).await?;
+    }
+    if replace {
+        client.play().await?;
+    }
+    Ok(songs.len())
+}
+
+// `1994` out of anything from `1994` to `1994-03-21`
+pub fn year(track: &Track) -> Option<u32> {
+    track.date.as_deref()?.get(..4)?.parse().ok()
+}
+
+// Good enough for shuffling songs, no need for a whole crate
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new() -> Rng {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        // xorshift gets stuck on 0
+        Rng(seed | 1)
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    // Somewhere in 0..n
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next() % n.max(1) as u64) as usize
+    }
+
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            items.swap(i, self.below(i + 1));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{self, Mpd};
+
+    fn status(state: &str, playlistlength: u32, song: Option<u32>) -> Status {
+        Status {
+            state: state.to_string(),
+            playlistlength,
+            song,
+            ..Status::default()
+        }
+    }
+
+    #[test]
+    fn counts_songs_left() {
+        assert_eq!(songs_left(&status("play", 5, Some(1))), Some(3));
+        assert_eq!(songs_left(&status("play", 5, Some(4))), Some(0));
+        assert_eq!(songs_left(&status("play", 5, None)), Some(5));
+        // the queue was cleared after the status was fetched
+        assert_eq!(songs_left(&status("play", 0, Some(3))), Some(0));
+        assert_eq!(songs_left(&status("pause", 5, Some(1))), None);
+    }
+
+    #[async_std::test]
+    async fn keeps_the_queue_if_nothing_matched() {
+        let mpd = Mpd::start(vec![mock::song("a/1.flac", "One", "Alpha", "First")]).await;
+        mpd.with(|s| {
+            let song = s.library[0].clone();
+            s.enqueue(song);
+        });
+        let mut client = MpdClient::new(mpd.addr).await.unwrap();
+        let mut conn = Connection::new(mpd.addr).await.unwrap();
+        let ratings = Ratings::default();
+
+        let nobody: SmartPlaylist =
+            toml::from_str("name = \"Nobody\"\nartist = \"Nobody\"").unwrap();
+        let added = queue(&mut client, &mut conn, &ratings, &nobody, &[], true)
+            .await
+            .unwrap();
+        assert_eq!(added, 0);
+        assert_eq!(mpd.with(|s| s.files().join(",")), "a/1.flac");
+        assert!(!mpd.commands().contains(&"clear".to_string()));
+
+        let alpha: SmartPlaylist = toml::from_str("name = \"Alpha\"\nartist = \"Alpha\"").unwrap();
+        let added = queue(&mut client, &mut conn, &ratings, &alpha, &[], true)
+            .await
+            .unwrap();
+        assert_eq!(added, 1);
+        assert!(mpd.commands().contains(&"clear".to_string()));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::stickers::{self, Ratings};
 use async_mpd::Track;
 use std::{
     cell::{RefCell, RefMut},
@@ -18,7 +19,7 @@ impl<T, A> StatefulList<T>
 where
     T: Index<usize, Output = A> + IndexMut<usize, Output = A>,
 {
-    pub fn state(&self) -> RefMut<ListState> {
+    pub fn state(&self) -> RefMut<'_, ListState> {
         self.state.borrow_mut()
     }
 }
@@ -31,20 +32,25 @@ where
         self.state
             .borrow()
             .selected()
-            .map(|i| self.items.get(i))
-            .flatten()
+            .and_then(|i| self.items.get(i))
     }
 
-    pub fn list(&self) -> List<'_> {
+    pub fn list(&self, ratings: &Ratings) -> List<'_> {
         List::new(
             self.items
                 .iter()
-                .map(|s| ListItem::new(Span::raw(s.title.as_deref().unwrap_or("Untitled"))))
+                .map(|s| {
+                    ListItem::new(Span::raw(format!(
+                        "{} {}",
+                        stickers::stars(ratings.get(&s.file)),
+                        s.title.as_deref().unwrap_or("Untitled")
+                    )))
+                })
                 .collect::<Vec<ListItem<'_>>>(),
         )
     }
 
-    pub fn tags(&self) -> Option<String> {
+    pub fn tags(&self, ratings: &Ratings) -> Option<String> {
         self.state.borrow().selected().and_then(|i| {
             self.items.get(i).map(|song| {
                 let tags = [
@@ -54,7 +60,10 @@ where
                     ("Release Date:", &song.date),
                 ];
 
+                let rating = ratings.get(&song.file).map(|r| stickers::stars(Some(r)));
+
                 tags.iter()
+                    .chain(&[("Rating:", &rating)])
                     .filter_map(|(n, t)| {
                         t.as_ref().map(|t| {
                             let mut buf = String::with_capacity(n.len() + t.len());
@@ -70,20 +79,16 @@ where
         })
     }
 
+    // Selects `index`, or the last item if there are fewer
     pub fn select(&mut self, index: usize) {
-        self.state.borrow_mut().select(if self.items.len() != 0 {
-            Some(if index == 0 {
-                index
-            } else {
-                self.items.len() % index
-            })
-        } else {
-            None
-        });
+        let last = self.items.len().checked_sub(1);
+        self.state
+            .borrow_mut()
+            .select(last.map(|last| index.min(last)));
     }
 
     pub fn select_last(&mut self) {
-        self.state.borrow_mut().select(Some(self.items.len() - 1));
+        self.select(usize::MAX);
     }
 
     // Select the next item. This will not be reflected until the widget is drawn in the
@@ -93,7 +98,7 @@ where
             self.state
                 .borrow()
                 .selected()
-                .map(|i| if i >= self.items.len() - 1 { 0 } else { i + 1 })
+                .map(|i| if i + 1 >= self.items.len() { 0 } else { i + 1 })
                 .unwrap_or(0)
         });
         self.state.borrow_mut().select(index);
@@ -106,7 +111,13 @@ where
             self.state
                 .borrow()
                 .selected()
-                .map(|i| if i == 0 { self.items.len() - 1 } else { i - 1 })
+                .map(|i| {
+                    if i == 0 {
+                        self.items.len().saturating_sub(1)
+                    } else {
+                        i - 1
+                    }
+                })
                 .unwrap_or(0)
         });
 
@@ -0,0 +1,113 @@
+use async_mpd::{MpdClient, Stats};
+use std::{collections::HashMap, time::Duration};
+
+// how many entries the breakdowns show
+const TOP: usize = 10;
+
+// MPD's own numbers plus breakdowns worked out from every song in the database
+#[derive(Debug, Default)]
+pub struct Library {
+    stats: Option<Stats>,
+    // most songs first
+    genres: Vec<(String, u64)>,
+    // oldest first, e.g. `1990s`
+    decades: Vec<(String, u64)>,
+    // longest first, `Artist - Album`
+    albums: Vec<(String, Duration)>,
+}
+
+impl Library {
+    // Loads everything again, this goes through the whole database so it isn't done on startup
+    pub async fn load(client: &mut MpdClient) -> Result<Library, async_mpd::Error> {
+        let stats = client.stats().await?;
+        let tracks = client.listallinfo(None).await?;
+
+        let mut genres: HashMap<&str, u64> = HashMap::new();
+        let mut decades: HashMap<u32, u64> = HashMap::new();
+        let mut albums: HashMap<(&str, &str), Duration> = HashMap::new();
+
+        for track in tracks.iter().filter_map(|t| t.track()) {
+            *genres
+                .entry(track.genre.as_deref().unwrap_or("Unknown"))
+                .or_default() += 1;
+
+            // dates are anything from `1994` to `1994-03-21`
+            if let Some(year) = track
+                .date
+                .as_deref()
+                .and_then(|d| d.get(..4))
+                .and_then(|y| y.parse::<u32>().ok())
+            {
+                *decades.entry(year / 10 * 10).or_default() += 1;
+            }
+
+            if let Some(album) = track.album.as_deref() {
+                let artist = track
+                    .album_artist
+                    .as_deref()
+                    .or(track.artist.as_deref())
+                    .unwrap_or("Unknown");
+                *albums.entry((artist, album)).or_default() += track.duration;
+            }
+        }
+
+        let mut genres = genres
+            .into_iter()
+            .map(|(g, n)| (g.to_string(), n))
+            .collect::<Vec<_>>();
+        genres.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        genres.truncate(TOP);
+
+        let mut decades = decades.into_iter().collect::<Vec<_>>();
+        decades.sort();
+        let decades = decades
+            .into_iter()
+            .map(|(d, n)| (format!("{}s", d), n))
+            .collect();
+
+        let mut albums = albums
+            .into_iter()
+            .map(|((artist, album), d)| (format!("{} - {}", artist, album), d))
+            .collect::<Vec<_>>();
+        albums.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        albums.truncate(TOP);
+
+        Ok(Library {
+            stats: Some(stats),
+            genres,
+            decades,
+            albums,
+        })
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.stats.is_some()
+    }
+
+    pub fn stats(&self) -> Option<&Stats> {
+        self.stats.as_ref()
+    }
+
+    pub fn genres(&self) -> &[(String, u64)] {
+        &self.genres
+    }
+
+    pub fn decades(&self) -> &[(String, u64)] {
+        &self.decades
+    }
+
+    pub fn albums(&self) -> &[(String, Duration)] {
+        &self.albums
+    }
+}
+
+// `3d 4h 12m`, leaving out the larger units while they're zero
+pub fn duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    let (days, hours, mins) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    match (days, hours) {
+        (0, 0) => format!("{}m {}s", mins, secs % 60),
+        (0, _) => format!("{}h {}m", hours, mins),
+        _ => format!("{}d {}h {}m", days, hours, mins),
+    }
+}
@@ -0,0 +1,255 @@
+use crate::protocol::Connection;
+use async_mpd::{Error, Status, Track};
+use std::{collections::HashMap, time::Duration};
+
+const RATING: &str = "rating";
+const PLAY_COUNT: &str = "playcount";
+const LAST_PLAYED: &str = "lastplayed";
+
+// Ratings of every rated song, kept in sync through the sticker subsystem
+#[derive(Debug, Default)]
+pub struct Ratings {
+    ratings: HashMap<String, u8>,
+}
+
+impl Ratings {
+    pub async fn load(&mut self, conn: &mut Connection) -> Result<(), Error> {
+        self.ratings = find(conn, RATING)
+            .await?
+            .into_iter()
+            .filter_map(|(file, r)| Some((file, r.parse().ok()?)))
+            .collect();
+        Ok(())
+    }
+
+    pub fn get(&self, file: &str) -> Option<u8> {
+        self.ratings.get(file).copied()
+    }
+
+    // What `rate` is about to set, so the next key press doesn't have to wait for MPD
+    pub fn set(&mut self, file: &str, rating: u8) {
+        if rating == 0 {
+            self.ratings.remove(file);
+        } else {
+            self.ratings.insert(file.to_string(), rating.min(5));
+        }
+    }
+}
+
+// `★★★☆☆`, five spaces for unrated songs so ratings line up
+pub fn stars(rating: Option<u8>) -> String {
+    match rating {
+        Some(r) => {
+            let r = r.min(5) as usize;
+            "★".repeat(r) + &"☆".repeat(5 - r)
+        }
+        None => " ".repeat(5),
+    }
+}
+
+// Sets the rating of `file`, 0 removes it
+pub async fn rate(conn: &mut Connection, file: &str, rating: u8) -> Result<(), Error> {
+    if rating == 0 {
+        match conn
+            .command("sticker", &["delete", "song", file, RATING])
+            .await
+        {
+            // it wasn't rated to begin with
+            Err(Error::CommandError { .. }) => Ok(()),
+            r => r.map(|_| ()),
+        }
+    } else {
+        conn.command(
+            "sticker",
+            &["set", "song", file, RATING, &rating.min(5).to_string()],
+        )
+        .await
+        .map(|_| ())
+    }
+}
+
+// Value of sticker `name` on `file`, `None` if it isn't set
+pub async fn get(conn: &mut Connection, file: &str, name: &str) -> Result<Option<String>, Error> {
+    match conn.command("sticker", &["get", "song", file, name]).await {
+        Ok(pairs) => Ok(pairs
+            .into_iter()
+            .find(|(k, _)| k == "sticker")
+            .and_then(|(_, v)| v.split_once('=').map(|(_, v)| v.to_string()))),
+        // no such sticker
+        Err(Error::CommandError { .. }) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Bumps the play count of `file` and sets when it was last played, as a unix timestamp
+pub async fn record_play(conn: &mut Connection, file: &str) -> Result<(), Error> {
+    let count = get(conn, file, PLAY_COUNT)
+        .await?
+        .and_then(|c| c.parse::<u64>().ok())
+        .unwrap_or(0);
+    let now = chrono::Utc::now().timestamp().to_string();
+
+    conn.command(
+        "sticker",
+        &["set", "song", file, PLAY_COUNT, &(count + 1).to_string()],
+    )
+    .await?;
+    conn.command("sticker", &["set", "song", file, LAST_PLAYED, &now])
+        .await?;
+    Ok(())
+}
+
+// When every song that was played before was last played, as unix timestamps
+pub async fn last_played(conn: &mut Connection) -> Result<HashMap<String, i64>, Error> {
+    Ok(find(conn, LAST_PLAYED)
+        .await?
+        .into_iter()
+        .filter_map(|(file, t)| Some((file, t.parse().ok()?)))
+        .collect())
+}
+
+// Every song with sticker `name` and its value
+async fn find(conn: &mut Connection, name: &str) -> Result<Vec<(String, String)>, Error> {
+    let pairs = match conn.command("sticker", &["find", "song", "", name]).await {
+        Ok(pairs) => pairs,
+        // an empty database or the sticker database is turned off
+        Err(Error::CommandError { msg }) => {
+            log::info!("Cannot find {} stickers: {}", name, msg);
+            return Ok(Vec::new());
+        }
+        Err(e) => return Err(e),
+    };
+
+    // file: a/b.flac
+    // sticker: rating=4
+    let mut found = Vec::new();
+    let mut file = None;
+    for (k, v) in pairs {
+        match k.as_str() {
+            "file" => file = Some(v),
+            "sticker" => {
+                if let (Some(file), Some((_, value))) = (file.take(), v.split_once('=')) {
+                    found.push((file, value.to_string()));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(found)
+}
+
+// Follows the playing song through status updates to tell when one was played to the end
+#[derive(Debug, Default)]
+pub struct Plays {
+    // id and file of the song last seen playing, and how far it got
+    current: Option<(u32, String, Duration, Duration)>,
+}
+
+impl Plays {
+    // Returns the file of the song that was playing before, if it made it (almost) to the end
+    pub fn update(&mut self, status: Option<&Status>, playing: Option<&Track>) -> Option<String> {
+        let (status, track) = match (status, playing) {
+            (Some(s), Some(t)) if s.state != "stop" => (s, t),
+            _ => return self.current.take().and_then(finished),
+        };
+        let id = status.songid?;
+        let elapsed = status.elapsed.unwrap_or_default();
+        let duration = status.duration.unwrap_or(track.duration);
+
+        match &mut self.current {
+            Some((current, _, seen, _)) if *current == id => {
+                *seen = (*seen).max(elapsed);
+                None
+            }
+            _ => self
+                .current
+                .replace((id, track.file.clone(), elapsed, duration))
+                .and_then(finished),
+        }
+    }
+}
+
+// status is only polled every so often, so the last bit of a song is never seen
+fn finished((_, file, seen, duration): (u32, String, Duration, Duration)) -> Option<String> {
+    if duration.as_secs() > 0 && seen.as_secs_f64() >= duration.as_secs_f64() * 0.9 {
+        Some(file)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(file: &str) -> Track {
+        Track {
+            file: file.to_string(),
+            duration: Duration::from_secs(300),
+            ..Track::default()
+        }
+    }
+
+    fn status(state: &str, id: u32, elapsed: u64) -> Status {
+        Status {
+            state: state.to_string(),
+            songid: Some(id),
+            elapsed: Some(Duration::from_secs(elapsed)),
+            duration: Some(Duration::from_secs(300)),
+            ..Status::default()
+        }
+    }
+
+    #[test]
+    fn counts_songs_played_to_nine_tenths() {
+        let mut plays = Plays::default();
+        let (a, b, c) = (track("a.flac"), track("b.flac"), track("c.flac"));
+
+        assert_eq!(plays.update(Some(&status("play", 1, 0)), Some(&a)), None);
+        assert_eq!(plays.update(Some(&status("play", 1, 270)), Some(&a)), None);
+        assert_eq!(
+            plays.update(Some(&status("play", 2, 0)), Some(&b)),
+            Some("a.flac".to_string())
+        );
+
+        // just short of it
+        plays.update(Some(&status("play", 2, 269)), Some(&b));
+        assert_eq!(plays.update(Some(&status("play", 3, 0)), Some(&c)), None);
+
+        // seeking back doesn't take away how far it got
+        plays.update(Some(&status("play", 3, 280)), Some(&c));
+        plays.update(Some(&status("play", 3, 10)), Some(&c));
+        assert_eq!(
+            plays.update(Some(&status("stop", 3, 0)), Some(&c)),
+            Some("c.flac".to_string())
+        );
+    }
+
+    #[test]
+    fn counts_a_song_once() {
+        let mut plays = Plays::default();
+        let a = track("a.flac");
+        plays.update(Some(&status("play", 1, 290)), Some(&a));
+        // repeating the same song keeps its id, it only counts when something else comes up
+        assert_eq!(plays.update(Some(&status("play", 1, 0)), Some(&a)), None);
+        assert_eq!(
+            plays.update(Some(&status("stop", 1, 0)), Some(&a)),
+            Some("a.flac".to_string())
+        );
+        assert_eq!(plays.update(Some(&status("stop", 1, 0)), Some(&a)), None);
+        assert_eq!(plays.update(None, None), None);
+    }
+
+    #[test]
+    fn skips_songs_without_a_length() {
+        let mut plays = Plays::default();
+        let stream = Track {
+            file: "http://radio".to_string(),
+            ..Track::default()
+        };
+        let mut playing = status("play", 1, 600);
+        playing.duration = None;
+        plays.update(Some(&playing), Some(&stream));
+        assert_eq!(plays.update(None, None), None);
+    }
+}
@@ -0,0 +1,90 @@
+use anyhow::Result;
+use crossterm::{
+    cursor::Show,
+    event::{DisableMouseCapture, EnableMouseCapture},
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+    ExecutableCommand,
+};
+use std::{
+    backtrace::Backtrace,
+    io::{self, Write},
+    panic,
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+// Set while the terminal is changed. A panic restores it in the hook and then again when
+// unwinding drops the guard, only the first one does anything.
+static ENTERED: AtomicBool = AtomicBool::new(false);
+
+// Raw mode, the alternate screen and mouse capture, for as long as it lives. Dropping it
+// restores the terminal, so returning early with `?` leaves it usable as well.
+pub struct Guard(());
+
+impl Guard {
+    pub fn enter() -> Result<Guard> {
+        // created first, so whatever fails after enabling raw mode is undone
+        let guard = Guard(());
+        ENTERED.store(true, Ordering::SeqCst);
+        enable_raw_mode()?;
+        io::stdout()
+            .execute(EnterAlternateScreen)?
+            .execute(EnableMouseCapture)?;
+        Ok(guard)
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        restore(&mut io::stdout());
+    }
+}
+
+// Undoes everything `Guard::enter` did, returns whether there was anything to undo. Errors
+// are ignored, the terminal might be gone already and there's nothing left to do about it.
+fn restore(out: &mut impl Write) -> bool {
+    if !ENTERED.swap(false, Ordering::SeqCst) {
+        return false;
+    }
+    let _ = disable_raw_mode();
+    let _ = out.execute(DisableMouseCapture);
+    let _ = out.execute(LeaveAlternateScreen);
+    let _ = out.execute(Show);
+    true
+}
+
+// Restores the terminal before saying what panicked, otherwise the message ends up on the
+// alternate screen and is gone along with it. The backtrace goes to `log`.
+pub fn set_panic_hook(log: Option<PathBuf>) {
+    panic::set_hook(Box::new(move |info| {
+        restore(&mut io::stdout());
+        log::error!("{}\n{}", info, Backtrace::force_capture());
+        eprintln!("rmpc {}", info);
+        if let Some(log) = &log {
+            eprintln!("The backtrace is in {}", log.display());
+        }
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restores_only_once() {
+        let mut out = Vec::new();
+        // never entered
+        assert!(!restore(&mut out));
+        assert!(out.is_empty());
+
+        ENTERED.store(true, Ordering::SeqCst);
+        assert!(restore(&mut out));
+        let restored = String::from_utf8(out.clone()).unwrap();
+        assert!(restored.contains("\x1b[?1049l"));
+        assert!(restored.ends_with("\x1b[?25h"));
+
+        // like the guard being dropped after the panic hook ran
+        assert!(!restore(&mut out));
+        assert_eq!(out.len(), restored.len());
+    }
+}
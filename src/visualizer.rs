@@ -0,0 +1,206 @@
+use crate::config::{self, VisualizerStyle};
+use std::{
+    collections::VecDeque,
+    f32::consts::PI,
+    fs::File,
+    io::{BufReader, Read},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+// samples per FFT, has to be a power of two
+const WINDOW: usize = 2048;
+
+// how long without new samples before everything is treated as silence (paused, stopped)
+const STALE: Duration = Duration::from_millis(200);
+
+// how fast bars fall down between frames
+const DECAY: f32 = 0.85;
+
+struct Samples {
+    // mono, newest last
+    buf: VecDeque<f32>,
+    updated: Instant,
+}
+
+pub struct Visualizer {
+    samples: Arc<Mutex<Samples>>,
+    style: VisualizerStyle,
+    fps: u32,
+    // bar heights of the last frame, for the decay
+    bars: Vec<f32>,
+}
+
+impl Visualizer {
+    // Starts reading from the fifo in the background
+    pub fn new(config: &config::Visualizer) -> Visualizer {
+        let samples = Arc::new(Mutex::new(Samples {
+            buf: VecDeque::from(vec![0.; WINDOW]),
+            updated: Instant::now(),
+        }));
+
+        let path = config.fifo_path.clone();
+        let channels = config.channels.max(1) as usize;
+        let shared = samples.clone();
+        thread::spawn(move || read_fifo(path, channels, shared));
+
+        Visualizer {
+            samples,
+            style: config.style,
+            fps: config.fps.clamp(1, 120),
+            bars: Vec::new(),
+        }
+    }
+
+    pub fn style(&self) -> VisualizerStyle {
+        self.style
+    }
+
+    pub fn frame_time(&self) -> Duration {
+        Duration::from_millis(1000 / self.fps as u64)
+    }
+
+    // Latest window of samples, silence if nothing is playing
+    fn window(&self) -> Vec<f32> {
+        let samples = self.samples.lock().unwrap();
+        if samples.updated.elapsed() > STALE {
+            vec![0.; WINDOW]
+        } else {
+            samples.buf.iter().copied().collect()
+        }
+    }
+
+    // Newest samples, scaled to -1..1
+    pub fn wave(&self, points: usize) -> Vec<(f64, f64)> {
+        let window = self.window();
+        let start = window.len().saturating_sub(points);
+        window[start..]
+            .iter()
+            .enumerate()
+            .map(|(i, s)| (i as f64, *s as f64))
+            .collect()
+    }
+
+    // Magnitudes of `count` logarithmically spaced frequency bands, from 0 to 100
+    pub fn spectrum(&mut self, count: usize) -> Vec<u64> {
+        let mut data = self
+            .window()
+            .iter()
+            .enumerate()
+            // hann window, stops the edges of the window from smearing the spectrum
+            .map(|(i, s)| {
+                let w = 0.5 - 0.5 * (2. * PI * i as f32 / (WINDOW - 1) as f32).cos();
+                (s * w, 0.)
+            })
+            .collect::<Vec<_>>();
+        fft(&mut data);
+
+        // only the first half is useful for real input, and skip the DC offset
+        let bins = &data[1..WINDOW / 2];
+        let bands = (0..count)
+            .map(|b| {
+                // starting at ~40Hz, lower than that is mostly rumble
+                let edge = |b: usize| {
+                    let f = 2. * (bins.len() as f32 / 2.).powf(b as f32 / count as f32);
+                    (f as usize).clamp(1, bins.len())
+                };
+                let (lo, hi) = (edge(b) - 1, edge(b + 1).max(edge(b)));
+                let sum = bins[lo..hi]
+                    .iter()
+                    .map(|(re, im)| (re * re + im * im).sqrt())
+                    .sum::<f32>();
+                let db = 20. * (sum / (hi - lo) as f32).max(1e-6).log10();
+                // roughly -10..50 dB in practice
+                ((db + 10.) * 100. / 60.).clamp(0., 100.)
+            })
+            .collect::<Vec<_>>();
+
+        self.bars.resize(count, 0.);
+        self.bars
+            .iter_mut()
+            .zip(bands)
+            .map(|(bar, new)| {
+                *bar = new.max(*bar * DECAY);
+                *bar as u64
+            })
+            .collect()
+    }
+}
+
+fn read_fifo(path: PathBuf, channels: usize, samples: Arc<Mutex<Samples>>) {
+    let mut logged = false;
+
+    loop {
+        // blocks until MPD opens the fifo for writing
+        let file = match File::open(&path) {
+            Ok(f) => f,
+            Err(e) => {
+                if !logged {
+                    log::error!("Cannot open visualizer fifo {}: {}", path.display(), e);
+                    logged = true;
+                }
+                thread::sleep(Duration::from_secs(5));
+                continue;
+            }
+        };
+        logged = false;
+
+        let mut reader = BufReader::new(file);
+        // 16 bit little endian samples, interleaved channels
+        let mut frames = vec![0u8; 256 * channels * 2];
+        while reader.read_exact(&mut frames).is_ok() {
+            let mut samples = samples.lock().unwrap();
+            for frame in frames.chunks_exact(channels * 2) {
+                let sum = frame
+                    .chunks_exact(2)
+                    .map(|s| i16::from_le_bytes([s[0], s[1]]) as f32)
+                    .sum::<f32>();
+                samples.buf.pop_front();
+                samples
+                    .buf
+                    .push_back(sum / channels as f32 / i16::MAX as f32);
+            }
+            samples.updated = Instant::now();
+        }
+
+        // the writer went away, wait a bit before opening it again
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+// In place radix-2 FFT, `data.len()` has to be a power of two
+fn fft(data: &mut [(f32, f32)]) {
+    let n = data.len();
+
+    // bit reversal permutation
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            data.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -2. * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (wr, wi) = ((angle * k as f32).cos(), (angle * k as f32).sin());
+                let (ar, ai) = data[start + k];
+                let (br, bi) = data[start + k + len / 2];
+                let (tr, ti) = (br * wr - bi * wi, br * wi + bi * wr);
+                data[start + k] = (ar + tr, ai + ti);
+                data[start + k + len / 2] = (ar - tr, ai - ti);
+            }
+        }
+        len <<= 1;
+    }
+}